@@ -0,0 +1,327 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details
+
+//! Recording of the messages exchanged with clients, for diagnosing
+//! misbehaving clients after the fact.
+//!
+//! A capture file is a header followed by a bincode-encoded `Record` per
+//! message.  Records are encoded as messages pass through a connection's
+//! `rpc::Tap` and written by the `Writer`'s thread, which flushes whenever
+//! it runs out of records, so a capture cut short by a crash is still
+//! readable up to the last complete record written.
+
+use crate::codec::MAX_MESSAGE_LEN;
+use crate::messages::{CallbackReq, CallbackResp, ClientMessage, ServerMessage, PROTOCOL_VERSION};
+use crate::rpc::{Envelope, Tap};
+use crate::PlatformHandle;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Instant;
+use std::{mem, thread};
+
+const MAGIC: [u8; 8] = *b"AIPCCAP\0";
+
+/// A message recorded in a capture.
+#[derive(Debug, Deserialize, Serialize)]
+pub enum Message {
+    /// Request from a client to the server.
+    Server(ServerMessage),
+    /// Response from the server to a client.
+    Client(ClientMessage),
+    /// Stream callback request from the server to a client.
+    Callback(CallbackReq),
+}
+
+// Serializes identically to `Message`.
+#[doc(hidden)]
+#[derive(Serialize)]
+pub enum MessageRef<'a> {
+    Server(&'a ServerMessage),
+    Client(&'a ClientMessage),
+    Callback(&'a CallbackReq),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Record {
+    /// Microseconds since the capture started.
+    pub time: u64,
+    /// Identifies the connection the message was exchanged on, in order of
+    /// the connections' creation.
+    pub connection: u32,
+    /// rpc identifier pairing a request with its response.
+    pub id: u32,
+    pub message: Message,
+}
+
+#[derive(Serialize)]
+struct RecordRef<'a> {
+    time: u64,
+    connection: u32,
+    id: u32,
+    message: MessageRef<'a>,
+}
+
+/// Messages that are recorded when passing through a `ConnectionTap`.
+pub trait Capture {
+    #[doc(hidden)]
+    fn captured(&self) -> Option<MessageRef<'_>>;
+}
+
+impl Capture for ServerMessage {
+    fn captured(&self) -> Option<MessageRef<'_>> {
+        Some(MessageRef::Server(self))
+    }
+}
+
+impl Capture for ClientMessage {
+    fn captured(&self) -> Option<MessageRef<'_>> {
+        Some(MessageRef::Client(self))
+    }
+}
+
+impl Capture for CallbackReq {
+    fn captured(&self) -> Option<MessageRef<'_>> {
+        Some(MessageRef::Callback(self))
+    }
+}
+
+impl Capture for CallbackResp {
+    fn captured(&self) -> Option<MessageRef<'_>> {
+        None
+    }
+}
+
+/// A capture file being written.  Shared by the connections it records.
+#[derive(Debug)]
+pub struct Writer {
+    // Only `None` while dropping.
+    records: Mutex<Option<mpsc::Sender<Vec<u8>>>>,
+    thread: Option<thread::JoinHandle<()>>,
+    start: Instant,
+    next_connection: AtomicUsize,
+}
+
+impl Writer {
+    /// Start a capture in a new file at `path`, replacing any existing file.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Arc<Writer>> {
+        let mut file = File::create(path)?;
+        file.write_all(&MAGIC)?;
+        file.write_all(&PROTOCOL_VERSION.to_le_bytes())?;
+        let (tx, rx) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("AudioIPC Capture".into())
+            .spawn(move || write_records(file, rx))?;
+        Ok(Arc::new(Writer {
+            records: Mutex::new(Some(tx)),
+            thread: Some(thread),
+            start: Instant::now(),
+            next_connection: AtomicUsize::new(0),
+        }))
+    }
+
+    /// A tap recording the messages exchanged on a new connection.
+    pub fn tap(self: &Arc<Self>) -> ConnectionTap {
+        let records = self.records.lock().unwrap().clone();
+        ConnectionTap {
+            records,
+            writer: self.clone(),
+            connection: self.next_connection.fetch_add(1, Ordering::Relaxed) as u32,
+        }
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        // The taps are gone, so this is the last sender.  Wait for the
+        // thread to write what they queued.
+        drop(self.records.get_mut().unwrap().take());
+        if let Some(thread) = self.thread.take() {
+            drop(thread.join());
+        }
+    }
+}
+
+// Write each record queued on `records` to `file`, until every sender is
+// dropped or writing fails.
+fn write_records(file: File, records: mpsc::Receiver<Vec<u8>>) {
+    let mut file = BufWriter::new(file);
+    let mut record = records.recv();
+    while let Ok(bytes) = record {
+        if let Err(e) = file.write_all(&bytes) {
+            debug!("Failed to write capture record: {:?}", e);
+            return;
+        }
+        record = match records.try_recv() {
+            Ok(bytes) => Ok(bytes),
+            Err(_) => {
+                if let Err(e) = file.flush() {
+                    debug!("Failed to write capture record: {:?}", e);
+                    return;
+                }
+                records.recv()
+            }
+        };
+    }
+}
+
+/// Records the messages exchanged on a connection with a `Writer`.
+#[derive(Debug)]
+pub struct ConnectionTap {
+    // Dropped before `writer`, whose drop waits for every sender to go.
+    records: Option<mpsc::Sender<Vec<u8>>>,
+    writer: Arc<Writer>,
+    connection: u32,
+}
+
+impl ConnectionTap {
+    // Queue a record of `message` for the writer's thread.  Each record is
+    // queued whole, so records from different connections don't interleave.
+    fn write<T: Capture>(&self, message: &Envelope<T>) {
+        let (captured, records) = match (message.body.captured(), &self.records) {
+            (Some(captured), Some(records)) => (captured, records),
+            _ => return,
+        };
+        let record = RecordRef {
+            time: self.writer.start.elapsed().as_micros() as u64,
+            connection: self.connection,
+            id: message.id,
+            message: captured,
+        };
+        match bincode::serialize(&record) {
+            // Fails only once the thread has given up after an error.
+            Ok(bytes) => drop(records.send(bytes)),
+            Err(e) => debug!("Failed to encode capture record: {:?}", e),
+        }
+    }
+}
+
+impl<In: Capture, Out: Capture> Tap<Envelope<In>, Envelope<Out>> for ConnectionTap {
+    fn incoming(&mut self, message: &Envelope<In>) {
+        self.write(message);
+    }
+
+    fn outgoing(&mut self, message: &Envelope<Out>) {
+        self.write(message);
+    }
+}
+
+/// Read the records of a capture.  A truncated final record is ignored.
+pub fn read<R: Read>(mut reader: R) -> io::Result<Vec<Record>> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    let mut version = [0u8; 4];
+    reader.read_exact(&mut version)?;
+    if magic != MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not an audioipc capture",
+        ));
+    }
+    let version = u32::from_le_bytes(version);
+    if version != PROTOCOL_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "capture of protocol version {}, expected {}",
+                version, PROTOCOL_VERSION
+            ),
+        ));
+    }
+
+    let mut records = Vec::new();
+    loop {
+        let r = bincode::config()
+            .limit(2 * MAX_MESSAGE_LEN)
+            .deserialize_from::<_, Record>(&mut reader);
+        match r {
+            Ok(mut record) => {
+                // Handles in a capture are values from another process,
+                // which must never be closed.
+                if let Message::Client(ref mut message) = record.message {
+                    mem::forget(take_platform_handles(message));
+                }
+                records.push(record);
+            }
+            Err(e) => match *e {
+                bincode::ErrorKind::Io(e) => {
+                    if e.kind() == io::ErrorKind::UnexpectedEof {
+                        return Ok(records);
+                    }
+                    return Err(e);
+                }
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, *e)),
+            },
+        }
+    }
+}
+
+/// Remove the handles carried by `message`, so messages can be compared
+/// regardless of the handles they carry.
+pub fn take_platform_handles(message: &mut ClientMessage) -> Vec<PlatformHandle> {
+    match *message {
+        ClientMessage::StreamCreated(ref mut data) => {
+            mem::replace(&mut data.platform_handles, Vec::new())
+        }
+        ClientMessage::ContextSetupDeviceCollectionCallback(ref mut data) => {
+            mem::replace(&mut data.platform_handles, Vec::new())
+        }
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read, Message, Writer};
+    use crate::messages::{CallbackReq, CallbackResp, ClientMessage, ServerMessage};
+    use crate::rpc::{Envelope, Tap};
+    use std::fs::File;
+
+    #[test]
+    fn capture_round_trip() {
+        let path = std::env::temp_dir().join(format!("audioipc-capture-{}", std::process::id()));
+        let writer = Writer::create(&path).unwrap();
+        let mut server = writer.tap();
+        let mut callback = writer.tap();
+        server.incoming(&Envelope {
+            id: 7,
+            body: ServerMessage::StreamStart(3),
+        });
+        server.outgoing(&Envelope {
+            id: 7,
+            body: ClientMessage::StreamStarted,
+        });
+        callback.outgoing(&Envelope {
+            id: 0,
+            body: CallbackReq::DeviceChange,
+        });
+        callback.incoming(&Envelope {
+            id: 0,
+            body: CallbackResp::DeviceChange,
+        });
+        drop((server, callback, writer));
+
+        let mut records = read(File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records.len(), 3);
+        assert!(records.windows(2).all(|r| r[0].time <= r[1].time));
+        let callback = records.pop().unwrap();
+        assert_eq!(callback.connection, 1);
+        match callback.message {
+            Message::Callback(CallbackReq::DeviceChange) => {}
+            m => panic!("unexpected {:?}", m),
+        }
+        assert!(records.iter().all(|r| r.connection == 0 && r.id == 7));
+        match (&records[0].message, &records[1].message) {
+            (
+                Message::Server(ServerMessage::StreamStart(3)),
+                Message::Client(ClientMessage::StreamStarted),
+            ) => {}
+            m => panic!("unexpected {:?}", m),
+        }
+    }
+}
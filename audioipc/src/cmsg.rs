@@ -3,6 +3,8 @@
 // This program is made available under an ISC-style license.  See the
 // accompanying file LICENSE for details
 
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use crate::PeerCredentials;
 use bytes::{BufMut, Bytes, BytesMut};
 use libc::{self, cmsghdr};
 use std::convert::TryInto;
@@ -38,47 +40,84 @@ pub fn iterator(c: Bytes) -> ControlMsgIter {
     ControlMsgIter { control: c }
 }
 
+impl ControlMsgIter {
+    // The level, type and data of the next entry.
+    fn next_entry(&mut self) -> Option<(libc::c_int, libc::c_int, Bytes)> {
+        let control = self.control.clone();
+        let cmsghdr_len = len(0);
+
+        if control.len() < cmsghdr_len {
+            // No more entries---not enough data in `control` for a
+            // complete message.
+            return None;
+        }
+
+        let cmsg: &cmsghdr = unsafe { &*(control.as_ptr() as *const _) };
+        // The offset to the next cmsghdr in control.  This must be
+        // aligned to a boundary that matches the type used to
+        // represent the length of the message.
+        let cmsg_len = cmsg.cmsg_len as usize;
+        if cmsg_len < cmsghdr_len || cmsg_len > control.len() {
+            // Malformed entry---stop rather than read past `control`.
+            self.control = Bytes::new();
+            return None;
+        }
+        let cmsg_space = space(cmsg_len - cmsghdr_len);
+        self.control = if cmsg_space > control.len() {
+            // No more entries---not enough data in `control` for a
+            // complete message.
+            Bytes::new()
+        } else {
+            control.slice_from(cmsg_space)
+        };
+
+        Some((
+            cmsg.cmsg_level,
+            cmsg.cmsg_type,
+            control.slice(cmsghdr_len, cmsg_len as _),
+        ))
+    }
+}
+
 impl Iterator for ControlMsgIter {
     type Item = Fds;
 
     fn next(&mut self) -> Option<Self::Item> {
-        loop {
-            let control = self.control.clone();
-            let cmsghdr_len = len(0);
-
-            if control.len() < cmsghdr_len {
-                // No more entries---not enough data in `control` for a
-                // complete message.
-                return None;
-            }
-
-            let cmsg: &cmsghdr = unsafe { &*(control.as_ptr() as *const _) };
-            // The offset to the next cmsghdr in control.  This must be
-            // aligned to a boundary that matches the type used to
-            // represent the length of the message.
-            let cmsg_len = cmsg.cmsg_len as usize;
-            let cmsg_space = space(cmsg_len - cmsghdr_len);
-            self.control = if cmsg_space > control.len() {
-                // No more entries---not enough data in `control` for a
-                // complete message.
-                Bytes::new()
-            } else {
-                control.slice_from(cmsg_space)
-            };
-
-            match (cmsg.cmsg_level, cmsg.cmsg_type) {
+        while let Some((level, kind, data)) = self.next_entry() {
+            match (level, kind) {
                 (libc::SOL_SOCKET, libc::SCM_RIGHTS) => {
                     trace!("Found SCM_RIGHTS...");
-                    return Some(Fds {
-                        fds: control.slice(cmsghdr_len, cmsg_len as _),
-                    });
+                    return Some(Fds { fds: data });
                 }
                 (level, kind) => {
                     trace!("Skipping cmsg level, {}, type={}...", level, kind);
                 }
             }
         }
+        None
+    }
+}
+
+/// The credentials of the sending process in `c`, which the kernel adds to
+/// messages received by a socket with `SO_PASSCRED` set.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn credentials(c: Bytes) -> Option<PeerCredentials> {
+    let mut control = iterator(c);
+    let mut credentials = None;
+    while let Some((level, kind, data)) = control.next_entry() {
+        if (level, kind) != (libc::SOL_SOCKET, libc::SCM_CREDENTIALS)
+            || data.len() < mem::size_of::<libc::ucred>()
+        {
+            continue;
+        }
+        let cred: libc::ucred = unsafe { std::ptr::read_unaligned(data.as_ptr() as *const _) };
+        credentials = Some(PeerCredentials {
+            pid: Some(cred.pid as u32),
+            uid: cred.uid,
+            gid: cred.gid,
+        });
     }
+    credentials
 }
 
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
@@ -12,6 +12,8 @@ use serde::ser::Serialize;
 use std::fmt::Debug;
 use std::io;
 use std::marker::PhantomData;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 ////////////////////////////////////////////////////////////////////////////////
 // Split buffer into size delimited frames - This appears more complicated than
@@ -52,6 +54,7 @@ pub trait Codec {
 /// message.
 pub struct LengthDelimitedCodec<In, Out> {
     state: State,
+    limit: MessageLimit,
     __in: PhantomData<In>,
     __out: PhantomData<Out>,
 }
@@ -61,21 +64,58 @@ enum State {
     Data(usize),
 }
 
-const MAX_MESSAGE_LEN: u64 = 1024 * 1024;
+pub const MAX_MESSAGE_LEN: u64 = 1024 * 1024;
+/// Upper bound for channels that only carry small fixed-size callback
+/// messages.
+pub const MAX_CALLBACK_MESSAGE_LEN: usize = 1024;
 const MESSAGE_LENGTH_SIZE: usize = std::mem::size_of::<u32>();
 // TODO: static assert that MAX_MESSAGE_LEN can be encoded into MESSAGE_LENGTH_SIZE.
 
+/// The largest message, in bytes, a `LengthDelimitedCodec` encodes or
+/// decodes.  Clones share the limit, so it can be lowered once a connection
+/// has negotiated it with the peer.
+#[derive(Clone, Debug)]
+pub struct MessageLimit(Arc<AtomicUsize>);
+
+impl MessageLimit {
+    pub fn new(len: usize) -> MessageLimit {
+        MessageLimit(Arc::new(AtomicUsize::new(len)))
+    }
+
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, len: usize) {
+        self.0.store(len, Ordering::Relaxed);
+    }
+}
+
 impl<In, Out> Default for LengthDelimitedCodec<In, Out> {
     fn default() -> Self {
+        Self::new(MAX_MESSAGE_LEN as usize)
+    }
+}
+
+impl<In, Out> LengthDelimitedCodec<In, Out> {
+    /// Create a codec that fails to decode messages longer than
+    /// `max_len` bytes with `InvalidData`, and to encode them with
+    /// `InvalidInput`.
+    pub fn new(max_len: usize) -> Self {
+        Self::with_limit(MessageLimit::new(max_len))
+    }
+
+    /// As `new`, with a limit that can be changed after the codec is
+    /// handed to a transport.
+    pub fn with_limit(limit: MessageLimit) -> Self {
         LengthDelimitedCodec {
             state: State::Length,
+            limit,
             __in: PhantomData,
             __out: PhantomData,
         }
     }
-}
 
-impl<In, Out> LengthDelimitedCodec<In, Out> {
     // Lengths are encoded as little endian u32
     fn decode_length(&mut self, buf: &mut BytesMut) -> io::Result<Option<usize>> {
         if buf.len() < MESSAGE_LENGTH_SIZE {
@@ -88,6 +128,15 @@ impl<In, Out> LengthDelimitedCodec<In, Out> {
         // Consume the length field
         let _ = buf.split_to(MESSAGE_LENGTH_SIZE);
 
+        // Refuse before reserving space for the message.
+        if n as usize > self.limit.get() {
+            trace!("oversized incoming message {}", n);
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "decoded message too big",
+            ));
+        }
+
         Ok(Some(n as usize))
     }
 
@@ -158,7 +207,7 @@ where
     fn encode(&mut self, item: Self::In, buf: &mut BytesMut) -> io::Result<()> {
         trace!("Attempting to encode");
         let encoded_len = serialized_size(&item).unwrap();
-        if encoded_len > MAX_MESSAGE_LEN {
+        if encoded_len > self.limit.get() as u64 {
             trace!("oversized message {}", encoded_len);
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
@@ -183,3 +232,37 @@ where
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_enforces_max_len() {
+        let mut codec = LengthDelimitedCodec::<Vec<u8>, Vec<u8>>::new(16);
+        let mut buf = BytesMut::new();
+        codec.encode(vec![0; 8], &mut buf).unwrap();
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(vec![0; 8]));
+
+        // The length prefix alone is enough to refuse the message.
+        let mut buf = BytesMut::new();
+        buf.put_u32_le(u32::max_value());
+        let e = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn shared_limit_applies_to_both_directions() {
+        let limit = MessageLimit::new(MAX_MESSAGE_LEN as usize);
+        let mut codec = LengthDelimitedCodec::<Vec<u8>, Vec<u8>>::with_limit(limit.clone());
+        let mut buf = BytesMut::new();
+        codec.encode(vec![0; 64], &mut buf).unwrap();
+
+        // As after a handshake agreeing on a smaller limit.
+        limit.set(16);
+        let e = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        let e = codec.encode(vec![0; 64], &mut BytesMut::new()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -6,7 +6,8 @@
 use crate::async_msg::{AsyncRecvMsg, AsyncSendMsg};
 use crate::cmsg;
 use crate::codec::Codec;
-use crate::messages::AssocRawPlatformHandle;
+use crate::messages::{expect_platform_handles, AssocRawPlatformHandle};
+use crate::{PeerCredentials, PlatformHandle};
 use bytes::{Bytes, BytesMut, IntoBuf};
 use futures::{task, AsyncSink, Poll, Sink, StartSend, Stream};
 use std::collections::VecDeque;
@@ -16,28 +17,52 @@ use std::{fmt, io, mem};
 const INITIAL_CAPACITY: usize = 1024;
 const BACKPRESSURE_THRESHOLD: usize = 4 * INITIAL_CAPACITY;
 const FDS_CAPACITY: usize = 16;
+// Most fds sent with a single message.  The receiver reserves room for this
+// many per read, so sending more is refused rather than risk the kernel
+// dropping them.
+const MAX_FDS_PER_MSG: usize = 3;
+
+// Room for the credentials attached to each read by a socket with
+// `SO_PASSCRED` set.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn credentials_space() -> usize {
+    cmsg::space(mem::size_of::<libc::ucred>())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn credentials_space() -> usize {
+    0
+}
 
 struct IncomingFds {
     cmsg: BytesMut,
     recv_fds: Option<cmsg::ControlMsgIter>,
+    // Fds received and not yet handed to a message, with the stream offset
+    // at the end of the read that received them.
+    received: VecDeque<(u64, Vec<RawFd>)>,
+    // Credentials of the sender of the last read, if the socket has
+    // `SO_PASSCRED` set.  A read never spans data from different senders.
+    credentials: Option<PeerCredentials>,
 }
 
 impl IncomingFds {
     pub fn new(c: usize) -> Self {
-        let capacity = c * cmsg::space(mem::size_of::<[RawFd; 3]>());
+        let capacity = c * cmsg::space(mem::size_of::<[RawFd; MAX_FDS_PER_MSG]>());
         IncomingFds {
             cmsg: BytesMut::with_capacity(capacity),
             recv_fds: None,
+            received: VecDeque::new(),
+            credentials: None,
         }
     }
 
-    pub fn take_fds(&mut self) -> Option<[RawFd; 3]> {
+    pub fn take_fds(&mut self) -> Option<Vec<RawFd>> {
         loop {
             let fds = self
                 .recv_fds
                 .as_mut()
                 .and_then(|recv_fds| recv_fds.next())
-                .map(|fds| clone_into_array(&fds));
+                .map(|fds| fds.to_vec());
 
             if fds.is_some() {
                 return fds;
@@ -52,9 +77,71 @@ impl IncomingFds {
     }
 
     pub fn cmsg(&mut self) -> &mut BytesMut {
-        self.cmsg.reserve(cmsg::space(mem::size_of::<[RawFd; 3]>()));
+        self.cmsg
+            .reserve(cmsg::space(mem::size_of::<[RawFd; MAX_FDS_PER_MSG]>()) + credentials_space());
         &mut self.cmsg
     }
+
+    // Note the fds and credentials received by a read ending at stream
+    // offset `end`.
+    fn read_done(&mut self, end: u64) {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            if let Some(credentials) = cmsg::credentials(self.cmsg.clone().freeze()) {
+                self.credentials = Some(credentials);
+            }
+        }
+        let mut fds = Vec::new();
+        while let Some(more) = self.take_fds() {
+            fds.extend_from_slice(&more);
+        }
+        if !fds.is_empty() {
+            self.received.push_back((end, fds));
+        }
+    }
+
+    // Hand `item`, which ends at stream offset `end`, the fds sent with it.
+    // Fds sent with a message arrive with its first bytes, and a read never
+    // continues past a message sent with fds, which is sent on its own.  So
+    // fds are a message's if it's the first to end at or after the end of
+    // the read they arrived with.  Any a message doesn't expect are closed,
+    // so a peer can't queue fds for a later message.  `item` was completed
+    // by the last read, so it also gets that read's credentials.
+    fn attach_to<T: AssocRawPlatformHandle>(&mut self, item: &mut T, end: u64) -> io::Result<()> {
+        if let Some(credentials) = self.credentials {
+            item.set_peer_credentials(credentials);
+        }
+        let mut fds = Vec::new();
+        while self
+            .received
+            .front()
+            .map_or(false, |&(read_end, _)| read_end <= end)
+        {
+            fds.extend(self.received.pop_front().unwrap().1);
+        }
+        let expected = item.platform_handle_count();
+        let fds = expect_platform_handles(fds, expected)?;
+        if expected > 0 {
+            item.take_platform_handles(
+                fds.into_iter()
+                    .map(|fd| PlatformHandle::new(fd, true))
+                    .collect(),
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Drop for IncomingFds {
+    fn drop(&mut self) {
+        // Close any fds received that no message claimed.
+        while let Some(fds) = self.take_fds() {
+            close_fds(&fds);
+        }
+        for (_, fds) in self.received.drain(..) {
+            close_fds(&fds);
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -71,6 +158,10 @@ pub struct FramedWithPlatformHandles<A, C> {
     // Stream
     read_buf: BytesMut,
     incoming_fds: IncomingFds,
+    // Stream offsets of the start of `read_buf` and of the end of the data
+    // read, used to match fds to messages.
+    decoded: u64,
+    received: u64,
     is_readable: bool,
     eof: bool,
     // Sink
@@ -180,16 +271,21 @@ where
             // readable again, at which point the stream is terminated.
             if self.is_readable {
                 if self.eof {
+                    let len = self.read_buf.len();
                     let mut item = self.codec.decode_eof(&mut self.read_buf)?;
-                    item.take_platform_handles(|| self.incoming_fds.take_fds());
+                    self.decoded += (len - self.read_buf.len()) as u64;
+                    self.incoming_fds.attach_to(&mut item, self.decoded)?;
                     return Ok(Some(item).into());
                 }
 
                 trace!("attempting to decode a frame");
 
-                if let Some(mut item) = self.codec.decode(&mut self.read_buf)? {
+                let len = self.read_buf.len();
+                let item = self.codec.decode(&mut self.read_buf)?;
+                self.decoded += (len - self.read_buf.len()) as u64;
+                if let Some(mut item) = item {
                     trace!("frame decoded from buffer");
-                    item.take_platform_handles(|| self.incoming_fds.take_fds());
+                    self.incoming_fds.attach_to(&mut item, self.decoded)?;
                     return Ok(Some(item).into());
                 }
 
@@ -201,14 +297,24 @@ where
             // Otherwise, try to read more data and try again. Make sure we've
             // got room for at least one byte to read to ensure that we don't
             // get a spurious 0 that looks like EOF
-            let (n, _) = try_ready!(self
+            let (n, flags) = try_ready!(self
                 .io
                 .recv_msg_buf(&mut self.read_buf, self.incoming_fds.cmsg()));
+            // The fds that didn't fit were closed by the kernel, so the
+            // message they were sent with can't be completed.
+            if flags & libc::MSG_CTRUNC != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "control message truncated, platform handles lost",
+                ));
+            }
 
             if n == 0 {
                 self.eof = true;
             }
 
+            self.received += n as u64;
+            self.incoming_fds.read_done(self.received);
             self.is_readable = true;
         }
     }
@@ -239,6 +345,24 @@ where
         // Need to take fd ownership here for `set_frame` to keep fds alive until `do_write`,
         // otherwise fds are closed too early (when `item` is dropped).
         let fds = item.platform_handles();
+        if let Some((ref fds, _)) = fds {
+            if fds.len() > MAX_FDS_PER_MSG {
+                close_fds(fds);
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{} platform handles exceed the limit of {} per message",
+                        fds.len(),
+                        MAX_FDS_PER_MSG
+                    ),
+                ));
+            }
+        }
+        if fds.is_some() {
+            // Messages with fds are sent on their own, so the receiver can
+            // tell which message they belong to.
+            self.set_frame(None);
+        }
         self.codec.encode(item, &mut self.write_buf)?;
 
         let fds = fds.and_then(|fds| {
@@ -284,26 +408,18 @@ pub fn framed_with_platformhandles<A, C>(io: A, codec: C) -> FramedWithPlatformH
         codec,
         read_buf: BytesMut::with_capacity(INITIAL_CAPACITY),
         incoming_fds: IncomingFds::new(FDS_CAPACITY),
+        decoded: 0,
+        received: 0,
         is_readable: false,
         eof: false,
         frames: VecDeque::new(),
         write_buf: BytesMut::with_capacity(INITIAL_CAPACITY),
         outgoing_fds: BytesMut::with_capacity(
-            FDS_CAPACITY * cmsg::space(mem::size_of::<[RawFd; 3]>()),
+            FDS_CAPACITY * cmsg::space(mem::size_of::<[RawFd; MAX_FDS_PER_MSG]>()),
         ),
     }
 }
 
-fn clone_into_array<A, T>(slice: &[T]) -> A
-where
-    A: Sized + Default + AsMut<[T]>,
-    T: Clone,
-{
-    let mut a = Default::default();
-    <A as AsMut<[T]>>::as_mut(&mut a).clone_from_slice(slice);
-    a
-}
-
 fn close_fds(fds: &[RawFd]) {
     for fd in fds {
         unsafe {
@@ -314,7 +430,20 @@ fn close_fds(fds: &[RawFd]) {
 
 #[cfg(test)]
 mod tests {
-    use bytes::BufMut;
+    use super::{close_fds, framed_with_platformhandles};
+    use crate::cmsg;
+    use crate::codec::{Codec, LengthDelimitedCodec};
+    use crate::messages::{ClientMessage, RegisterDeviceCollectionChanged};
+    use crate::msg::send_msg_with_flags;
+    use crate::rpc::Envelope;
+    use crate::{MessageStream, PlatformHandle};
+    use bytes::{BufMut, Bytes, BytesMut};
+    use futures::{Sink, Stream};
+    use iovec::IoVec;
+    use std::io;
+    use std::os::unix::io::{IntoRawFd, RawFd};
+    use tokio::reactor;
+    use tokio::runtime::current_thread;
 
     extern "C" {
         fn cmsghdr_bytes(size: *mut libc::size_t) -> *const u8;
@@ -361,4 +490,128 @@ mod tests {
         assert!(incoming.take_fds().is_some());
         assert!(incoming.take_fds().is_none());
     }
+
+    // Send `body` with `fds` attached, whatever handles it expects.
+    fn send_with_fds(socket: RawFd, body: ClientMessage, fds: &[RawFd]) {
+        let mut codec = LengthDelimitedCodec::<Envelope<ClientMessage>, ()>::default();
+        let mut buf = BytesMut::new();
+        codec.encode(Envelope { id: 1, body }, &mut buf).unwrap();
+        let mut cmsg_buf = BytesMut::with_capacity(cmsg::space(std::mem::size_of_val(fds)));
+        let cmsg = if fds.is_empty() {
+            Bytes::new()
+        } else {
+            cmsg::builder(&mut cmsg_buf).rights(fds).finish().unwrap()
+        };
+        let iov: &IoVec = (&buf[..]).into();
+        assert_eq!(
+            send_msg_with_flags(socket, &[iov], &cmsg, 0).unwrap(),
+            buf.len()
+        );
+    }
+
+    fn pipe() -> (RawFd, RawFd) {
+        let mut pipe = [0; 2];
+        assert_eq!(unsafe { libc::pipe(pipe.as_mut_ptr()) }, 0);
+        (pipe[0], pipe[1])
+    }
+
+    #[test]
+    fn too_many_fds_are_refused() {
+        let (sender, _receiver) = MessageStream::anonymous_ipc_pair().unwrap();
+        let mut rt = current_thread::Runtime::new().unwrap();
+        let handle = reactor::Handle::default();
+        let mut framed = framed_with_platformhandles(
+            sender.into_tokio_ipc(&handle).unwrap(),
+            LengthDelimitedCodec::<Envelope<ClientMessage>, ()>::default(),
+        );
+        let pipes: Vec<_> = (0..2).map(|_| pipe()).collect();
+        let handles = pipes
+            .iter()
+            .flat_map(|&(read, write)| vec![read, write])
+            .map(|fd| PlatformHandle::new(fd, true))
+            .collect();
+        let body =
+            ClientMessage::ContextSetupDeviceCollectionCallback(RegisterDeviceCollectionChanged {
+                platform_handles: handles,
+                target_pid: 0,
+            });
+        let err = framed.start_send(Envelope { id: 1, body }).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        // Sent anyway, fds beyond the receiver's room are lost, and it fails
+        // rather than look for them later.
+        let (sender, receiver) = MessageStream::anonymous_ipc_pair().unwrap();
+        let sender = sender.into_raw_fd();
+        let (pipe_read, pipe_write) = pipe();
+        send_with_fds(
+            sender,
+            ClientMessage::ClientDisconnected,
+            &[pipe_write; 250],
+        );
+        let framed = framed_with_platformhandles(
+            receiver.into_tokio_ipc(&handle).unwrap(),
+            LengthDelimitedCodec::<(), Envelope<ClientMessage>>::default(),
+        );
+        let err = rt
+            .block_on(framed.into_future())
+            .map(|_| ())
+            .map_err(|(e, _)| e)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        close_fds(&[pipe_read, pipe_write, sender]);
+    }
+
+    #[test]
+    fn unexpected_fds_are_closed() {
+        let (pipe_read, pipe_write) = pipe();
+        assert_eq!(
+            unsafe { libc::fcntl(pipe_read, libc::F_SETFL, libc::O_NONBLOCK) },
+            0
+        );
+
+        let (sender, receiver) = MessageStream::anonymous_ipc_pair().unwrap();
+        let sender = sender.into_raw_fd();
+        // A message carrying no handles, sent with one, then a message
+        // expecting one, sent without.
+        send_with_fds(sender, ClientMessage::ClientDisconnected, &[pipe_write]);
+        unsafe { libc::close(pipe_write) };
+        let expects_handle =
+            ClientMessage::ContextSetupDeviceCollectionCallback(RegisterDeviceCollectionChanged {
+                platform_handles: Vec::new(),
+                target_pid: 0,
+            });
+        send_with_fds(sender, expects_handle, &[]);
+
+        let mut rt = current_thread::Runtime::new().unwrap();
+        let receiver = receiver
+            .into_tokio_ipc(&reactor::Handle::default())
+            .unwrap();
+        let framed = framed_with_platformhandles(
+            receiver,
+            LengthDelimitedCodec::<(), Envelope<ClientMessage>>::default(),
+        );
+
+        let (msg, framed) = rt
+            .block_on(framed.into_future())
+            .map_err(|(e, _)| e)
+            .unwrap();
+        match msg.unwrap().body {
+            ClientMessage::ClientDisconnected => {}
+            body => panic!("unexpected message {:?}", body),
+        }
+        // The fd sent with it was closed, leaving the pipe without writers.
+        let mut byte = 0u8;
+        assert_eq!(
+            unsafe { libc::read(pipe_read, &mut byte as *mut u8 as *mut _, 1) },
+            0
+        );
+        // Rather than handed to the next message.
+        assert!(rt.block_on(framed.into_future()).is_err());
+
+        unsafe {
+            libc::close(pipe_read);
+            libc::close(sender);
+        }
+    }
 }
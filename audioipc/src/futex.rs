@@ -0,0 +1,47 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details
+
+// Waiting on a word of shared memory until another process changes it.
+// Wakeups may be spurious, so callers must recheck the word.
+
+use std::ptr;
+use std::sync::atomic::AtomicU32;
+use std::time::Duration;
+
+/// Wait up to `timeout` for `word` to change from `expected`.
+pub fn wait(word: &AtomicU32, expected: u32, timeout: Duration) {
+    let timeout = libc::timespec {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_nsec: timeout.subsec_nanos() as libc::c_long,
+    };
+    // The word is shared between processes, so FUTEX_PRIVATE_FLAG mustn't
+    // be used.
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            word as *const AtomicU32,
+            libc::FUTEX_WAIT,
+            expected,
+            &timeout as *const libc::timespec,
+            ptr::null::<u32>(),
+            0,
+        );
+    }
+}
+
+/// Wake every process waiting on `word`.
+pub fn wake(word: &AtomicU32) {
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            word as *const AtomicU32,
+            libc::FUTEX_WAKE,
+            libc::c_int::max_value(),
+            ptr::null::<libc::timespec>(),
+            ptr::null::<u32>(),
+            0,
+        );
+    }
+}
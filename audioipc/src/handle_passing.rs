@@ -4,7 +4,8 @@
 // accompanying file LICENSE for details
 
 use crate::codec::Codec;
-use crate::messages::AssocRawPlatformHandle;
+use crate::messages::{expect_platform_handles, AssocRawPlatformHandle};
+use crate::PlatformHandle;
 use bytes::{Bytes, BytesMut, IntoBuf};
 use futures::{task, AsyncSink, Poll, Sink, StartSend, Stream};
 use std::collections::VecDeque;
@@ -117,14 +118,16 @@ where
             // readable again, at which point the stream is terminated.
             if self.is_readable {
                 if self.eof {
-                    let item = self.codec.decode_eof(&mut self.read_buf)?;
+                    let mut item = self.codec.decode_eof(&mut self.read_buf)?;
+                    check_handles(&mut item)?;
                     return Ok(Some(item).into());
                 }
 
                 trace!("attempting to decode a frame");
 
-                if let Some(item) = self.codec.decode(&mut self.read_buf)? {
+                if let Some(mut item) = self.codec.decode(&mut self.read_buf)? {
                     trace!("frame decoded from buffer");
+                    check_handles(&mut item)?;
                     return Ok(Some(item).into());
                 }
 
@@ -179,21 +182,27 @@ where
         let handles = item.platform_handles();
         if let Some((handles, target_pid)) = handles {
             // TODO: This could leak target handles if a duplicate fails - make this more robust.
-            let remote_handles = unsafe {
-                // Attempt to duplicate all 3 handles before checking
-                // result, since we rely on duplicate_platformhandle closing
-                // our source handles.
-                let r1 = duplicate_platformhandle(handles[0], Some(target_pid), true);
-                let r2 = duplicate_platformhandle(handles[1], Some(target_pid), true);
-                let r3 = duplicate_platformhandle(handles[2], Some(target_pid), true);
-                [r1?, r2?, r3?]
-            };
+            // Attempt to duplicate all handles before checking results,
+            // since we rely on duplicate_platformhandle closing our source
+            // handles.
+            let results: Vec<_> = handles
+                .iter()
+                .map(|&h| unsafe { duplicate_platformhandle(h, Some(target_pid), true) })
+                .collect();
+            let remote_handles = results.into_iter().collect::<Result<Vec<_>, _>>()?;
             trace!(
                 "item handles: {:?} remote_handles: {:?}",
                 handles,
                 remote_handles
             );
-            item.take_platform_handles(|| Some(remote_handles));
+            // The remote handles belong to the target process, so mustn't be
+            // closed here.
+            item.take_platform_handles(
+                remote_handles
+                    .into_iter()
+                    .map(|h| PlatformHandle::new(h, false))
+                    .collect(),
+            );
         }
 
         self.codec.encode(item, &mut self.write_buf)?;
@@ -226,6 +235,27 @@ where
     }
 }
 
+// Handles arrive already duplicated into this process as part of the
+// message, so only the count needs checking.  They're owned by this
+// process, as when deserialized (see `PlatformHandleVisitor`), so are
+// closed if the message is dropped without its handles being taken.  Only
+// the handles sent, which belong to the target process, are unowned.
+fn check_handles<T: AssocRawPlatformHandle>(item: &mut T) -> io::Result<()> {
+    let expected = item.platform_handle_count();
+    if expected == 0 {
+        return Ok(());
+    }
+    let handles = item.platform_handles().map_or_else(Vec::new, |(h, _)| h);
+    let handles = expect_platform_handles(handles, expected)?;
+    item.take_platform_handles(
+        handles
+            .into_iter()
+            .map(|h| PlatformHandle::new(h, true))
+            .collect(),
+    );
+    Ok(())
+}
+
 pub fn framed_with_platformhandles<A, C>(io: A, codec: C) -> FramedWithPlatformHandles<A, C> {
     FramedWithPlatformHandles {
         io,
@@ -16,8 +16,11 @@ extern crate futures;
 extern crate tokio_io;
 
 mod async_msg;
-#[cfg(unix)]
+pub mod capture;
+#[cfg(all(unix, not(feature = "fuzzing")))]
 mod cmsg;
+#[cfg(all(unix, feature = "fuzzing"))]
+pub mod cmsg;
 pub mod codec;
 pub mod core;
 #[allow(deprecated)]
@@ -31,9 +34,13 @@ pub mod handle_passing;
 #[cfg(windows)]
 pub use handle_passing as platformhandle_passing;
 pub mod frame;
+#[cfg(target_os = "linux")]
+mod futex;
 pub mod messages;
 #[cfg(unix)]
 mod msg;
+pub mod region;
+pub mod ringbuf;
 pub mod rpc;
 pub mod shm;
 
@@ -46,8 +53,9 @@ mod tokio_named_pipes;
 
 pub use crate::messages::{ClientMessage, ServerMessage};
 
-// TODO: Remove hardcoded size and allow allocation based on cubeb backend requirements.
-pub const SHM_AREA_SIZE: usize = 2 * 1024 * 1024;
+// Upper bound on the size of the audio sections of a stream's shared memory
+// region.
+pub const MAX_SHM_AREA_SIZE: usize = 16 * 1024 * 1024;
 
 #[cfg(unix)]
 use std::os::unix::io::IntoRawFd;
@@ -162,6 +170,15 @@ impl PlatformHandle {
         let dup = unsafe { platformhandle_passing::duplicate_platformhandle(h, None, false) }?;
         Ok(PlatformHandle::new(dup, true))
     }
+
+    #[cfg(unix)]
+    pub fn duplicate(h: PlatformHandleType) -> Result<PlatformHandle, std::io::Error> {
+        let dup = unsafe { libc::fcntl(h, libc::F_DUPFD_CLOEXEC, 0) };
+        if dup < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(PlatformHandle::new(dup, true))
+    }
 }
 
 impl Drop for PlatformHandle {
@@ -183,6 +200,16 @@ unsafe fn close_platformhandle(handle: PlatformHandleType) {
     winapi::um::handleapi::CloseHandle(handle);
 }
 
+/// Identity of the process at the other end of a `MessageStream`, as
+/// reported by the operating system rather than by the peer itself.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerCredentials {
+    /// Not reported on every platform.
+    pub pid: Option<u32>,
+    pub uid: u32,
+    pub gid: u32,
+}
+
 #[cfg(unix)]
 pub mod messagestream_unix;
 #[cfg(unix)]
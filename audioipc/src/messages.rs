@@ -3,14 +3,20 @@
 // This program is made available under an ISC-style license.  See the
 // accompanying file LICENSE for details
 
+use crate::codec::{MAX_CALLBACK_MESSAGE_LEN, MAX_MESSAGE_LEN};
+use crate::rpc::Envelope;
+use crate::PeerCredentials;
 use crate::PlatformHandle;
 use crate::PlatformHandleType;
 #[cfg(target_os = "linux")]
 use audio_thread_priority::RtPriorityThreadInfo;
 use cubeb::{self, ffi};
 use std::ffi::{CStr, CString};
+use std::io;
 use std::os::raw::{c_char, c_int, c_uint};
 use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Device {
@@ -131,6 +137,24 @@ pub struct StreamParams {
     pub prefs: ffi::cubeb_stream_prefs,
 }
 
+impl StreamParams {
+    /// Size of a frame in bytes, or `None` for an unknown sample format.
+    pub fn frame_size(&self) -> Option<usize> {
+        let sample_size = match self.format {
+            ffi::CUBEB_SAMPLE_S16LE | ffi::CUBEB_SAMPLE_S16BE => 2,
+            ffi::CUBEB_SAMPLE_FLOAT32LE | ffi::CUBEB_SAMPLE_FLOAT32BE => 4,
+            _ => return None,
+        };
+        Some(sample_size * self.channels as usize)
+    }
+
+    /// Size in bytes of `frames` frames, or `None` for an unknown sample
+    /// format or on overflow.
+    pub fn area_size(&self, frames: u32) -> Option<usize> {
+        self.frame_size()?.checked_mul(frames as usize)
+    }
+}
+
 impl<'a> From<&'a cubeb::StreamParamsRef> for StreamParams {
     fn from(x: &cubeb::StreamParamsRef) -> StreamParams {
         unsafe { *(x.as_ptr() as *mut StreamParams) }
@@ -141,9 +165,19 @@ impl<'a> From<&'a cubeb::StreamParamsRef> for StreamParams {
 pub struct StreamCreateParams {
     pub input_stream_params: Option<StreamParams>,
     pub output_stream_params: Option<StreamParams>,
+    // Number of frames of output the client renders ahead into a shared
+    // memory ring buffer.  Requires `FEATURE_SHM_RING_BUFFER` and an
+    // output-only stream.
+    pub output_ring_buffer_frames: Option<u32>,
+    // Signal data callbacks through the shared memory region rather than
+    // with `CallbackReq::Data`.  Requires `FEATURE_SHM_DATA_SIGNAL`, and
+    // excludes an output ring buffer.
+    pub shm_data_signal: bool,
+    // Requested latency, used to size the shared memory areas.
+    pub latency_frames: u32,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct StreamInitParams {
     pub stream_name: Option<Vec<u8>>,
     pub input_device: usize,
@@ -178,22 +212,109 @@ fn opt_str(v: Option<Vec<u8>>) -> *mut c_char {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct StreamCreate {
     pub token: usize,
-    pub platform_handles: [PlatformHandle; 3],
+    // The callback connection and the stream's shared memory region.
+    pub platform_handles: Vec<PlatformHandle>,
     pub target_pid: u32,
+    // Size of the region passed in `platform_handles`.  The placement of
+    // its sections is described by the region's header (see `region`).
+    pub shm_size: usize,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RegisterDeviceCollectionChanged {
-    pub platform_handles: [PlatformHandle; 3],
+    // The device collection callback connection.
+    pub platform_handles: Vec<PlatformHandle>,
     pub target_pid: u32,
 }
 
+/// Version of the protocol spoken over the client/server connection.  Bump
+/// this whenever the layout of any message exchanged after the handshake
+/// changes.
+pub const PROTOCOL_VERSION: u32 = 9;
+
+/// Output streams may be fed from a shared memory ring buffer filled ahead
+/// by the client instead of a callback RPC per data callback.
+pub const FEATURE_SHM_RING_BUFFER: u32 = 1;
+
+/// Data callbacks may be signalled with futexes in the stream's shared
+/// memory region instead of a callback RPC per data callback.  Linux only.
+pub const FEATURE_SHM_DATA_SIGNAL: u32 = 2;
+
+/// Bitmask of optional protocol features supported by this build.  Feature
+/// bits are only used on a connection if both peers advertise them.
+#[cfg(target_os = "linux")]
+pub const SUPPORTED_FEATURES: u32 = FEATURE_SHM_RING_BUFFER | FEATURE_SHM_DATA_SIGNAL;
+#[cfg(not(target_os = "linux"))]
+pub const SUPPORTED_FEATURES: u32 = FEATURE_SHM_RING_BUFFER;
+
+// The handshake messages (`ServerMessage::ClientConnect`,
+// `ClientMessage::ClientConnected` and `ClientMessage::ClientRejected`) must
+// remain the first variants of their enums and their payloads, along with
+// the `rpc::Envelope` they are sent in, must never change, so that peers
+// built from different revisions can always decode them.
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClientConnectParams {
+    pub pid: u32,
+    pub protocol_version: u32,
+    pub features: u32,
+    pub max_message_len: u32,
+    /// Credentials the operating system reported for the sender, filled in
+    /// on receipt where the platform provides them.  Never sent.
+    #[serde(skip)]
+    pub peer: Option<PeerCredentials>,
+}
+
+impl ClientConnectParams {
+    pub fn new(pid: u32) -> ClientConnectParams {
+        ClientConnectParams {
+            pid,
+            protocol_version: PROTOCOL_VERSION,
+            features: SUPPORTED_FEATURES,
+            max_message_len: MAX_MESSAGE_LEN as u32,
+            peer: None,
+        }
+    }
+}
+
+/// Connection parameters agreed during the handshake.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ConnectionParams {
+    pub protocol_version: u32,
+    pub features: u32,
+    pub max_message_len: u32,
+}
+
+impl ConnectionParams {
+    /// Negotiate connection parameters for a connecting client.  Returns
+    /// `None` if the client speaks an incompatible protocol version.
+    pub fn negotiate(client: &ClientConnectParams) -> Option<ConnectionParams> {
+        if client.protocol_version != PROTOCOL_VERSION {
+            return None;
+        }
+        Some(ConnectionParams {
+            protocol_version: PROTOCOL_VERSION,
+            features: client.features & SUPPORTED_FEATURES,
+            // No lower than the largest control message, which includes
+            // the reply to this handshake.
+            max_message_len: client
+                .max_message_len
+                .max(MAX_CALLBACK_MESSAGE_LEN as u32)
+                .min(MAX_MESSAGE_LEN as u32),
+        })
+    }
+
+    pub fn has_feature(&self, feature: u32) -> bool {
+        self.features & feature == feature
+    }
+}
+
 // Client -> Server messages.
 // TODO: Callbacks should be different messages types so
 // ServerConn::process_msg doesn't have a catch-all case.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ServerMessage {
-    ClientConnect(u32),
+    ClientConnect(ClientConnectParams),
     ClientDisconnect,
 
     ContextGetBackendId,
@@ -217,6 +338,7 @@ pub enum ServerMessage {
     StreamSetName(usize, CString),
     StreamGetCurrentDevice(usize),
     StreamRegisterDeviceChangeCallback(usize, bool),
+    StreamGetStats(usize),
 
     #[cfg(target_os = "linux")]
     PromoteThreadToRealTime([u8; std::mem::size_of::<RtPriorityThreadInfo>()]),
@@ -226,7 +348,9 @@ pub enum ServerMessage {
 // TODO: Streams need id.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ClientMessage {
-    ClientConnected,
+    ClientConnected(ConnectionParams),
+    // Carries the server's protocol version.
+    ClientRejected(u32),
     ClientDisconnected,
 
     ContextBackendId(String),
@@ -250,13 +374,25 @@ pub enum ClientMessage {
     StreamNameSet,
     StreamCurrentDevice(Device),
     StreamRegisterDeviceChangeCallback,
+    StreamStats(CallbackStats),
 
     #[cfg(target_os = "linux")]
     ThreadPromoted,
 
+    // The request would exceed one of the client's resource limits.
+    LimitExceeded(ClientLimit),
     Error(c_int),
 }
 
+/// A per-client resource limit enforced by the server.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ClientLimit {
+    Streams,
+    SharedMemory,
+    DeviceCollectionSetups,
+    MessageRate,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub enum CallbackReq {
     Data {
@@ -275,6 +411,92 @@ pub enum CallbackResp {
     DeviceChange,
 }
 
+/// Number of buckets in a `CallbackStats` latency histogram.
+pub const LATENCY_HISTOGRAM_BUCKETS: usize = 16;
+
+/// Statistics on a stream's data callbacks, recorded separately by the
+/// server and the client.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct CallbackStats {
+    pub callbacks: u64,
+    /// Callbacks answered with fewer frames than requested.
+    pub short_writes: u64,
+    pub errors: u64,
+    /// Callbacks the client failed to supply audio for in time, played as
+    /// silence.  Server only.
+    pub missed_deadlines: u64,
+    /// Time taken to answer callbacks: the IPC round-trip on the server,
+    /// the data callback itself on the client.  Bucket `i` counts times
+    /// below 2^i microseconds not counted by an earlier bucket; the last
+    /// bucket also counts anything slower.
+    pub latency_histogram: [u64; LATENCY_HISTOGRAM_BUCKETS],
+}
+
+impl CallbackStats {
+    pub fn record_latency(&mut self, latency: Duration) {
+        self.latency_histogram[latency_bucket(latency)] += 1;
+    }
+}
+
+fn latency_bucket(latency: Duration) -> usize {
+    let micros = latency.as_micros().min(u128::from(u64::max_value())) as u64;
+    let bucket = (64 - micros.leading_zeros()) as usize;
+    bucket.min(LATENCY_HISTOGRAM_BUCKETS - 1)
+}
+
+/// `CallbackStats` recorded without locking, so the realtime callback
+/// thread never waits on a thread taking a snapshot.
+#[derive(Debug, Default)]
+pub struct AtomicCallbackStats {
+    callbacks: AtomicU64,
+    short_writes: AtomicU64,
+    errors: AtomicU64,
+    missed_deadlines: AtomicU64,
+    latency_histogram: [AtomicU64; LATENCY_HISTOGRAM_BUCKETS],
+}
+
+impl AtomicCallbackStats {
+    pub fn record_callback(&self) {
+        self.callbacks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_short_write(&self) {
+        self.short_writes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_missed_deadline(&self) {
+        self.missed_deadlines.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_latency(&self, latency: Duration) {
+        self.latency_histogram[latency_bucket(latency)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The counts are read one at a time, so a snapshot taken during a
+    /// callback may include only some of what it records.
+    pub fn snapshot(&self) -> CallbackStats {
+        let mut stats = CallbackStats {
+            callbacks: self.callbacks.load(Ordering::Relaxed),
+            short_writes: self.short_writes.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            missed_deadlines: self.missed_deadlines.load(Ordering::Relaxed),
+            ..CallbackStats::default()
+        };
+        for (count, bucket) in stats
+            .latency_histogram
+            .iter_mut()
+            .zip(self.latency_histogram.iter())
+        {
+            *count = bucket.load(Ordering::Relaxed);
+        }
+        stats
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub enum DeviceCollectionReq {
     DeviceChange(ffi::cubeb_device_type),
@@ -285,79 +507,135 @@ pub enum DeviceCollectionResp {
     DeviceChange,
 }
 
+/// Number of platform handles carried by a `StreamCreated` message.
+pub const STREAM_CREATED_HANDLES: usize = 2;
+/// Number of platform handles carried by a
+/// `ContextSetupDeviceCollectionCallback` message.
+pub const DEVICE_COLLECTION_HANDLES: usize = 1;
+
 pub trait AssocRawPlatformHandle {
-    fn platform_handles(&self) -> Option<([PlatformHandleType; 3], u32)> {
+    /// Release the handles to send with this message, along with the pid
+    /// of the process receiving them.
+    fn platform_handles(&self) -> Option<(Vec<PlatformHandleType>, u32)> {
         None
     }
 
-    fn take_platform_handles<F>(&mut self, f: F)
-    where
-        F: FnOnce() -> Option<[PlatformHandleType; 3]>,
-    {
-        assert!(f().is_none());
+    /// Number of handles a message of this type carries.
+    fn platform_handle_count(&self) -> usize {
+        0
+    }
+
+    /// Replace the message's handles with `handles`, which holds
+    /// `platform_handle_count()` entries.
+    fn take_platform_handles(&mut self, handles: Vec<PlatformHandle>) {
+        assert!(handles.is_empty());
     }
+
+    /// Note the credentials the operating system reported for the process
+    /// that sent this message.
+    fn set_peer_credentials(&mut self, _credentials: PeerCredentials) {}
 }
 
-impl AssocRawPlatformHandle for ServerMessage {}
+/// Check `handles` received with a message expecting `expected` handles.
+/// Surplus handles are closed.  If too few were received, they are all
+/// closed and an error is returned.
+pub fn expect_platform_handles(
+    mut handles: Vec<PlatformHandleType>,
+    expected: usize,
+) -> io::Result<Vec<PlatformHandleType>> {
+    if handles.len() != expected {
+        warn!(
+            "Received {} platform handles, expected {}",
+            handles.len(),
+            expected
+        );
+    }
+    let keep = if handles.len() < expected {
+        0
+    } else {
+        expected
+    };
+    for handle in handles.drain(keep..) {
+        unsafe { crate::close_platformhandle(handle) };
+    }
+    if handles.len() < expected {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "missing platform handles",
+        ));
+    }
+    Ok(handles)
+}
+
+impl AssocRawPlatformHandle for ServerMessage {
+    fn set_peer_credentials(&mut self, credentials: PeerCredentials) {
+        if let ServerMessage::ClientConnect(ref mut params) = *self {
+            params.peer = Some(credentials);
+        }
+    }
+}
 
 impl AssocRawPlatformHandle for ClientMessage {
-    fn platform_handles(&self) -> Option<([PlatformHandleType; 3], u32)> {
-        unsafe {
-            match *self {
-                ClientMessage::StreamCreated(ref data) => Some((
-                    [
-                        data.platform_handles[0].into_raw(),
-                        data.platform_handles[1].into_raw(),
-                        data.platform_handles[2].into_raw(),
-                    ],
-                    data.target_pid,
-                )),
-                ClientMessage::ContextSetupDeviceCollectionCallback(ref data) => Some((
-                    [
-                        data.platform_handles[0].into_raw(),
-                        data.platform_handles[1].into_raw(),
-                        data.platform_handles[2].into_raw(),
-                    ],
-                    data.target_pid,
-                )),
-                _ => None,
+    fn platform_handles(&self) -> Option<(Vec<PlatformHandleType>, u32)> {
+        let (handles, target_pid) = match *self {
+            ClientMessage::StreamCreated(ref data) => (&data.platform_handles, data.target_pid),
+            ClientMessage::ContextSetupDeviceCollectionCallback(ref data) => {
+                (&data.platform_handles, data.target_pid)
             }
+            _ => return None,
+        };
+        let handles = handles.iter().map(|h| unsafe { h.into_raw() }).collect();
+        Some((handles, target_pid))
+    }
+
+    fn platform_handle_count(&self) -> usize {
+        match *self {
+            ClientMessage::StreamCreated(_) => STREAM_CREATED_HANDLES,
+            ClientMessage::ContextSetupDeviceCollectionCallback(_) => DEVICE_COLLECTION_HANDLES,
+            _ => 0,
         }
     }
 
-    fn take_platform_handles<F>(&mut self, f: F)
-    where
-        F: FnOnce() -> Option<[PlatformHandleType; 3]>,
-    {
-        let owned = cfg!(unix);
+    fn take_platform_handles(&mut self, handles: Vec<PlatformHandle>) {
+        assert_eq!(handles.len(), self.platform_handle_count());
         match *self {
-            ClientMessage::StreamCreated(ref mut data) => {
-                let handles =
-                    f().expect("platform_handles must be available when processing StreamCreated");
-                data.platform_handles = [
-                    PlatformHandle::new(handles[0], owned),
-                    PlatformHandle::new(handles[1], owned),
-                    PlatformHandle::new(handles[2], owned),
-                ]
-            }
+            ClientMessage::StreamCreated(ref mut data) => data.platform_handles = handles,
             ClientMessage::ContextSetupDeviceCollectionCallback(ref mut data) => {
-                let handles = f().expect("platform_handles must be available when processing ContextSetupDeviceCollectionCallback");
-                data.platform_handles = [
-                    PlatformHandle::new(handles[0], owned),
-                    PlatformHandle::new(handles[1], owned),
-                    PlatformHandle::new(handles[2], owned),
-                ]
+                data.platform_handles = handles
             }
             _ => {}
         }
     }
 }
 
+impl<T: AssocRawPlatformHandle> AssocRawPlatformHandle for Envelope<T> {
+    fn platform_handles(&self) -> Option<(Vec<PlatformHandleType>, u32)> {
+        self.body.platform_handles()
+    }
+
+    fn platform_handle_count(&self) -> usize {
+        self.body.platform_handle_count()
+    }
+
+    fn take_platform_handles(&mut self, handles: Vec<PlatformHandle>) {
+        self.body.take_platform_handles(handles)
+    }
+
+    fn set_peer_credentials(&mut self, credentials: PeerCredentials) {
+        self.body.set_peer_credentials(credentials)
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::StreamParams;
+    use super::{
+        AtomicCallbackStats, CallbackStats, ClientConnectParams, ConnectionParams, Envelope,
+        ServerMessage, StreamParams, LATENCY_HISTOGRAM_BUCKETS, MAX_CALLBACK_MESSAGE_LEN,
+        MAX_MESSAGE_LEN, PROTOCOL_VERSION,
+    };
     use cubeb::ffi;
     use std::mem;
+    use std::time::Duration;
 
     #[test]
     fn stream_params_size_check() {
@@ -383,4 +661,106 @@ mod test {
         assert_eq!(params.layout, raw.layout);
         assert_eq!(params.prefs, raw.prefs);
     }
+
+    #[test]
+    fn handshake_compatible() {
+        let client = ClientConnectParams::new(1234);
+        let params = ConnectionParams::negotiate(&client).unwrap();
+        assert_eq!(params.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(params.features, client.features);
+        assert_eq!(params.max_message_len, client.max_message_len);
+    }
+
+    #[test]
+    fn handshake_negotiates_limits() {
+        let mut client = ClientConnectParams::new(1234);
+        client.features = !0;
+        client.max_message_len = 4096;
+        let params = ConnectionParams::negotiate(&client).unwrap();
+        assert_eq!(params.features, super::SUPPORTED_FEATURES);
+        assert_eq!(params.max_message_len, 4096);
+
+        // Clamped to what every message can be encoded in.
+        client.max_message_len = 0;
+        let params = ConnectionParams::negotiate(&client).unwrap();
+        assert_eq!(params.max_message_len, MAX_CALLBACK_MESSAGE_LEN as u32);
+        client.max_message_len = u32::max_value();
+        let params = ConnectionParams::negotiate(&client).unwrap();
+        assert_eq!(params.max_message_len, MAX_MESSAGE_LEN as u32);
+    }
+
+    #[test]
+    fn handshake_rejects_version_mismatch() {
+        let mut client = ClientConnectParams::new(1234);
+        client.protocol_version = PROTOCOL_VERSION + 1;
+        assert!(ConnectionParams::negotiate(&client).is_none());
+    }
+
+    #[test]
+    fn handshake_is_first_variant() {
+        // Peers from other revisions rely on the handshake being variant 0,
+        // sent as the first request on the connection.
+        let msg = Envelope {
+            id: 0,
+            body: ServerMessage::ClientConnect(ClientConnectParams::new(1234)),
+        };
+        let encoded = bincode::serialize(&msg).unwrap();
+        assert_eq!(&encoded[..8], &[0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn latency_histogram_buckets() {
+        let mut stats = CallbackStats::default();
+        stats.record_latency(Duration::from_nanos(999));
+        stats.record_latency(Duration::from_micros(1));
+        stats.record_latency(Duration::from_micros(3));
+        stats.record_latency(Duration::from_micros(4));
+        stats.record_latency(Duration::from_secs(60));
+        assert_eq!(&stats.latency_histogram[..4], &[1, 1, 1, 1]);
+        assert_eq!(stats.latency_histogram[LATENCY_HISTOGRAM_BUCKETS - 1], 1);
+        assert_eq!(stats.latency_histogram.iter().sum::<u64>(), 5);
+    }
+
+    #[test]
+    fn atomic_stats_snapshot() {
+        let stats = AtomicCallbackStats::default();
+        stats.record_callback();
+        stats.record_callback();
+        stats.record_short_write();
+        stats.record_missed_deadline();
+        stats.record_latency(Duration::from_micros(3));
+        let mut expected = CallbackStats {
+            callbacks: 2,
+            short_writes: 1,
+            missed_deadlines: 1,
+            ..CallbackStats::default()
+        };
+        expected.record_latency(Duration::from_micros(3));
+        assert_eq!(stats.snapshot(), expected);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn expect_platform_handles_closes_surplus() {
+        fn pipe() -> [libc::c_int; 2] {
+            let mut fds = [0; 2];
+            assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+            fds
+        }
+        fn is_open(fd: libc::c_int) -> bool {
+            unsafe { libc::fcntl(fd, libc::F_GETFD) != -1 }
+        }
+
+        let [a, b] = pipe();
+        let kept = super::expect_platform_handles(vec![a, b], 1).unwrap();
+        assert_eq!(kept, vec![a]);
+        assert!(is_open(a));
+        assert!(!is_open(b));
+        unsafe { libc::close(a) };
+
+        let [a, b] = pipe();
+        assert!(super::expect_platform_handles(vec![a, b], 3).is_err());
+        assert!(!is_open(a));
+        assert!(!is_open(b));
+    }
 }
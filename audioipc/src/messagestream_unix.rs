@@ -4,8 +4,10 @@
 // accompanying file LICENSE for details
 
 use super::tokio_uds_stream as tokio_uds;
+use super::PeerCredentials;
 use futures::Poll;
 use mio::Ready;
+use std::io;
 use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
 use std::os::unix::net;
 use tokio_io::{AsyncRead, AsyncWrite};
@@ -29,6 +31,34 @@ impl MessageStream {
         MessageStream::new(net::UnixStream::from_raw_fd(raw))
     }
 
+    /// Credentials of the peer as recorded by the kernel when the
+    /// connection was established.  For a pair created by
+    /// `anonymous_ipc_pair` this is the creating process, whichever process
+    /// the other end is later handed to.
+    pub fn peer_credentials(&self) -> io::Result<PeerCredentials> {
+        peer_credentials(self.0.as_raw_fd())
+    }
+
+    /// Have the kernel attach the credentials of the sending process to
+    /// everything received on this end, whichever process holds the other.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn set_pass_credentials(&self) -> io::Result<()> {
+        let on: libc::c_int = 1;
+        let r = unsafe {
+            libc::setsockopt(
+                self.0.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PASSCRED,
+                &on as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if r != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
     pub fn into_tokio_ipc(
         self,
         handle: &tokio::reactor::Handle,
@@ -45,6 +75,44 @@ impl IntoRawFd for MessageStream {
     }
 }
 
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_credentials(fd: RawFd) -> io::Result<PeerCredentials> {
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let r = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if r != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(PeerCredentials {
+        pid: Some(cred.pid as u32),
+        uid: cred.uid,
+        gid: cred.gid,
+    })
+}
+
+// getpeereid(3) doesn't report the peer's pid.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn peer_credentials(fd: RawFd) -> io::Result<PeerCredentials> {
+    let mut uid = 0;
+    let mut gid = 0;
+    if unsafe { libc::getpeereid(fd, &mut uid, &mut gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(PeerCredentials {
+        pid: None,
+        uid,
+        gid,
+    })
+}
+
 impl AsyncMessageStream {
     fn new(stream: tokio_uds::UnixStream) -> AsyncMessageStream {
         AsyncMessageStream(stream)
@@ -42,6 +42,14 @@ impl MessageStream {
         MessageStream::new(miow::pipe::NamedPipe::from_raw_handle(raw))
     }
 
+    /// Peer credentials aren't available for named pipes.
+    pub fn peer_credentials(&self) -> std::io::Result<super::PeerCredentials> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "peer credentials not supported",
+        ))
+    }
+
     pub fn into_tokio_ipc(
         self,
         handle: &tokio::reactor::Handle,
@@ -0,0 +1,494 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details
+
+//! Layout of the shared memory region of a stream.
+//!
+//! Each stream shares a single region between the server and the client.
+//! The region starts with a `Header` describing where the input and output
+//! sections lie, written by the server when it creates the region and
+//! validated by the client when it maps it, so the placement of the
+//! sections is never assumed by either side.  Sections start on page
+//! boundaries, so each side can protect the section the other writes from
+//! its own stray writes.  The protection is only that: either side can
+//! undo it, so the server never reads back the input section, and checks
+//! whatever it reads from the client.
+//!
+//! The header also holds a sequence counter for the output section,
+//! advanced by the client each time it has written audio there, so the
+//! server can tell a callback answered without writing output from one
+//! that rendered it.
+//!
+//! On Linux, data callbacks can be signalled through the header instead of
+//! `CallbackReq::Data` messages: the server publishes the next sequence
+//! number with the frames wanted in the request word, and the client
+//! publishes the same sequence number with the frames rendered in the
+//! response word.  Each side waits on the other's word with a futex,
+//! avoiding the socket, the codec and the event loops of the rpc path.  The
+//! server signals no request until the last has been answered, so the
+//! client never sees a request change under it.
+//!
+//! The peer on the other side of the shared memory is not trusted, so the
+//! layout is read from the header once and validated before use.
+
+use crate::errors::*;
+#[cfg(target_os = "linux")]
+use crate::futex;
+use crate::shm::{page_size, Access, SharedMem, SharedMemView};
+use crate::MAX_SHM_AREA_SIZE;
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+#[cfg(target_os = "linux")]
+use std::time::{Duration, Instant};
+use std::{mem, ptr};
+
+const MAGIC: [u8; 4] = *b"AIPR";
+
+/// Version of the header and the placement of sections it describes.  Bump
+/// when either changes.
+pub const LAYOUT_VERSION: u32 = 4;
+
+/// Largest region a client maps.  Sections hold up to about
+/// `MAX_SHM_AREA_SIZE` bytes, and the server may reuse a region of up to
+/// twice the size needed.
+pub const MAX_REGION_SIZE: usize = 5 * MAX_SHM_AREA_SIZE;
+
+// The output section holds a `ringbuf` rather than a callback's audio.
+const FLAG_OUTPUT_RING: u32 = 1;
+// Data callbacks are signalled through the header.
+const FLAG_DATA_SIGNAL: u32 = 2;
+
+#[repr(C)]
+struct Header {
+    magic: [u8; 4],
+    version: u32,
+    header_size: u32,
+    flags: u32,
+    input_offset: u64,
+    input_size: u64,
+    output_offset: u64,
+    output_size: u64,
+    input_frame_size: u32,
+    output_frame_size: u32,
+    output_sequence: AtomicU32,
+    // The sequence number in the low half, and the frames in the high half,
+    // so each is published in a single store.  See `signal_word`.
+    request: AtomicU64,
+    response: AtomicU64,
+}
+
+const HEADER_SIZE: usize = mem::size_of::<Header>();
+
+#[cfg(target_os = "linux")]
+fn signal(seq: u32, frames: u32) -> u64 {
+    (u64::from(frames) << 32) | u64::from(seq)
+}
+
+// Sequence number and frames of a signal.
+fn unpack_signal(signal: u64) -> (u32, u32) {
+    (signal as u32, (signal >> 32) as u32)
+}
+
+// The half of a request or response word holding the sequence number,
+// which is what futexes, being 32 bits, wait on.
+#[cfg(target_os = "linux")]
+fn signal_word(word: &AtomicU64) -> &AtomicU32 {
+    let seq = if cfg!(target_endian = "little") { 0 } else { 1 };
+    unsafe { &*(word as *const AtomicU64 as *const AtomicU32).add(seq) }
+}
+
+fn page_align(size: usize) -> usize {
+    let page = page_size();
+    (size + page - 1) / page * page
+}
+
+/// Placement of the sections of a stream's region.  A section is empty for
+/// a direction the stream doesn't use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Layout {
+    pub input_offset: usize,
+    pub input_size: usize,
+    pub output_offset: usize,
+    pub output_size: usize,
+    pub input_frame_size: u16,
+    pub output_frame_size: u16,
+    /// The output section holds a `ringbuf` filled ahead by the client.
+    pub output_ring: bool,
+    /// Data callbacks are signalled through the header.
+    pub data_signal: bool,
+}
+
+impl Layout {
+    /// Place sections of `input_size` and `output_size` bytes after the
+    /// header.
+    pub fn new(
+        input_size: usize,
+        input_frame_size: u16,
+        output_size: usize,
+        output_frame_size: u16,
+        output_ring: bool,
+        data_signal: bool,
+    ) -> Layout {
+        let input_offset = page_align(HEADER_SIZE);
+        Layout {
+            input_offset,
+            input_size,
+            output_offset: input_offset + page_align(input_size),
+            output_size,
+            input_frame_size,
+            output_frame_size,
+            output_ring,
+            data_signal,
+        }
+    }
+
+    /// Size in bytes of a region holding this layout.
+    pub fn size(&self) -> usize {
+        self.output_offset + page_align(self.output_size)
+    }
+
+    // Check the layout fits a region of `size` bytes.
+    fn validate(&self, size: usize) -> Result<()> {
+        if size > MAX_REGION_SIZE {
+            bail!("region too large");
+        }
+        if self.data_signal && (self.output_ring || !cfg!(target_os = "linux")) {
+            bail!("unsupported data signalling");
+        }
+        let page = page_size();
+        let section_end = |offset: usize, len: usize, frame_size: u16| {
+            if offset < HEADER_SIZE || offset % page != 0 || (len > 0 && frame_size == 0) {
+                return None;
+            }
+            offset.checked_add(len).filter(|&end| end <= size)
+        };
+        let input_end = section_end(self.input_offset, self.input_size, self.input_frame_size);
+        let output_end = section_end(self.output_offset, self.output_size, self.output_frame_size);
+        let (input_end, output_end) = match (input_end, output_end) {
+            (Some(input_end), Some(output_end)) => (input_end, output_end),
+            _ => bail!("region section out of bounds"),
+        };
+        let disjoint = self.input_size == 0
+            || self.output_size == 0
+            || input_end <= self.output_offset
+            || output_end <= self.input_offset;
+        if !disjoint {
+            bail!("region sections overlap");
+        }
+        Ok(())
+    }
+}
+
+/// A stream's region as mapped by this process.  Doesn't own the mapping,
+/// which must outlive it.
+#[derive(Clone, Copy)]
+pub struct Region {
+    header: *const Header,
+    input: Option<SharedMemView>,
+    output: Option<SharedMemView>,
+    layout: Layout,
+}
+
+unsafe impl Send for Region {}
+
+impl Region {
+    /// Write the header for `layout` to a newly created region, mapped
+    /// read-write by `shm`.  The output section is protected from the
+    /// server unless it holds a ring buffer, whose read position the server
+    /// writes.
+    pub unsafe fn init(shm: &mut SharedMem, layout: Layout) -> Result<Region> {
+        layout.validate(shm.size())?;
+        let ptr = shm.get_mut_slice(HEADER_SIZE)?.as_mut_ptr();
+        ptr::write(
+            ptr as *mut Header,
+            Header {
+                magic: MAGIC,
+                version: LAYOUT_VERSION,
+                header_size: HEADER_SIZE as u32,
+                flags: if layout.output_ring {
+                    FLAG_OUTPUT_RING
+                } else {
+                    0
+                } | if layout.data_signal {
+                    FLAG_DATA_SIGNAL
+                } else {
+                    0
+                },
+                input_offset: layout.input_offset as u64,
+                input_size: layout.input_size as u64,
+                output_offset: layout.output_offset as u64,
+                output_size: layout.output_size as u64,
+                input_frame_size: u32::from(layout.input_frame_size),
+                output_frame_size: u32::from(layout.output_frame_size),
+                output_sequence: AtomicU32::new(0),
+                request: AtomicU64::new(0),
+                response: AtomicU64::new(0),
+            },
+        );
+        let output_access = if layout.output_ring {
+            Access::ReadWrite
+        } else {
+            Access::ReadOnly
+        };
+        Region::map(shm, layout, Access::ReadWrite, output_access)
+    }
+
+    /// Validate the header of a region created by the peer, mapped
+    /// read-write by `shm`.  The input section is protected from the
+    /// client's stray writes, see `Access`.
+    pub unsafe fn attach(shm: &mut SharedMem) -> Result<Region> {
+        let header = &*(shm.get_slice(HEADER_SIZE)?.as_ptr() as *const Header);
+        // Read each field once; the peer may modify them later.
+        let magic = ptr::read_volatile(&header.magic);
+        let version = ptr::read_volatile(&header.version);
+        if magic != MAGIC {
+            bail!("not a stream region");
+        }
+        if version != LAYOUT_VERSION {
+            bail!(
+                "region layout version {}, expected {}",
+                version,
+                LAYOUT_VERSION
+            );
+        }
+        if ptr::read_volatile(&header.header_size) as usize != HEADER_SIZE {
+            bail!("invalid region header size");
+        }
+        let field = |value: u64| -> Result<usize> {
+            value
+                .try_into()
+                .map_err(|_| "region header field out of range".into())
+        };
+        let frame_size = |value: u32| -> Result<u16> {
+            value
+                .try_into()
+                .map_err(|_| "region frame size out of range".into())
+        };
+        let flags = ptr::read_volatile(&header.flags);
+        let layout = Layout {
+            input_offset: field(ptr::read_volatile(&header.input_offset))?,
+            input_size: field(ptr::read_volatile(&header.input_size))?,
+            output_offset: field(ptr::read_volatile(&header.output_offset))?,
+            output_size: field(ptr::read_volatile(&header.output_size))?,
+            input_frame_size: frame_size(ptr::read_volatile(&header.input_frame_size))?,
+            output_frame_size: frame_size(ptr::read_volatile(&header.output_frame_size))?,
+            output_ring: flags & FLAG_OUTPUT_RING != 0,
+            data_signal: flags & FLAG_DATA_SIGNAL != 0,
+        };
+        layout.validate(shm.size())?;
+        Region::map(shm, layout, Access::ReadOnly, Access::ReadWrite)
+    }
+
+    unsafe fn map(
+        shm: &mut SharedMem,
+        layout: Layout,
+        input_access: Access,
+        output_access: Access,
+    ) -> Result<Region> {
+        let mut section = |offset: usize, size: usize, access: Access| -> Result<_> {
+            if size == 0 {
+                return Ok(None);
+            }
+            if access == Access::ReadOnly {
+                shm.protect(offset, size, access)?;
+            }
+            match shm.unsafe_view().section(offset, size, access) {
+                Some(view) => Ok(Some(view)),
+                None => bail!("region section out of bounds"),
+            }
+        };
+        let input = section(layout.input_offset, layout.input_size, input_access)?;
+        let output = section(layout.output_offset, layout.output_size, output_access)?;
+        Ok(Region {
+            header: shm.unsafe_view().get_slice(HEADER_SIZE)?.as_ptr() as *const Header,
+            input,
+            output,
+            layout,
+        })
+    }
+
+    fn header(&self) -> &Header {
+        unsafe { &*self.header }
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    /// The input section, written by the server and read by the client.
+    pub fn input(&self) -> Option<SharedMemView> {
+        self.input
+    }
+
+    /// The output section, written by the client and read by the server.
+    pub fn output(&self) -> Option<SharedMemView> {
+        self.output
+    }
+
+    /// Advance the output sequence counter once output has been written.
+    pub fn output_written(&self) {
+        self.header()
+            .output_sequence
+            .fetch_add(1, Ordering::Release);
+    }
+
+    /// Sequence number of the output last written, to compare with after a
+    /// data callback.
+    pub fn output_sequence(&self) -> u32 {
+        self.header().output_sequence.load(Ordering::Acquire)
+    }
+
+    /// Ask the client for a data callback of `nframes` frames, once input
+    /// has been written.  Returns the request's sequence number, or `None`
+    /// if the client hasn't answered the last request yet.
+    #[cfg(target_os = "linux")]
+    pub fn signal_request(&self, nframes: u32) -> Option<u32> {
+        let header = self.header();
+        let (last, _) = unpack_signal(header.request.load(Ordering::Relaxed));
+        let (answered, _) = unpack_signal(header.response.load(Ordering::Acquire));
+        if answered != last {
+            return None;
+        }
+        let seq = last.wrapping_add(1);
+        header
+            .request
+            .store(signal(seq, nframes), Ordering::Release);
+        futex::wake(signal_word(&header.request));
+        Some(seq)
+    }
+
+    /// Wait up to `timeout` for the client to answer request `seq`.
+    /// Returns the frames the client rendered, or `None` on timeout.
+    #[cfg(target_os = "linux")]
+    pub fn wait_response(&self, seq: u32, timeout: Duration) -> Option<i32> {
+        let header = self.header();
+        let deadline = Instant::now() + timeout;
+        loop {
+            let (response, frames) = unpack_signal(header.response.load(Ordering::Acquire));
+            if response == seq {
+                return Some(frames as i32);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            futex::wait(signal_word(&header.response), response, deadline - now);
+        }
+    }
+
+    /// Wait up to `timeout` for a request following request `last`.
+    /// Returns the request's sequence number and the frames wanted, or
+    /// `None` if the wait timed out or was interrupted.
+    #[cfg(target_os = "linux")]
+    pub fn wait_request(&self, last: u32, timeout: Duration) -> Option<(u32, u32)> {
+        let header = self.header();
+        if self.request_sequence() == last {
+            futex::wait(signal_word(&header.request), last, timeout);
+        }
+        let (request, frames) = unpack_signal(header.request.load(Ordering::Acquire));
+        if request == last {
+            return None;
+        }
+        Some((request, frames))
+    }
+
+    /// Answer request `seq` with the `frames` rendered, once output has
+    /// been written.
+    #[cfg(target_os = "linux")]
+    pub fn respond(&self, seq: u32, frames: i32) {
+        let header = self.header();
+        header
+            .response
+            .store(signal(seq, frames as u32), Ordering::Release);
+        futex::wake(signal_word(&header.response));
+    }
+
+    /// Wake a `wait_request` in this process early, e.g. to shut down.
+    #[cfg(target_os = "linux")]
+    pub fn interrupt_wait_request(&self) {
+        futex::wake(signal_word(&self.header().request));
+    }
+
+    /// The sequence number of the latest request.
+    pub fn request_sequence(&self) -> u32 {
+        unpack_signal(self.header().request.load(Ordering::Acquire)).0
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::{Layout, Region, HEADER_SIZE, LAYOUT_VERSION};
+    use crate::shm::{page_size, Access, SharedMem};
+
+    #[test]
+    fn attach_validates_header() {
+        let layout = Layout::new(100, 4, 200, 2, false, false);
+        assert_eq!(layout.input_offset, page_size());
+        assert_eq!(layout.output_offset, 2 * page_size());
+        let (mut shm, handle) =
+            SharedMem::new("audioipc-region-test", layout.size(), Access::ReadWrite).unwrap();
+        let server = unsafe { Region::init(&mut shm, layout).unwrap() };
+        assert!(unsafe { server.output().unwrap().get_mut_slice(1) }.is_err());
+
+        let mut peer =
+            unsafe { SharedMem::from(&handle, layout.size(), Access::ReadWrite).unwrap() };
+        let client = unsafe { Region::attach(&mut peer).unwrap() };
+        assert_eq!(*client.layout(), layout);
+        assert!(unsafe { client.input().unwrap().get_mut_slice(1) }.is_err());
+        unsafe { client.output().unwrap().get_mut_slice(200).unwrap()[199] = 42 };
+        client.output_written();
+        assert_eq!(server.output_sequence(), 1);
+        assert_eq!(
+            unsafe { server.output().unwrap().get_slice(200).unwrap()[199] },
+            42
+        );
+
+        // An unknown layout version, or sections beyond the region, are
+        // rejected.
+        let header = unsafe { shm.unsafe_view().section(0, HEADER_SIZE, Access::ReadWrite) };
+        let mut header = header.unwrap();
+        let bytes = unsafe { header.get_mut_slice(HEADER_SIZE).unwrap() };
+        bytes[4..8].copy_from_slice(&(LAYOUT_VERSION + 1).to_ne_bytes());
+        assert!(unsafe { Region::attach(&mut peer) }.is_err());
+        bytes[4..8].copy_from_slice(&LAYOUT_VERSION.to_ne_bytes());
+        bytes[40..48].copy_from_slice(&(2 * page_size() as u64).to_ne_bytes());
+        assert!(unsafe { Region::attach(&mut peer) }.is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn data_signal_round_trip() {
+        use std::time::Duration;
+
+        let layout = Layout::new(0, 0, 64, 2, false, true);
+        let (mut shm, handle) =
+            SharedMem::new("audioipc-region-test", layout.size(), Access::ReadWrite).unwrap();
+        let server = unsafe { Region::init(&mut shm, layout).unwrap() };
+        let mut peer =
+            unsafe { SharedMem::from(&handle, layout.size(), Access::ReadWrite).unwrap() };
+        let client = unsafe { Region::attach(&mut peer).unwrap() };
+        assert!(client.layout().data_signal);
+
+        let last = client.request_sequence();
+        let responder = std::thread::spawn(move || {
+            // Wakeups may be spurious.
+            let (seq, nframes) = loop {
+                if let Some(request) = client.wait_request(last, Duration::from_secs(5)) {
+                    break request;
+                }
+            };
+            client.respond(seq, nframes as i32 / 2);
+        });
+        let seq = server.signal_request(32).unwrap();
+        assert_eq!(server.wait_response(seq, Duration::from_secs(5)), Some(16));
+        responder.join().unwrap();
+
+        // A request nobody answers times out, and holds off the next.
+        let seq = server.signal_request(32).unwrap();
+        assert_eq!(server.wait_response(seq, Duration::from_millis(10)), None);
+        assert_eq!(server.signal_request(32), None);
+        server.respond(seq, 0);
+        assert_eq!(server.signal_request(32), Some(seq.wrapping_add(1)));
+    }
+}
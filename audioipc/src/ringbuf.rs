@@ -0,0 +1,291 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details
+
+//! Lock-free single producer, single consumer ring buffer for use in shared
+//! memory.
+//!
+//! The shared area starts with a `Header` holding the capacity and the read
+//! and write positions, followed by the data area.  Positions are
+//! free-running byte counters that wrap at 2^32; the capacity is a power of
+//! two so the fill level can always be computed from their difference.
+//!
+//! The peer on the other side of the shared memory is not trusted, so every
+//! value read from the header is validated before use.
+
+use crate::errors::*;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::{cmp, mem, ptr};
+
+#[repr(C)]
+struct Header {
+    capacity: u32,
+    read: AtomicU32,
+    write: AtomicU32,
+    drained: AtomicU32,
+}
+
+const HEADER_SIZE: usize = mem::size_of::<Header>();
+const MAX_CAPACITY: usize = 1 << 31;
+
+/// Number of bytes of shared memory needed to hold a ring buffer of at
+/// least `capacity` bytes.
+pub fn required_size(capacity: usize) -> usize {
+    HEADER_SIZE + capacity.next_power_of_two()
+}
+
+/// Initialize a ring buffer of at least `capacity` bytes in the area at
+/// `ptr`.  Must be called before either side attaches.
+pub unsafe fn init(ptr: *mut u8, size: usize, capacity: usize) -> Result<()> {
+    let capacity = match capacity.checked_next_power_of_two() {
+        Some(c) if c <= MAX_CAPACITY && HEADER_SIZE + c <= size => c,
+        _ => bail!("ring buffer capacity too large"),
+    };
+    if ptr as usize % mem::align_of::<Header>() != 0 {
+        bail!("ring buffer area misaligned");
+    }
+    ptr::write(
+        ptr as *mut Header,
+        Header {
+            capacity: capacity as u32,
+            read: AtomicU32::new(0),
+            write: AtomicU32::new(0),
+            drained: AtomicU32::new(0),
+        },
+    );
+    Ok(())
+}
+
+struct RingBuffer {
+    header: *const Header,
+    data: *mut u8,
+    capacity: u32,
+}
+
+unsafe impl Send for RingBuffer {}
+
+impl RingBuffer {
+    unsafe fn attach(ptr: *mut u8, size: usize) -> Result<RingBuffer> {
+        if size < HEADER_SIZE || ptr as usize % mem::align_of::<Header>() != 0 {
+            bail!("invalid ring buffer area");
+        }
+        let header = ptr as *const Header;
+        // Read the capacity once; the peer may modify it later.
+        let capacity = ptr::read_volatile(&(*header).capacity) as usize;
+        if capacity == 0
+            || !capacity.is_power_of_two()
+            || capacity > MAX_CAPACITY
+            || HEADER_SIZE + capacity > size
+        {
+            bail!("invalid ring buffer capacity {}", capacity);
+        }
+        Ok(RingBuffer {
+            header,
+            data: ptr.add(HEADER_SIZE),
+            capacity: capacity as u32,
+        })
+    }
+
+    fn header(&self) -> &Header {
+        unsafe { &*self.header }
+    }
+
+    // Bytes available to read, or `None` if the positions are inconsistent.
+    fn fill(&self, read: u32, write: u32) -> Option<u32> {
+        let fill = write.wrapping_sub(read);
+        if fill <= self.capacity {
+            Some(fill)
+        } else {
+            None
+        }
+    }
+
+    fn copy_in(&self, pos: u32, src: &[u8]) {
+        let offset = (pos & (self.capacity - 1)) as usize;
+        let first = cmp::min(src.len(), self.capacity as usize - offset);
+        unsafe {
+            ptr::copy_nonoverlapping(src.as_ptr(), self.data.add(offset), first);
+            ptr::copy_nonoverlapping(src[first..].as_ptr(), self.data, src.len() - first);
+        }
+    }
+
+    fn copy_out(&self, pos: u32, dst: &mut [u8]) {
+        let offset = (pos & (self.capacity - 1)) as usize;
+        let first = cmp::min(dst.len(), self.capacity as usize - offset);
+        unsafe {
+            ptr::copy_nonoverlapping(self.data.add(offset), dst.as_mut_ptr(), first);
+            let rest = dst.len() - first;
+            ptr::copy_nonoverlapping(self.data, dst[first..].as_mut_ptr(), rest);
+        }
+    }
+}
+
+/// Writing end of a ring buffer.
+pub struct Producer(RingBuffer);
+
+impl Producer {
+    pub unsafe fn attach(ptr: *mut u8, size: usize) -> Result<Producer> {
+        RingBuffer::attach(ptr, size).map(Producer)
+    }
+
+    /// Number of bytes that can currently be written.
+    pub fn free_space(&self) -> usize {
+        let header = self.0.header();
+        let read = header.read.load(Ordering::Acquire);
+        let write = header.write.load(Ordering::Relaxed);
+        self.0
+            .fill(read, write)
+            .map_or(0, |fill| (self.0.capacity - fill) as usize)
+    }
+
+    /// Write as much of `src` as fits, returning the number of bytes written.
+    pub fn push(&mut self, src: &[u8]) -> usize {
+        let n = cmp::min(src.len(), self.free_space());
+        let header = self.0.header();
+        let write = header.write.load(Ordering::Relaxed);
+        self.0.copy_in(write, &src[..n]);
+        header
+            .write
+            .store(write.wrapping_add(n as u32), Ordering::Release);
+        n
+    }
+
+    /// Signal that no more data will be written.
+    pub fn set_drained(&mut self) {
+        self.0.header().drained.store(1, Ordering::Release);
+    }
+}
+
+/// Reading end of a ring buffer.
+pub struct Consumer(RingBuffer);
+
+impl Consumer {
+    pub unsafe fn attach(ptr: *mut u8, size: usize) -> Result<Consumer> {
+        RingBuffer::attach(ptr, size).map(Consumer)
+    }
+
+    /// Number of bytes that can currently be read.
+    pub fn available(&self) -> usize {
+        let header = self.0.header();
+        let read = header.read.load(Ordering::Relaxed);
+        let write = header.write.load(Ordering::Acquire);
+        self.0.fill(read, write).unwrap_or(0) as usize
+    }
+
+    /// Read up to `dst.len()` bytes, returning the number of bytes read.
+    pub fn pop(&mut self, dst: &mut [u8]) -> usize {
+        let n = cmp::min(dst.len(), self.available());
+        let header = self.0.header();
+        let read = header.read.load(Ordering::Relaxed);
+        self.0.copy_out(read, &mut dst[..n]);
+        header
+            .read
+            .store(read.wrapping_add(n as u32), Ordering::Release);
+        n
+    }
+
+    /// Discard everything written so far, e.g. once playback has stopped.
+    /// The producer must not be writing concurrently.
+    pub fn discard(&mut self) {
+        let header = self.0.header();
+        let write = header.write.load(Ordering::Acquire);
+        header.read.store(write, Ordering::Release);
+    }
+
+    /// True once the producer has signalled the end of the stream and all
+    /// written data has been read.
+    pub fn is_drained(&self) -> bool {
+        self.0.header().drained.load(Ordering::Acquire) != 0 && self.available() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn area(capacity: usize) -> Vec<u32> {
+        vec![0; required_size(capacity) / mem::size_of::<u32>()]
+    }
+
+    #[test]
+    fn push_pop_wraps() {
+        let mut buf = area(16);
+        let ptr = buf.as_mut_ptr() as *mut u8;
+        let size = buf.len() * mem::size_of::<u32>();
+        let (mut producer, mut consumer) = unsafe {
+            init(ptr, size, 16).unwrap();
+            (
+                Producer::attach(ptr, size).unwrap(),
+                Consumer::attach(ptr, size).unwrap(),
+            )
+        };
+
+        let mut out = [0u8; 16];
+        for i in 0..100u8 {
+            let data = [
+                i,
+                i.wrapping_add(1),
+                i.wrapping_add(2),
+                i.wrapping_add(3),
+                0,
+                0,
+            ];
+            assert_eq!(producer.push(&data), data.len());
+            assert_eq!(consumer.available(), data.len());
+            assert_eq!(consumer.pop(&mut out[..data.len()]), data.len());
+            assert_eq!(&out[..data.len()], &data[..]);
+        }
+        assert_eq!(consumer.available(), 0);
+        assert!(!consumer.is_drained());
+        producer.set_drained();
+        assert!(consumer.is_drained());
+    }
+
+    #[test]
+    fn push_stops_when_full() {
+        let mut buf = area(8);
+        let ptr = buf.as_mut_ptr() as *mut u8;
+        let size = buf.len() * mem::size_of::<u32>();
+        let (mut producer, mut consumer) = unsafe {
+            init(ptr, size, 8).unwrap();
+            (
+                Producer::attach(ptr, size).unwrap(),
+                Consumer::attach(ptr, size).unwrap(),
+            )
+        };
+
+        assert_eq!(producer.push(&[1; 12]), 8);
+        assert_eq!(producer.free_space(), 0);
+        let mut out = [0u8; 12];
+        assert_eq!(consumer.pop(&mut out), 8);
+        assert_eq!(producer.free_space(), 8);
+
+        producer.push(&[2; 6]);
+        consumer.discard();
+        assert_eq!(consumer.available(), 0);
+        assert_eq!(producer.free_space(), 8);
+    }
+
+    #[test]
+    fn rejects_corrupt_header() {
+        let mut buf = area(8);
+        let ptr = buf.as_mut_ptr() as *mut u8;
+        let size = buf.len() * mem::size_of::<u32>();
+        unsafe {
+            init(ptr, size, 8).unwrap();
+        }
+        // Capacity that isn't a power of two.
+        buf[0] = 7;
+        assert!(unsafe { Consumer::attach(ptr, size) }.is_err());
+        // Capacity larger than the area.
+        buf[0] = 64;
+        assert!(unsafe { Consumer::attach(ptr, size) }.is_err());
+
+        buf[0] = 8;
+        let consumer = unsafe { Consumer::attach(ptr, size).unwrap() };
+        // Write position too far ahead of the read position.
+        buf[2] = 100;
+        assert_eq!(consumer.available(), 0);
+    }
+}
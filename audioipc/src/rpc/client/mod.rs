@@ -39,31 +39,61 @@
 // IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 // DEALINGS IN THE SOFTWARE.
 
+use crate::codec::{self, LengthDelimitedCodec};
 use crate::rpc::driver::Driver;
-use crate::rpc::Handler;
+use crate::rpc::{Envelope, Handler, Tap};
 use futures::sync::oneshot;
 use futures::{Async, Future, Poll, Sink, Stream};
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use std::io;
+use std::sync::atomic::Ordering;
 use tokio::runtime::current_thread;
 
 mod proxy;
 
 pub use self::proxy::{ClientProxy, Response};
 
+// Identifiers of requests given up on, remembered so their late responses
+// can be told apart from responses to requests never sent.
+const MAX_ABANDONED: usize = 256;
+
 pub fn bind_client<C>(transport: C::Transport) -> proxy::ClientProxy<C::Request, C::Response>
 where
     C: Client,
 {
-    let (tx, rx) = proxy::channel();
+    bind::<C>(transport, None)
+}
+
+/// As `bind_client`, passing the messages exchanged through `tap`.
+pub fn bind_client_with_tap<C>(
+    transport: C::Transport,
+    tap: Box<dyn Tap<Envelope<C::Response>, Envelope<C::Request>>>,
+) -> proxy::ClientProxy<C::Request, C::Response>
+where
+    C: Client,
+{
+    bind::<C>(transport, Some(tap))
+}
+
+fn bind<C>(
+    transport: C::Transport,
+    tap: Option<Box<dyn Tap<Envelope<C::Response>, Envelope<C::Request>>>>,
+) -> proxy::ClientProxy<C::Request, C::Response>
+where
+    C: Client,
+{
+    let (tx, rx, closed) = proxy::channel();
 
     let fut = {
         let handler = ClientHandler::<C> {
             transport,
             requests: rx,
-            in_flight: VecDeque::with_capacity(32),
+            closed,
+            next_id: 0,
+            in_flight: HashMap::with_capacity(32),
+            abandoned: VecDeque::with_capacity(MAX_ABANDONED),
         };
-        Driver::new(handler)
+        Driver::new(handler, tap)
     };
 
     // Spawn the RPC driver into task
@@ -72,6 +102,15 @@ where
     tx
 }
 
+/// Codec for the transport of `C`, limiting incoming responses to
+/// `C::MAX_MESSAGE_LEN`.
+pub fn client_codec<C>() -> LengthDelimitedCodec<Envelope<C::Request>, Envelope<C::Response>>
+where
+    C: Client,
+{
+    LengthDelimitedCodec::new(C::MAX_MESSAGE_LEN)
+}
+
 pub trait Client: 'static {
     /// Request
     type Request: 'static;
@@ -81,8 +120,11 @@ pub trait Client: 'static {
 
     /// The message transport, which works with async I/O objects of type `A`
     type Transport: 'static
-        + Stream<Item = Self::Response, Error = io::Error>
-        + Sink<SinkItem = Self::Request, SinkError = io::Error>;
+        + Stream<Item = Envelope<Self::Response>, Error = io::Error>
+        + Sink<SinkItem = Envelope<Self::Request>, SinkError = io::Error>;
+
+    /// Largest response, in bytes, accepted from the server.
+    const MAX_MESSAGE_LEN: usize = codec::MAX_MESSAGE_LEN as usize;
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -93,15 +135,22 @@ where
 {
     transport: C::Transport,
     requests: proxy::Receiver<C::Request, C::Response>,
-    in_flight: VecDeque<oneshot::Sender<C::Response>>,
+    // Signals the proxies once the connection has gone away.
+    closed: proxy::Closed,
+    // Identifier to assign to the next request.
+    next_id: u32,
+    // Requests awaiting a response, keyed by identifier.
+    in_flight: HashMap<u32, oneshot::Sender<C::Response>>,
+    // The most recent requests whose caller stopped waiting, oldest first.
+    abandoned: VecDeque<u32>,
 }
 
 impl<C> Handler for ClientHandler<C>
 where
     C: Client,
 {
-    type In = C::Response;
-    type Out = C::Request;
+    type In = Envelope<C::Response>;
+    type Out = Envelope<C::Request>;
     type Transport = C::Transport;
 
     fn transport(&mut self) -> &mut Self::Transport {
@@ -110,11 +159,15 @@ where
 
     fn consume(&mut self, response: Self::In) -> io::Result<()> {
         trace!("ClientHandler::consume");
-        if let Some(complete) = self.in_flight.pop_front() {
-            drop(complete.send(response));
+        if let Some(complete) = self.in_flight.remove(&response.id) {
+            drop(complete.send(response.body));
+        } else if let Some(i) = self.abandoned.iter().position(|&id| id == response.id) {
+            // The caller gave up waiting and the request was forgotten.
+            debug!("Discarding late response to request {}", response.id);
+            self.abandoned.remove(i);
         } else {
             return Err(io::Error::new(
-                io::ErrorKind::Other,
+                io::ErrorKind::InvalidData,
                 "request / response mismatch",
             ));
         }
@@ -126,15 +179,34 @@ where
     fn produce(&mut self) -> Poll<Option<Self::Out>, io::Error> {
         trace!("ClientHandler::produce");
 
+        // Forget requests whose caller has stopped waiting for a response.
+        let abandoned = &mut self.abandoned;
+        self.in_flight.retain(|&id, complete| {
+            let waiting = complete.poll_cancel() == Ok(Async::NotReady);
+            if !waiting {
+                if abandoned.len() == MAX_ABANDONED {
+                    abandoned.pop_front();
+                }
+                abandoned.push_back(id);
+            }
+            waiting
+        });
+
         // Try to get a new request
         match self.requests.poll() {
             Ok(Async::Ready(Some((request, complete)))) => {
                 trace!("  --> received request");
 
-                // Track complete handle
-                self.in_flight.push_back(complete);
+                // Track complete handle, skipping identifiers that may
+                // still be answered once `next_id` has wrapped.
+                let mut id = self.next_id;
+                while self.in_flight.contains_key(&id) || self.abandoned.contains(&id) {
+                    id = id.wrapping_add(1);
+                }
+                self.next_id = id.wrapping_add(1);
+                self.in_flight.insert(id, complete);
 
-                Ok(Some(request).into())
+                Ok(Some(Envelope { id, body: request }).into())
             }
             Ok(Async::Ready(None)) => {
                 trace!("  --> client dropped");
@@ -157,6 +229,161 @@ where
 impl<C: Client> Drop for ClientHandler<C> {
     fn drop(&mut self) {
         let _ = self.transport.close();
+        self.closed.store(true, Ordering::SeqCst);
         self.in_flight.clear();
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rpc::null_transport::NullTransport;
+    use futures::future;
+    use std::time::Duration;
+
+    struct TestClient;
+
+    impl Client for TestClient {
+        type Request = u32;
+        type Response = u32;
+        type Transport = NullTransport;
+    }
+
+    fn handler() -> (ClientProxy<u32, u32>, ClientHandler<TestClient>) {
+        let (proxy, requests, closed) = proxy::channel();
+        let handler = ClientHandler {
+            transport: NullTransport,
+            requests,
+            closed,
+            next_id: 0,
+            in_flight: HashMap::new(),
+            abandoned: VecDeque::new(),
+        };
+        (proxy, handler)
+    }
+
+    // Let the handler take the next request from its proxies, returning the
+    // identifier the request is sent with.
+    fn sent_id(handler: &mut ClientHandler<TestClient>) -> Option<u32> {
+        future::lazy(|| match handler.produce() {
+            Ok(Async::Ready(Some(envelope))) => Ok::<_, ()>(Some(envelope.id)),
+            Ok(Async::NotReady) => Ok(None),
+            _ => panic!("Handler failed"),
+        })
+        .wait()
+        .unwrap()
+    }
+
+    fn send(
+        proxy: &ClientProxy<u32, u32>,
+        handler: &mut ClientHandler<TestClient>,
+        request: u32,
+    ) -> (u32, Response<u32>) {
+        let response = proxy.call(request);
+        (sent_id(handler).unwrap(), response)
+    }
+
+    #[test]
+    fn responses_complete_requests_out_of_order() {
+        let (proxy, mut handler) = handler();
+        let (first, first_response) = send(&proxy, &mut handler, 1);
+        let (second, second_response) = send(&proxy, &mut handler, 2);
+        assert_ne!(first, second);
+
+        handler
+            .consume(Envelope {
+                id: second,
+                body: 20,
+            })
+            .unwrap();
+        assert_eq!(second_response.wait().unwrap(), 20);
+        assert!(handler.has_in_flight());
+
+        handler
+            .consume(Envelope {
+                id: first,
+                body: 10,
+            })
+            .unwrap();
+        assert_eq!(first_response.wait().unwrap(), 10);
+        assert!(!handler.has_in_flight());
+    }
+
+    #[test]
+    fn unknown_and_duplicate_ids_are_rejected() {
+        let (proxy, mut handler) = handler();
+        let (id, response) = send(&proxy, &mut handler, 1);
+
+        assert!(handler
+            .consume(Envelope {
+                id: id.wrapping_add(1),
+                body: 0,
+            })
+            .is_err());
+        assert!(handler.has_in_flight());
+
+        handler.consume(Envelope { id, body: 10 }).unwrap();
+        assert!(handler.consume(Envelope { id, body: 11 }).is_err());
+        assert_eq!(response.wait().unwrap(), 10);
+        assert!(!handler.has_in_flight());
+    }
+
+    #[test]
+    fn timed_out_requests_are_forgotten() {
+        let (proxy, mut handler) = handler();
+        let response = proxy.call_with_timeout(1, Some(Duration::from_millis(10)));
+        let id = sent_id(&mut handler).unwrap();
+        assert_eq!(response.wait().unwrap_err().kind(), io::ErrorKind::TimedOut);
+
+        assert_eq!(sent_id(&mut handler), None);
+        assert!(!handler.has_in_flight());
+        // The late response is discarded, once.
+        handler.consume(Envelope { id, body: 10 }).unwrap();
+        assert!(handler.consume(Envelope { id, body: 10 }).is_err());
+    }
+
+    #[test]
+    fn cancelled_requests_are_forgotten() {
+        let (proxy, mut handler) = handler();
+        let (id, response) = send(&proxy, &mut handler, 1);
+        drop(response);
+
+        assert_eq!(sent_id(&mut handler), None);
+        assert!(!handler.has_in_flight());
+        handler.consume(Envelope { id, body: 10 }).unwrap();
+    }
+
+    #[test]
+    fn polled_responses_time_out() {
+        let (proxy, mut handler) = handler();
+        let response = proxy.call_with_timeout(1, Some(Duration::from_millis(10)));
+        sent_id(&mut handler).unwrap();
+
+        // Driven by the runtime rather than `wait`, so only the timer wakes
+        // the response at its deadline.
+        let mut rt = current_thread::Runtime::new().unwrap();
+        let result = rt.block_on(response.then(Ok::<_, ()>)).unwrap();
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn wrapped_ids_skip_requests_in_flight() {
+        let (proxy, mut handler) = handler();
+        handler.next_id = u32::max_value();
+        let (last, _last_response) = send(&proxy, &mut handler, 1);
+        let (first, _first_response) = send(&proxy, &mut handler, 2);
+        assert_eq!((last, first), (u32::max_value(), 0));
+
+        handler.next_id = u32::max_value();
+        let (id, _response) = send(&proxy, &mut handler, 3);
+        assert_eq!(id, 1);
+
+        // Nor are identifiers of abandoned requests reused.
+        let (id, response) = send(&proxy, &mut handler, 4);
+        assert_eq!(id, 2);
+        drop(response);
+        handler.next_id = 2;
+        let (id, _response) = send(&proxy, &mut handler, 5);
+        assert_eq!(id, 3);
+    }
+}
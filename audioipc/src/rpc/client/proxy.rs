@@ -14,6 +14,9 @@
 // * Remove the `Envelope` type.
 // * Renamed `pair` to `channel` to represent that an `rpc::channel`
 //   is being created.
+// * Add per-call timeouts, enforced by `Response::wait` and by a timer
+//   when polled on a thread with one.
+// * Track whether the connection behind the proxy has closed.
 //
 // Original License:
 //
@@ -43,10 +46,16 @@
 // IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 // DEALINGS IN THE SOFTWARE.
 
+use futures::executor::{self, Notify};
 use futures::sync::{mpsc, oneshot};
 use futures::{Async, Future, Poll};
 use std::fmt;
 use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
 
 /// Message used to dispatch requests to the task managing the
 /// client connection.
@@ -58,33 +67,72 @@ pub type Receiver<R, Q> = mpsc::UnboundedReceiver<Request<R, Q>>;
 /// Response future returned from a client
 pub struct Response<Q> {
     inner: oneshot::Receiver<Q>,
+    deadline: Option<Instant>,
+    // Wakes the task polling the response at the deadline, created on the
+    // first poll.  Fails without a tokio timer on the polling thread.
+    timer: Option<Delay>,
 }
 
 pub struct ClientProxy<R, Q> {
     tx: mpsc::UnboundedSender<Request<R, Q>>,
+    timeout: Option<Duration>,
+    closed: Arc<AtomicBool>,
 }
 
 impl<R, Q> Clone for ClientProxy<R, Q> {
     fn clone(&self) -> Self {
         ClientProxy {
             tx: self.tx.clone(),
+            timeout: self.timeout,
+            closed: self.closed.clone(),
         }
     }
 }
 
-pub fn channel<R, Q>() -> (ClientProxy<R, Q>, Receiver<R, Q>) {
+/// Set by the task managing the client connection once the connection
+/// has closed.
+pub type Closed = Arc<AtomicBool>;
+
+pub fn channel<R, Q>() -> (ClientProxy<R, Q>, Receiver<R, Q>, Closed) {
     // Create a channel to send the requests to client-side of rpc.
     let (tx, rx) = mpsc::unbounded();
+    let closed = Arc::new(AtomicBool::new(false));
 
     // Wrap the `tx` part in ClientProxy so the rpc call interface
     // can be implemented.
-    let client = ClientProxy { tx };
+    let client = ClientProxy {
+        tx,
+        timeout: None,
+        closed: closed.clone(),
+    };
 
-    (client, rx)
+    (client, rx, closed)
 }
 
 impl<R, Q> ClientProxy<R, Q> {
+    /// Set the timeout applied by `call`.  `None` waits forever.  Clones
+    /// made afterwards inherit the timeout.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// True once the connection has closed.  All further calls fail.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
     pub fn call(&self, request: R) -> Response<Q> {
+        self.call_with_timeout(request, self.timeout)
+    }
+
+    /// Like `call`, but the response fails with `io::ErrorKind::TimedOut`
+    /// if it hasn't arrived within `timeout`.  The request is then
+    /// forgotten and a late response is discarded.
+    pub fn call_with_timeout(&self, request: R, timeout: Option<Duration>) -> Response<Q> {
         // The response to returned from the rpc client task over a
         // oneshot channel.
         let (tx, rx) = oneshot::channel();
@@ -95,7 +143,11 @@ impl<R, Q> ClientProxy<R, Q> {
         // into a BrokenPipe, which conveys the proper error.
         let _ = self.tx.unbounded_send((request, tx));
 
-        Response { inner: rx }
+        Response {
+            inner: rx,
+            deadline: timeout.map(|t| Instant::now() + t),
+            timer: None,
+        }
     }
 }
 
@@ -113,19 +165,95 @@ impl<Q> Future for Response<Q> {
     type Item = Q;
     type Error = io::Error;
 
+    // The deadline is checked whenever the response is polled.  The task is
+    // woken when it passes by a tokio timer, if the polling thread has one,
+    // or by `wait`.
     fn poll(&mut self) -> Poll<Q, io::Error> {
         match self.inner.poll() {
             Ok(Async::Ready(res)) => Ok(Async::Ready(res)),
-            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::NotReady) => match self.deadline {
+                Some(deadline) if Instant::now() >= deadline => Err(timed_out()),
+                Some(deadline) => {
+                    let timer = self.timer.get_or_insert_with(|| Delay::new(deadline));
+                    match timer.poll() {
+                        Ok(Async::Ready(())) => Err(timed_out()),
+                        _ => Ok(Async::NotReady),
+                    }
+                }
+                None => Ok(Async::NotReady),
+            },
             // Convert oneshot::Canceled into io::Error
-            Err(_) => {
-                let e = io::Error::new(io::ErrorKind::BrokenPipe, "broken pipe");
-                Err(e)
+            Err(_) => Err(broken_pipe()),
+        }
+    }
+
+    fn wait(self) -> Result<Q, io::Error> {
+        let deadline = self.deadline;
+        let notify = Arc::new(ThreadNotify {
+            thread: thread::current(),
+        });
+        let mut task = executor::spawn(self);
+        loop {
+            if let Async::Ready(res) = task.poll_future_notify(&notify, 0)? {
+                return Ok(res);
+            }
+            match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(timed_out());
+                    }
+                    thread::park_timeout(deadline - now);
+                }
+                None => thread::park(),
             }
         }
     }
 }
 
+impl<Q> Response<Q> {
+    /// Block until the response arrives or `deadline` passes, returning
+    /// `None` in the latter case.  Unlike a timeout, the response can then
+    /// be waited for again.
+    pub fn wait_until(&mut self, deadline: Instant) -> Option<Result<Q, io::Error>> {
+        let notify = Arc::new(ThreadNotify {
+            thread: thread::current(),
+        });
+        let mut task = executor::spawn(&mut self.inner);
+        loop {
+            match task.poll_future_notify(&notify, 0) {
+                Ok(Async::Ready(res)) => return Some(Ok(res)),
+                Ok(Async::NotReady) => {}
+                Err(_) => return Some(Err(broken_pipe())),
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            thread::park_timeout(deadline - now);
+        }
+    }
+}
+
+fn broken_pipe() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "broken pipe")
+}
+
+fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "rpc timed out")
+}
+
+// Wakes the thread blocked in `Response::wait`.
+struct ThreadNotify {
+    thread: thread::Thread,
+}
+
+impl Notify for ThreadNotify {
+    fn notify(&self, _id: usize) {
+        self.thread.unpark();
+    }
+}
+
 impl<Q> fmt::Debug for Response<Q>
 where
     Q: fmt::Debug,
@@ -3,7 +3,7 @@
 // This program is made available under an ISC-style license.  See the
 // accompanying file LICENSE for details
 
-use crate::rpc::Handler;
+use crate::rpc::{Handler, Tap};
 use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
 use std::fmt;
 use std::io;
@@ -15,6 +15,9 @@ where
     // Glue
     handler: T,
 
+    // Observer of the messages passing through the connection, if any.
+    tap: Option<Box<dyn Tap<T::In, T::Out>>>,
+
     // True as long as the connection has more request frames to read.
     run: bool,
 
@@ -27,9 +30,10 @@ where
     T: Handler,
 {
     /// Create a new rpc driver with the given service and transport.
-    pub fn new(handler: T) -> Driver<T> {
+    pub fn new(handler: T, tap: Option<Box<dyn Tap<T::In, T::Out>>>) -> Driver<T> {
         Driver {
             handler,
+            tap,
             run: true,
             is_flushed: true,
         }
@@ -61,6 +65,10 @@ where
             Some(message) => {
                 trace!("received message");
 
+                if let Some(tap) = &mut self.tap {
+                    tap.incoming(&message);
+                }
+
                 if let Err(e) = self.handler.consume(message) {
                     // TODO: Should handler be infalliable?
                     panic!("unimplemented error handling: {:?}", e);
@@ -103,6 +111,9 @@ where
 
     fn process_outgoing(&mut self, message: T::Out) -> io::Result<()> {
         trace!("process_outgoing");
+        if let Some(tap) = &mut self.tap {
+            tap.outgoing(&message);
+        }
         assert_send(&mut self.handler.transport(), message)?;
 
         Ok(())
@@ -167,6 +178,7 @@ where
         f.debug_struct("rpc::Handler")
             .field("handler", &self.handler)
             .field("run", &self.run)
+            .field("tapped", &self.tap.is_some())
             .field("is_flushed", &self.is_flushed)
             .finish()
     }
@@ -8,10 +8,23 @@ use std::io;
 
 mod client;
 mod driver;
+#[cfg(test)]
+mod null_transport;
 mod server;
 
-pub use self::client::{bind_client, Client, ClientProxy, Response};
-pub use self::server::{bind_server, Server};
+pub use self::client::{
+    bind_client, bind_client_with_tap, client_codec, Client, ClientProxy, Response,
+};
+pub use self::server::{bind_server, bind_server_with_tap, server_codec, Server};
+
+/// Wire format of rpc messages.  Each request carries an identifier which is
+/// echoed in its response, so responses can be sent in the order requests
+/// complete rather than the order they were received.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Envelope<T> {
+    pub id: u32,
+    pub body: T,
+}
 
 pub trait Handler {
     /// Message type read from transport
@@ -34,3 +47,13 @@ pub trait Handler {
     /// RPC currently in flight
     fn has_in_flight(&self) -> bool;
 }
+
+/// Observes the messages passing through an rpc connection, e.g. to record
+/// them.  Called on the thread driving the connection.
+pub trait Tap<In, Out> {
+    /// A message decoded from the transport.
+    fn incoming(&mut self, message: &In);
+
+    /// A message about to be written to the transport.
+    fn outgoing(&mut self, message: &Out);
+}
@@ -0,0 +1,35 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details
+
+// A transport for tests driving rpc handlers directly, so nothing is read
+// from or written to the transport.
+
+use super::Envelope;
+use futures::{Async, AsyncSink, Poll, Sink, StartSend, Stream};
+use std::io;
+
+pub struct NullTransport;
+
+impl Stream for NullTransport {
+    type Item = Envelope<u32>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        Ok(Async::NotReady)
+    }
+}
+
+impl Sink for NullTransport {
+    type SinkItem = Envelope<u32>;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, _: Self::SinkItem) -> StartSend<Self::SinkItem, io::Error> {
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
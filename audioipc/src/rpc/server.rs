@@ -39,8 +39,9 @@
 // IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 // DEALINGS IN THE SOFTWARE.
 
+use crate::codec::{self, LengthDelimitedCodec};
 use crate::rpc::driver::Driver;
-use crate::rpc::Handler;
+use crate::rpc::{Envelope, Handler, Tap};
 use futures::{Async, Future, Poll, Sink, Stream};
 use std::collections::VecDeque;
 use std::io;
@@ -50,6 +51,27 @@ use tokio::runtime::current_thread;
 pub fn bind_server<S>(transport: S::Transport, server: S)
 where
     S: Server,
+{
+    bind(transport, server, None)
+}
+
+/// As `bind_server`, passing the messages exchanged through `tap`.
+pub fn bind_server_with_tap<S>(
+    transport: S::Transport,
+    server: S,
+    tap: Box<dyn Tap<Envelope<S::Request>, Envelope<S::Response>>>,
+) where
+    S: Server,
+{
+    bind(transport, server, Some(tap))
+}
+
+fn bind<S>(
+    transport: S::Transport,
+    server: S,
+    tap: Option<Box<dyn Tap<Envelope<S::Request>, Envelope<S::Response>>>>,
+) where
+    S: Server,
 {
     let fut = {
         let handler = ServerHandler {
@@ -57,13 +79,22 @@ where
             transport,
             in_flight: VecDeque::with_capacity(32),
         };
-        Driver::new(handler)
+        Driver::new(handler, tap)
     };
 
     // Spawn the RPC driver into task
     current_thread::spawn(fut.map_err(|_| ()))
 }
 
+/// Codec for the transport of `S`, limiting incoming requests to
+/// `S::MAX_MESSAGE_LEN`.
+pub fn server_codec<S>() -> LengthDelimitedCodec<Envelope<S::Response>, Envelope<S::Request>>
+where
+    S: Server,
+{
+    LengthDelimitedCodec::new(S::MAX_MESSAGE_LEN)
+}
+
 pub trait Server: 'static {
     /// Request
     type Request: 'static;
@@ -77,8 +108,11 @@ pub trait Server: 'static {
     /// The message transport, which works with async I/O objects of
     /// type `A`.
     type Transport: 'static
-        + Stream<Item = Self::Request, Error = io::Error>
-        + Sink<SinkItem = Self::Response, SinkError = io::Error>;
+        + Stream<Item = Envelope<Self::Request>, Error = io::Error>
+        + Sink<SinkItem = Envelope<Self::Response>, SinkError = io::Error>;
+
+    /// Largest request, in bytes, accepted from the client.
+    const MAX_MESSAGE_LEN: usize = codec::MAX_MESSAGE_LEN as usize;
 
     /// Process the request and return the response asynchronously.
     fn process(&mut self, req: Self::Request) -> Self::Future;
@@ -94,7 +128,7 @@ where
     server: S,
     // The transport responsible for sending/receving messages over the wire
     transport: S::Transport,
-    // FIFO of "in flight" responses to requests.
+    // "In flight" responses to requests, sent as they complete.
     in_flight: VecDeque<InFlight<S::Future>>,
 }
 
@@ -102,8 +136,8 @@ impl<S> Handler for ServerHandler<S>
 where
     S: Server,
 {
-    type In = S::Request;
-    type Out = S::Response;
+    type In = Envelope<S::Request>;
+    type Out = Envelope<S::Response>;
     type Transport = S::Transport;
 
     /// Mutable reference to the transport
@@ -114,8 +148,9 @@ where
     /// Consume a message
     fn consume(&mut self, request: Self::In) -> io::Result<()> {
         trace!("ServerHandler::consume");
-        let response = self.server.process(request);
-        self.in_flight.push_back(InFlight::Active(response));
+        let response = self.server.process(request.body);
+        self.in_flight
+            .push_back(InFlight::Active(request.id, response));
 
         // TODO: Should the error be handled differently?
         Ok(())
@@ -130,20 +165,20 @@ where
             pending.poll();
         }
 
-        // Is the head of the queue ready?
-        match self.in_flight.front() {
-            Some(&InFlight::Done(_)) => {}
-            _ => {
+        // Is any response ready?
+        let ready = match self.in_flight.iter().position(InFlight::is_done) {
+            Some(ready) => ready,
+            None => {
                 trace!("  --> not ready");
                 return Ok(Async::NotReady);
             }
-        }
+        };
 
         // Return the ready response
-        match self.in_flight.pop_front() {
-            Some(InFlight::Done(res)) => {
+        match self.in_flight.remove(ready) {
+            Some(InFlight::Done(id, body)) => {
                 trace!("  --> received response");
-                Ok(Async::Ready(Some(res)))
+                Ok(Async::Ready(Some(Envelope { id, body })))
             }
             _ => panic!(),
         }
@@ -165,20 +200,119 @@ impl<S: Server> Drop for ServerHandler<S> {
 ////////////////////////////////////////////////////////////////////////////////
 
 enum InFlight<F: Future<Error = ()>> {
-    Active(F),
-    Done(F::Item),
+    Active(u32, F),
+    Done(u32, F::Item),
 }
 
 impl<F: Future<Error = ()>> InFlight<F> {
     fn poll(&mut self) {
-        let res = match *self {
-            InFlight::Active(ref mut f) => match f.poll() {
-                Ok(Async::Ready(e)) => e,
+        let (id, res) = match *self {
+            InFlight::Active(id, ref mut f) => match f.poll() {
+                Ok(Async::Ready(e)) => (id, e),
                 Err(_) => unreachable!(),
                 Ok(Async::NotReady) => return,
             },
             _ => return,
         };
-        *self = InFlight::Done(res);
+        *self = InFlight::Done(id, res);
+    }
+
+    fn is_done(&self) -> bool {
+        match *self {
+            InFlight::Done(..) => true,
+            InFlight::Active(..) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rpc::null_transport::NullTransport;
+    use futures::future;
+    use futures::sync::oneshot;
+
+    // Answers each request with its value once `complete` is called for it.
+    #[derive(Default)]
+    struct TestServer {
+        pending: Vec<(u32, oneshot::Sender<u32>)>,
+    }
+
+    impl TestServer {
+        fn complete(&mut self, request: u32) {
+            let i = self
+                .pending
+                .iter()
+                .position(|&(r, _)| r == request)
+                .unwrap();
+            drop(self.pending.remove(i).1.send(request));
+        }
+    }
+
+    impl Server for TestServer {
+        type Request = u32;
+        type Response = u32;
+        type Future = Box<dyn Future<Item = u32, Error = ()>>;
+        type Transport = NullTransport;
+
+        fn process(&mut self, req: u32) -> Self::Future {
+            let (tx, rx) = oneshot::channel();
+            self.pending.push((req, tx));
+            Box::new(rx.map_err(|_| ()))
+        }
+    }
+
+    fn handler() -> ServerHandler<TestServer> {
+        ServerHandler {
+            server: TestServer::default(),
+            transport: NullTransport,
+            in_flight: VecDeque::new(),
+        }
+    }
+
+    // The responses the handler has ready to send.
+    fn responses(handler: &mut ServerHandler<TestServer>) -> Vec<(u32, u32)> {
+        future::lazy(|| {
+            let mut responses = Vec::new();
+            while let Async::Ready(Some(envelope)) = handler.produce().unwrap() {
+                responses.push((envelope.id, envelope.body));
+            }
+            Ok::<_, ()>(responses)
+        })
+        .wait()
+        .unwrap()
+    }
+
+    #[test]
+    fn responses_are_sent_as_requests_complete() {
+        let mut handler = handler();
+        for (id, request) in [(7, 70), (8, 80), (9, 90)].iter() {
+            handler
+                .consume(Envelope {
+                    id: *id,
+                    body: *request,
+                })
+                .unwrap();
+        }
+        assert!(responses(&mut handler).is_empty());
+
+        handler.server.complete(90);
+        assert_eq!(responses(&mut handler), vec![(9, 90)]);
+        handler.server.complete(70);
+        handler.server.complete(80);
+        assert_eq!(responses(&mut handler), vec![(7, 70), (8, 80)]);
+        assert!(!handler.has_in_flight());
+    }
+
+    #[test]
+    fn duplicate_ids_are_answered_separately() {
+        let mut handler = handler();
+        handler.consume(Envelope { id: 1, body: 10 }).unwrap();
+        handler.consume(Envelope { id: 1, body: 11 }).unwrap();
+
+        handler.server.complete(11);
+        assert_eq!(responses(&mut handler), vec![(1, 11)]);
+        handler.server.complete(10);
+        assert_eq!(responses(&mut handler), vec![(1, 10)]);
     }
 }
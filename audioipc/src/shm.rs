@@ -12,10 +12,22 @@ pub use unix::SharedMem;
 #[cfg(windows)]
 pub use windows::SharedMem;
 
+/// How a process maps a shared memory area.  Each side maps the areas it
+/// only reads `ReadOnly`, so a stray write can't corrupt them for the other
+/// side.  This guards against bugs, not a hostile peer: both sides hold a
+/// writable handle to the area and can change the protection of their own
+/// mappings, so neither may rely on the other leaving an area untouched.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    ReadOnly,
+    ReadWrite,
+}
+
 #[derive(Copy, Clone)]
 pub struct SharedMemView {
     ptr: *mut c_void,
     size: usize,
+    access: Access,
 }
 
 unsafe impl Send for SharedMemView {}
@@ -31,6 +43,9 @@ impl SharedMemView {
     }
 
     pub unsafe fn get_mut_slice(&mut self, size: usize) -> Result<&mut [u8]> {
+        if self.access == Access::ReadOnly {
+            bail!("mmap is read-only");
+        }
         let map = slice::from_raw_parts_mut(self.ptr as _, self.size);
         if size <= self.size {
             Ok(&mut map[..size])
@@ -38,53 +53,104 @@ impl SharedMemView {
             bail!("mmap size")
         }
     }
+
+    // The `size` bytes at `offset`, or `None` if they're out of bounds.
+    // Can't grant write access to a read-only view.
+    pub(crate) fn section(
+        &self,
+        offset: usize,
+        size: usize,
+        access: Access,
+    ) -> Option<SharedMemView> {
+        match offset.checked_add(size) {
+            Some(end) if end <= self.size => Some(SharedMemView {
+                ptr: unsafe { (self.ptr as *mut u8).add(offset) as *mut c_void },
+                size,
+                access: if self.access == Access::ReadOnly {
+                    Access::ReadOnly
+                } else {
+                    access
+                },
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Granularity of the protection set by `SharedMem::protect`.
+#[cfg(unix)]
+pub fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+#[cfg(windows)]
+pub fn page_size() -> usize {
+    unsafe {
+        let mut info = std::mem::zeroed::<winapi::um::sysinfoapi::SYSTEM_INFO>();
+        winapi::um::sysinfoapi::GetSystemInfo(&mut info);
+        info.dwPageSize as usize
+    }
+}
+
+// Check the `size` bytes at `offset` lie within `view`, starting on a page
+// boundary.
+fn check_protect(view: &SharedMemView, offset: usize, size: usize) -> Result<()> {
+    if view.access == Access::ReadOnly {
+        bail!("mmap is read-only");
+    }
+    if offset % page_size() != 0 || view.section(offset, size, Access::ReadOnly).is_none() {
+        bail!("invalid protection range");
+    }
+    Ok(())
 }
 
 #[cfg(unix)]
 mod unix {
     use super::*;
-    use memmap::{MmapMut, MmapOptions};
-    use std::env::temp_dir;
-    use std::fs::{remove_file, File, OpenOptions};
-    use std::os::unix::io::FromRawFd;
+    use memmap::{Mmap, MmapMut, MmapOptions};
+    use std::fs::File;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    // Seals applied to memfds before they're shared, so neither side can
+    // resize an area under the other's mapping.  Areas are always memfds
+    // on Linux, so a peer can't hand over a file it could resize.
+    #[cfg(target_os = "linux")]
+    const SEALS: libc::c_int = libc::F_SEAL_SHRINK | libc::F_SEAL_GROW;
 
     fn open_shm_file(id: &str) -> Result<File> {
         #[cfg(target_os = "linux")]
         {
             let id_cstring = std::ffi::CString::new(id).unwrap();
             unsafe {
-                let r = libc::syscall(libc::SYS_memfd_create, id_cstring.as_ptr(), 0);
-                if r >= 0 {
-                    use std::os::unix::io::FromRawFd as _;
-                    return Ok(File::from_raw_fd(r.try_into().unwrap()));
+                let r = libc::syscall(
+                    libc::SYS_memfd_create,
+                    id_cstring.as_ptr(),
+                    libc::MFD_ALLOW_SEALING,
+                );
+                if r < 0 {
+                    return Err(std::io::Error::last_os_error().into());
                 }
+                Ok(File::from_raw_fd(r.try_into().unwrap()))
             }
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            use std::env::temp_dir;
+            use std::fs::{remove_file, OpenOptions};
 
-            let mut path = std::path::PathBuf::from("/dev/shm");
+            let mut path = temp_dir();
             path.push(id);
 
-            if let Ok(file) = OpenOptions::new()
+            let file = OpenOptions::new()
                 .read(true)
                 .write(true)
                 .create_new(true)
-                .open(&path)
-            {
-                let _ = remove_file(&path);
-                return Ok(file);
-            }
-        }
+                .open(&path)?;
 
-        let mut path = temp_dir();
-        path.push(id);
-
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create_new(true)
-            .open(&path)?;
-
-        let _ = remove_file(&path);
-        Ok(file)
+            let _ = remove_file(&path);
+            Ok(file)
+        }
     }
 
     fn handle_enospc(s: &str) -> Result<()> {
@@ -99,8 +165,6 @@ mod unix {
     }
 
     fn allocate_file(file: &File, size: usize) -> Result<()> {
-        use std::os::unix::io::AsRawFd;
-
         // First, set the file size.  This may create a sparse file on
         // many systems, which can fail with SIGBUS when accessed via a
         // mapping and the lazy backing allocation fails due to low disk
@@ -149,36 +213,131 @@ mod unix {
         Ok(())
     }
 
+    #[cfg(target_os = "linux")]
+    fn seal_file(file: &File) -> Result<()> {
+        if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_ADD_SEALS, SEALS) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn seal_file(_file: &File) -> Result<()> {
+        Ok(())
+    }
+
+    // Check a received file was sealed by `seal_file`.  Fails with
+    // `EINVAL` for files that can't be sealed, which aren't memfds.
+    #[cfg(target_os = "linux")]
+    fn verify_seals(file: &File) -> Result<()> {
+        let seals = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GET_SEALS) };
+        if seals < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        if seals & SEALS != SEALS {
+            bail!("shared memory file is not sealed");
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn verify_seals(_file: &File) -> Result<()> {
+        Ok(())
+    }
+
+    enum Mapping {
+        ReadOnly(Mmap),
+        ReadWrite(MmapMut),
+    }
+
     pub struct SharedMem {
-        _mmap: MmapMut,
+        _mapping: Mapping,
         view: SharedMemView,
     }
 
     impl SharedMem {
-        pub fn new(id: &str, size: usize) -> Result<(SharedMem, PlatformHandle)> {
+        /// Create a shared memory area of `size` bytes, returning this
+        /// process's mapping and a handle for mapping it in the peer.
+        pub fn new(id: &str, size: usize, access: Access) -> Result<(SharedMem, PlatformHandle)> {
             let file = open_shm_file(id)?;
             allocate_file(&file, size)?;
-            let mut mmap = unsafe { MmapOptions::new().map_mut(&file)? };
-            assert_eq!(mmap.len(), size);
-            let view = SharedMemView {
-                ptr: mmap.as_mut_ptr() as _,
-                size,
+            seal_file(&file)?;
+            let shm = SharedMem::map(&file, size, access)?;
+            Ok((shm, PlatformHandle::from(file)))
+        }
+
+        /// Map an area of `size` bytes created by the peer with `new`,
+        /// taking ownership of `handle`.
+        pub unsafe fn from(
+            handle: &PlatformHandle,
+            size: usize,
+            access: Access,
+        ) -> Result<SharedMem> {
+            let file = File::from_raw_fd(handle.into_raw());
+            verify_seals(&file)?;
+            if file.metadata()?.len() != size as u64 {
+                bail!("shared memory file size mismatch");
+            }
+            SharedMem::map(&file, size, access)
+        }
+
+        fn map(file: &File, size: usize, access: Access) -> Result<SharedMem> {
+            let mut options = MmapOptions::new();
+            options.len(size);
+            let (mapping, ptr) = match access {
+                Access::ReadOnly => {
+                    let mmap = unsafe { options.map(file)? };
+                    let ptr = mmap.as_ptr() as *mut c_void;
+                    (Mapping::ReadOnly(mmap), ptr)
+                }
+                Access::ReadWrite => {
+                    let mut mmap = unsafe { options.map_mut(file)? };
+                    let ptr = mmap.as_mut_ptr() as *mut c_void;
+                    (Mapping::ReadWrite(mmap), ptr)
+                }
             };
-            let handle = PlatformHandle::from(file);
-            Ok((SharedMem { _mmap: mmap, view }, handle))
+            Ok(SharedMem {
+                _mapping: mapping,
+                view: SharedMemView { ptr, size, access },
+            })
         }
 
-        pub unsafe fn from(handle: &PlatformHandle, size: usize) -> Result<SharedMem> {
-            let mut mmap = {
-                let file = File::from_raw_fd(handle.into_raw());
-                MmapOptions::new().map_mut(&file)?
+        /// Zero the area, even if this process maps it read-only.  Undoes
+        /// any `protect` of a read-write mapping.
+        pub fn zero(&mut self) -> Result<()> {
+            let view = self.view;
+            let protect = |prot| {
+                if unsafe { libc::mprotect(view.ptr, view.size, prot) } != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
             };
-            assert_eq!(mmap.len(), size);
-            let view = SharedMemView {
-                ptr: mmap.as_mut_ptr() as _,
-                size,
+            protect(libc::PROT_READ | libc::PROT_WRITE)?;
+            unsafe { std::ptr::write_bytes(view.ptr as *mut u8, 0, view.size) };
+            if view.access == Access::ReadOnly {
+                protect(libc::PROT_READ)?;
+            }
+            Ok(())
+        }
+
+        /// Change the protection of the `size` bytes at `offset` of a
+        /// read-write mapping, which must start on a page boundary.
+        /// Protection applies to whole pages.
+        pub fn protect(&mut self, offset: usize, size: usize, access: Access) -> Result<()> {
+            check_protect(&self.view, offset, size)?;
+            let prot = match access {
+                Access::ReadOnly => libc::PROT_READ,
+                Access::ReadWrite => libc::PROT_READ | libc::PROT_WRITE,
             };
-            Ok(SharedMem { _mmap: mmap, view })
+            let ptr = unsafe { (self.view.ptr as *mut u8).add(offset) as *mut c_void };
+            if unsafe { libc::mprotect(ptr, size, prot) } != 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            Ok(())
+        }
+
+        pub fn size(&self) -> usize {
+            self.view.size
         }
 
         pub unsafe fn unsafe_view(&self) -> SharedMemView {
@@ -203,14 +362,24 @@ mod windows {
         shared::{minwindef::DWORD, ntdef::HANDLE},
         um::{
             handleapi::CloseHandle,
-            memoryapi::{MapViewOfFile, UnmapViewOfFile, FILE_MAP_ALL_ACCESS},
+            memoryapi::{
+                MapViewOfFile, UnmapViewOfFile, VirtualProtect, FILE_MAP_ALL_ACCESS, FILE_MAP_READ,
+                FILE_MAP_WRITE,
+            },
             winbase::CreateFileMappingA,
-            winnt::PAGE_READWRITE,
+            winnt::{PAGE_READONLY, PAGE_READWRITE},
         },
     };
 
     use crate::INVALID_HANDLE_VALUE;
 
+    fn map_access(access: Access) -> DWORD {
+        match access {
+            Access::ReadOnly => FILE_MAP_READ,
+            Access::ReadWrite => FILE_MAP_ALL_ACCESS,
+        }
+    }
+
     pub struct SharedMem {
         handle: HANDLE,
         view: SharedMemView,
@@ -232,7 +401,7 @@ mod windows {
     }
 
     impl SharedMem {
-        pub fn new(_id: &str, size: usize) -> Result<(SharedMem, PlatformHandle)> {
+        pub fn new(_id: &str, size: usize, access: Access) -> Result<(SharedMem, PlatformHandle)> {
             unsafe {
                 let handle = CreateFileMappingA(
                     INVALID_HANDLE_VALUE,
@@ -246,7 +415,7 @@ mod windows {
                     return Err(std::io::Error::last_os_error().into());
                 }
 
-                let ptr = MapViewOfFile(handle, FILE_MAP_ALL_ACCESS, 0, 0, size);
+                let ptr = MapViewOfFile(handle, map_access(access), 0, 0, size);
                 if ptr.is_null() {
                     return Err(std::io::Error::last_os_error().into());
                 }
@@ -255,15 +424,19 @@ mod windows {
                 Ok((
                     SharedMem {
                         handle,
-                        view: SharedMemView { ptr, size },
+                        view: SharedMemView { ptr, size, access },
                     },
                     handle2,
                 ))
             }
         }
 
-        pub unsafe fn from(handle: &PlatformHandle, size: usize) -> Result<SharedMem> {
-            let ptr = MapViewOfFile(handle.as_raw(), FILE_MAP_ALL_ACCESS, 0, 0, size);
+        pub unsafe fn from(
+            handle: &PlatformHandle,
+            size: usize,
+            access: Access,
+        ) -> Result<SharedMem> {
+            let ptr = MapViewOfFile(handle.as_raw(), map_access(access), 0, 0, size);
             if ptr.is_null() {
                 return Err(std::io::Error::last_os_error().into());
             }
@@ -271,10 +444,63 @@ mod windows {
                 // A invalid `handle` means this is a non-owning `SharedMem`.  See `Drop` impl.
                 // TODO: This can be made *owning* after further `PlatformHandle` ownership refactoring.
                 handle: INVALID_HANDLE_VALUE,
-                view: SharedMemView { ptr, size },
+                view: SharedMemView { ptr, size, access },
             })
         }
 
+        /// Zero the area.  A read-only mapping can only be zeroed if the
+        /// area was created by this process.  Undoes any `protect` of a
+        /// read-write mapping.
+        pub fn zero(&mut self) -> Result<()> {
+            let size = self.view.size;
+            unsafe {
+                if self.view.access == Access::ReadWrite {
+                    self.protect(0, size, Access::ReadWrite)?;
+                    ptr::write_bytes(self.view.ptr as *mut u8, 0, size);
+                    return Ok(());
+                }
+                if self.handle == INVALID_HANDLE_VALUE {
+                    bail!("can't zero a read-only mapping of a peer's area");
+                }
+                let ptr = MapViewOfFile(self.handle, FILE_MAP_WRITE, 0, 0, size);
+                if ptr.is_null() {
+                    return Err(std::io::Error::last_os_error().into());
+                }
+                ptr::write_bytes(ptr as *mut u8, 0, size);
+                let ok = UnmapViewOfFile(ptr);
+                assert_ne!(ok, 0);
+            }
+            Ok(())
+        }
+
+        /// Change the protection of the `size` bytes at `offset` of a
+        /// read-write mapping, which must start on a page boundary.
+        /// Protection applies to whole pages.
+        pub fn protect(&mut self, offset: usize, size: usize, access: Access) -> Result<()> {
+            check_protect(&self.view, offset, size)?;
+            let protection = match access {
+                Access::ReadOnly => PAGE_READONLY,
+                Access::ReadWrite => PAGE_READWRITE,
+            };
+            let mut old = 0;
+            let ok = unsafe {
+                VirtualProtect(
+                    (self.view.ptr as *mut u8).add(offset) as *mut c_void,
+                    size,
+                    protection,
+                    &mut old,
+                )
+            };
+            if ok == 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            Ok(())
+        }
+
+        pub fn size(&self) -> usize {
+            self.view.size
+        }
+
         pub unsafe fn unsafe_view(&self) -> SharedMemView {
             self.view
         }
@@ -288,3 +514,63 @@ mod windows {
         }
     }
 }
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::{Access, SharedMem};
+
+    #[test]
+    fn read_only_mapping() {
+        let (mut shm, handle) = SharedMem::new("audioipc-shm-test", 64, Access::ReadWrite).unwrap();
+        unsafe { shm.get_mut_slice(64).unwrap()[0] = 42 };
+
+        let mut peer = unsafe { SharedMem::from(&handle, 64, Access::ReadOnly).unwrap() };
+        assert_eq!(unsafe { peer.get_slice(64).unwrap()[0] }, 42);
+        assert!(unsafe { peer.get_mut_slice(64) }.is_err());
+        assert!(unsafe { peer.unsafe_view().get_mut_slice(1) }.is_err());
+
+        drop(peer);
+        let (mut shm, handle) = SharedMem::new("audioipc-shm-test", 64, Access::ReadOnly).unwrap();
+        let mut peer = unsafe { SharedMem::from(&handle, 64, Access::ReadWrite).unwrap() };
+        unsafe { peer.get_mut_slice(64).unwrap()[63] = 42 };
+        shm.zero().unwrap();
+        assert_eq!(unsafe { shm.get_slice(64).unwrap()[63] }, 0);
+    }
+
+    #[test]
+    fn size_mismatch_is_rejected() {
+        let (_shm, handle) = SharedMem::new("audioipc-shm-test", 64, Access::ReadWrite).unwrap();
+        assert!(unsafe { SharedMem::from(&handle, 128, Access::ReadWrite) }.is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn memfd_is_sealed() {
+        use std::fs::File;
+        use std::os::unix::io::FromRawFd;
+
+        let (_shm, handle) = SharedMem::new("audioipc-shm-test", 64, Access::ReadWrite).unwrap();
+        let file = unsafe { File::from_raw_fd(libc::dup(handle.as_raw())) };
+        assert!(file.set_len(32).is_err());
+        assert!(file.set_len(128).is_err());
+        assert_eq!(file.metadata().unwrap().len(), 64);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn unsealed_file_is_rejected() {
+        use crate::PlatformHandle;
+
+        let path = std::env::temp_dir().join(format!("audioipc-shm-{}", std::process::id()));
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        file.set_len(64).unwrap();
+        let handle = PlatformHandle::from(file);
+        assert!(unsafe { SharedMem::from(&handle, 64, Access::ReadWrite) }.is_err());
+    }
+}
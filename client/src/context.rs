@@ -5,18 +5,20 @@
 
 use crate::stream;
 use crate::{assert_not_in_callback, run_in_callback};
-use crate::{ClientStream, AUDIOIPC_INIT_PARAMS};
+use crate::{ClientOptions, ClientStream, AUDIOIPC_INIT_PARAMS};
 #[cfg(target_os = "linux")]
 use audio_thread_priority::get_current_thread_info;
 #[cfg(not(target_os = "linux"))]
 use audio_thread_priority::promote_current_thread_to_real_time;
-use audioipc::codec::LengthDelimitedCodec;
+use audioipc::codec::{
+    LengthDelimitedCodec, MessageLimit, MAX_CALLBACK_MESSAGE_LEN, MAX_MESSAGE_LEN,
+};
 use audioipc::frame::{framed, Framed};
 use audioipc::platformhandle_passing::{framed_with_platformhandles, FramedWithPlatformHandles};
 use audioipc::{core, rpc};
 use audioipc::{
     messages, messages::DeviceCollectionReq, messages::DeviceCollectionResp, ClientMessage,
-    ServerMessage,
+    PlatformHandleType, ServerMessage,
 };
 use cubeb_backend::{
     ffi, Context, ContextOps, DeviceCollectionRef, DeviceId, DeviceType, Error, Ops, Result,
@@ -26,9 +28,11 @@ use futures::Future;
 use futures_cpupool::{CpuFuture, CpuPool};
 use std::ffi::{CStr, CString};
 use std::os::raw::c_void;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, Weak};
 use std::thread;
+use std::time::Duration;
 use std::{fmt, io, mem, ptr};
 use tokio::reactor;
 use tokio::runtime::current_thread;
@@ -40,7 +44,7 @@ impl rpc::Client for CubebClient {
     type Response = ClientMessage;
     type Transport = FramedWithPlatformHandles<
         audioipc::AsyncMessageStream,
-        LengthDelimitedCodec<Self::Request, Self::Response>,
+        LengthDelimitedCodec<rpc::Envelope<Self::Request>, rpc::Envelope<Self::Response>>,
     >;
 }
 
@@ -51,15 +55,41 @@ pub const CLIENT_OPS: Ops = capi_new!(ClientContext, ClientStream);
 #[repr(C)]
 pub struct ClientContext {
     _ops: *const Ops,
-    rpc: rpc::ClientProxy<ServerMessage, ClientMessage>,
+    // Shared with realtime threads, which promote themselves through the
+    // connection current when they start.
+    server: Arc<Mutex<ServerConnection>>,
     core: core::CoreThread,
     cpu_pool: CpuPool,
+    output_ring_buffer_frames: u32,
+    shm_data_signal: bool,
+    stream_callback_threads: bool,
+    stack_size: usize,
+    rpc_timeout: Option<Duration>,
+    thread_create_callback: Option<extern "C" fn(*const ::std::os::raw::c_char)>,
+    thread_destroy_callback: Option<extern "C" fn()>,
+    reconnect_callback: Option<extern "C" fn(*mut c_void) -> PlatformHandleType>,
+    reconnect_user_data: *mut c_void,
+    // Serializes attempts to reconnect to the server.
+    reconnect_lock: Mutex<()>,
+    // Threads started by StreamRecovery, joined on drop.  `None` once
+    // dropping.
+    recovery_threads: Mutex<Option<Vec<thread::JoinHandle<()>>>>,
+    // Open streams, recreated after reconnecting to the server.
+    streams: Mutex<Vec<Weak<stream::StreamShared>>>,
     backend_id: CString,
-    device_collection_rpc: bool,
+    device_collection_rpc: AtomicBool,
     input_device_callback: Arc<Mutex<DeviceCollectionCallback>>,
     output_device_callback: Arc<Mutex<DeviceCollectionCallback>>,
 }
 
+// The current connection to the server, replaced when reconnecting.
+pub(crate) struct ServerConnection {
+    rpc: rpc::ClientProxy<ServerMessage, ClientMessage>,
+    params: messages::ConnectionParams,
+    // Incremented each time the connection is re-established.
+    generation: usize,
+}
+
 impl ClientContext {
     #[doc(hidden)]
     pub fn handle(&self) -> current_thread::Handle {
@@ -68,13 +98,346 @@ impl ClientContext {
 
     #[doc(hidden)]
     pub fn rpc(&self) -> rpc::ClientProxy<ServerMessage, ClientMessage> {
-        self.rpc.clone()
+        self.server.lock().unwrap().rpc.clone()
+    }
+
+    pub(crate) fn server_connection(&self) -> Arc<Mutex<ServerConnection>> {
+        self.server.clone()
+    }
+
+    pub(crate) fn rpc_and_generation(
+        &self,
+    ) -> (rpc::ClientProxy<ServerMessage, ClientMessage>, usize) {
+        let server = self.server.lock().unwrap();
+        (server.rpc.clone(), server.generation)
     }
 
     #[doc(hidden)]
     pub fn cpu_pool(&self) -> CpuPool {
         self.cpu_pool.clone()
     }
+
+    // Frames to render ahead for output-only streams, or `None` if the
+    // ring buffer transport is disabled or unsupported by the server.
+    pub(crate) fn output_ring_buffer_frames(&self) -> Option<u32> {
+        if self.output_ring_buffer_frames > 0
+            && self
+                .server
+                .lock()
+                .unwrap()
+                .params
+                .has_feature(messages::FEATURE_SHM_RING_BUFFER)
+        {
+            Some(self.output_ring_buffer_frames)
+        } else {
+            None
+        }
+    }
+
+    // Whether to signal data callbacks through shared memory, if enabled
+    // and supported by the server.
+    pub(crate) fn shm_data_signal(&self) -> bool {
+        self.shm_data_signal
+            && self
+                .server
+                .lock()
+                .unwrap()
+                .params
+                .has_feature(messages::FEATURE_SHM_DATA_SIGNAL)
+    }
+
+    // A pool with a single realtime thread for one stream's data callbacks,
+    // or `None` if streams share `cpu_pool`.
+    pub(crate) fn stream_callback_pool(&self) -> Option<CpuPool> {
+        if !self.stream_callback_threads {
+            return None;
+        }
+        let server = self.server_connection();
+        let (thread_create_callback, thread_destroy_callback) = self.thread_callbacks();
+        Some(
+            futures_cpupool::Builder::new()
+                .name_prefix("AudioIPC Stream")
+                .after_start(move || promote_and_register_thread(&server, thread_create_callback))
+                .before_stop(move || unregister_thread(thread_destroy_callback))
+                .pool_size(1)
+                .stack_size(self.stack_size)
+                .create(),
+        )
+    }
+
+    pub(crate) fn thread_callbacks(
+        &self,
+    ) -> (
+        Option<extern "C" fn(*const ::std::os::raw::c_char)>,
+        Option<extern "C" fn()>,
+    ) {
+        (self.thread_create_callback, self.thread_destroy_callback)
+    }
+
+    pub(crate) fn register_stream(&self, stream: &Arc<stream::StreamShared>) {
+        let mut streams = self.streams.lock().unwrap();
+        streams.retain(|s| s.strong_count() > 0);
+        streams.push(Arc::downgrade(stream));
+    }
+
+    // Run `f` against the server.  If it fails because the server has gone
+    // away, reconnect and retry once.
+    fn call_server<T, F>(&self, f: F) -> Result<T>
+    where
+        F: Fn(&rpc::ClientProxy<ServerMessage, ClientMessage>) -> Result<T>,
+    {
+        let (rpc, generation) = self.rpc_and_generation();
+        match f(&rpc) {
+            Err(_) if rpc.is_closed() => {
+                self.reconnect(generation)?;
+                f(&self.rpc())
+            }
+            r => r,
+        }
+    }
+
+    // Re-establish the connection to the server after it has gone away and
+    // recreate the open streams.  `generation` is that of the connection
+    // found to be closed; if it has already been replaced there's nothing
+    // to do.  If the server can't be reached the open streams are put in
+    // the error state.
+    pub(crate) fn reconnect(&self, generation: usize) -> Result<()> {
+        let _guard = self.reconnect_lock.lock().unwrap();
+        if self.server.lock().unwrap().generation != generation {
+            return Ok(());
+        }
+
+        let streams: Vec<_> = {
+            let mut streams = self.streams.lock().unwrap();
+            streams.retain(|s| s.strong_count() > 0);
+            streams.iter().filter_map(Weak::upgrade).collect()
+        };
+
+        let (rpc, params) = match self.connect_server() {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("Failed to reconnect to server");
+                for stream in &streams {
+                    stream.fail(self);
+                }
+                return Err(e);
+            }
+        };
+
+        {
+            let mut server = self.server.lock().unwrap();
+            server.rpc = rpc;
+            server.params = params;
+            server.generation += 1;
+        }
+        info!("Reconnected to server");
+
+        if let Err(e) = self.restore_device_collection_changed() {
+            warn!("Failed to restore device collection callbacks: {:?}", e);
+        }
+        for stream in &streams {
+            stream.recreate(self);
+        }
+        Ok(())
+    }
+
+    // Obtain a new server connection from the reconnect callback and
+    // perform the handshake on it.
+    fn connect_server(
+        &self,
+    ) -> Result<(
+        rpc::ClientProxy<ServerMessage, ClientMessage>,
+        messages::ConnectionParams,
+    )> {
+        let callback = self.reconnect_callback.ok_or_else(Error::error)?;
+        let server_connection = callback(self.reconnect_user_data);
+        if server_connection == audioipc::INVALID_HANDLE_VALUE {
+            return Err(Error::error());
+        }
+        let server_stream = unsafe { audioipc::MessageStream::from_raw_fd(server_connection) };
+
+        let (tx_rpc, rx_rpc) = mpsc::channel();
+        let limit = MessageLimit::new(MAX_MESSAGE_LEN as usize);
+        let codec_limit = limit.clone();
+        self.handle()
+            .spawn(futures::future::lazy(move || {
+                let handle = reactor::Handle::default();
+                if let Err(e) = server_stream
+                    .into_tokio_ipc(&handle)
+                    .and_then(|stream| bind_and_send_client(stream, codec_limit, &tx_rpc))
+                {
+                    debug!("Failed to bind server connection: {:?}", e);
+                }
+                Ok(())
+            }))
+            .map_err(|_| Error::error())?;
+        let mut rpc = rx_rpc.recv().map_err(|_| Error::error())?;
+        rpc.set_timeout(self.rpc_timeout);
+
+        let params = connect(&rpc, &limit)?;
+        Ok((rpc, params))
+    }
+
+    // Set up the connection the server sends device collection changes on,
+    // if not already done.
+    fn setup_device_collection_rpc(
+        &self,
+        rpc: &rpc::ClientProxy<ServerMessage, ClientMessage>,
+    ) -> Result<()> {
+        if self
+            .device_collection_rpc
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return Ok(());
+        }
+
+        let r = self.bind_device_collection_rpc(rpc);
+        if r.is_err() {
+            self.device_collection_rpc.store(false, Ordering::SeqCst);
+        }
+        r
+    }
+
+    fn bind_device_collection_rpc(
+        &self,
+        rpc: &rpc::ClientProxy<ServerMessage, ClientMessage>,
+    ) -> Result<()> {
+        let fds = send_recv!(rpc,
+                             ContextSetupDeviceCollectionCallback =>
+                             ContextSetupDeviceCollectionCallback())?;
+
+        // The transport checked the message carries exactly one handle.
+        let stream =
+            unsafe { audioipc::MessageStream::from_raw_fd(fds.platform_handles[0].into_raw()) };
+
+        let server = DeviceCollectionServer {
+            input_device_callback: self.input_device_callback.clone(),
+            output_device_callback: self.output_device_callback.clone(),
+            cpu_pool: self.cpu_pool(),
+        };
+
+        let (wait_tx, wait_rx) = mpsc::channel();
+        self.handle()
+            .spawn(futures::future::lazy(move || {
+                let handle = reactor::Handle::default();
+                let stream = stream.into_tokio_ipc(&handle).unwrap();
+                let transport = framed(stream, rpc::server_codec::<DeviceCollectionServer>());
+                rpc::bind_server(transport, server);
+                wait_tx.send(()).unwrap();
+                Ok(())
+            }))
+            .expect("Failed to spawn DeviceCollectionServer");
+        wait_rx.recv().unwrap();
+        Ok(())
+    }
+
+    pub(crate) fn stream_recovery(
+        &self,
+        stream: &Arc<stream::StreamShared>,
+        generation: usize,
+    ) -> StreamRecovery {
+        StreamRecovery {
+            ctx: self,
+            stream: Arc::downgrade(stream),
+            generation,
+        }
+    }
+
+    // Re-register the device collection callbacks on a new connection.
+    fn restore_device_collection_changed(&self) -> Result<()> {
+        self.device_collection_rpc.store(false, Ordering::SeqCst);
+        let mut devtype = DeviceType::empty();
+        if self.input_device_callback.lock().unwrap().cb.is_some() {
+            devtype |= DeviceType::INPUT;
+        }
+        if self.output_device_callback.lock().unwrap().cb.is_some() {
+            devtype |= DeviceType::OUTPUT;
+        }
+        if devtype.is_empty() {
+            return Ok(());
+        }
+
+        let rpc = self.rpc();
+        self.setup_device_collection_rpc(&rpc)?;
+        send_recv!(rpc,
+                   ContextRegisterDeviceCollectionChanged(devtype.bits(), true) =>
+                   ContextRegisteredDeviceCollectionChanged)
+    }
+}
+
+// Recovers a stream whose callback connection closed while it was open,
+// which happens on the RPC thread, so on a thread of its own.
+pub(crate) struct StreamRecovery {
+    ctx: *const ClientContext,
+    stream: Weak<stream::StreamShared>,
+    // That of the connection the stream was created on.
+    generation: usize,
+}
+
+// The thread dereferences `ctx`, which outlives it.  See the `Drop` for
+// `ClientContext`.
+unsafe impl Send for StreamRecovery {}
+
+impl StreamRecovery {
+    pub(crate) fn start(self) {
+        let ctx = unsafe { &*self.ctx };
+        let mut threads = ctx.recovery_threads.lock().unwrap();
+        let threads = match *threads {
+            Some(ref mut threads) => threads,
+            None => return,
+        };
+        // Join those done, so a context that sees many server restarts
+        // doesn't accumulate them.
+        let (done, running) = threads.drain(..).partition(|t| t.is_finished());
+        *threads = running;
+        for thread in done {
+            let _ = thread.join();
+        }
+        match thread::Builder::new()
+            .name("AudioIPC Recovery".into())
+            .spawn(move || self.run())
+        {
+            Ok(thread) => threads.push(thread),
+            Err(e) => warn!("Failed to start stream recovery: {:?}", e),
+        }
+    }
+
+    fn run(self) {
+        let ctx = unsafe { &*self.ctx };
+        let stream = match self.stream.upgrade() {
+            Some(stream) => stream,
+            None => return,
+        };
+        let (rpc, generation) = ctx.rpc_and_generation();
+        // Already reconnected, which recreated the stream.
+        if generation != self.generation {
+            return;
+        }
+        match send_recv!(rpc, ContextGetBackendId => ContextBackendId()) {
+            Err(_) if rpc.is_closed() => {
+                // Puts the stream in the error state on failure.
+                let _ = ctx.reconnect(generation);
+            }
+            // The server is still there but has dropped the stream.
+            _ => stream.fail(ctx),
+        }
+    }
+}
+
+// `limit` is applied to the connection's messages, and lowered to the
+// negotiated limit by `connect`.
+fn bind_and_send_client(
+    stream: audioipc::AsyncMessageStream,
+    limit: MessageLimit,
+    tx_rpc: &mpsc::Sender<rpc::ClientProxy<ServerMessage, ClientMessage>>,
+) -> io::Result<()> {
+    let transport = framed_with_platformhandles(stream, LengthDelimitedCodec::with_limit(limit));
+    let rpc = rpc::bind_client::<CubebClient>(transport);
+    // If send fails then the rx end has closed
+    // which is unlikely here.
+    let _ = tx_rpc.send(rpc);
+    Ok(())
 }
 
 #[cfg(target_os = "linux")]
@@ -103,7 +466,7 @@ fn promote_thread(_rpc: &rpc::ClientProxy<ServerMessage, ClientMessage>) {
     }
 }
 
-fn register_thread(callback: Option<extern "C" fn(*const ::std::os::raw::c_char)>) {
+pub(crate) fn register_thread(callback: Option<extern "C" fn(*const ::std::os::raw::c_char)>) {
     if let Some(func) = callback {
         let thr = thread::current();
         let name = CString::new(thr.name().unwrap()).unwrap();
@@ -111,20 +474,50 @@ fn register_thread(callback: Option<extern "C" fn(*const ::std::os::raw::c_char)
     }
 }
 
-fn unregister_thread(callback: Option<extern "C" fn()>) {
+pub(crate) fn unregister_thread(callback: Option<extern "C" fn()>) {
     if let Some(func) = callback {
         func();
     }
 }
 
-fn promote_and_register_thread(
-    rpc: &rpc::ClientProxy<ServerMessage, ClientMessage>,
+pub(crate) fn promote_and_register_thread(
+    server: &Mutex<ServerConnection>,
     callback: Option<extern "C" fn(*const ::std::os::raw::c_char)>,
 ) {
-    promote_thread(rpc);
+    // Not a connection captured when the thread was created, which may
+    // have been replaced since.
+    let rpc = server.lock().unwrap().rpc.clone();
+    promote_thread(&rpc);
     register_thread(callback);
 }
 
+// Perform the protocol handshake.  This must be the first message sent on a
+// new connection.
+fn connect(
+    rpc: &rpc::ClientProxy<ServerMessage, ClientMessage>,
+    limit: &MessageLimit,
+) -> Result<messages::ConnectionParams> {
+    let params = messages::ClientConnectParams::new(std::process::id());
+    match rpc.call(ServerMessage::ClientConnect(params)).wait() {
+        Ok(ClientMessage::ClientConnected(connection)) => {
+            debug!("Connected to server: {:?}", connection);
+            limit.set(connection.max_message_len as usize);
+            Ok(connection)
+        }
+        Ok(ClientMessage::ClientRejected(version)) => {
+            warn!(
+                "Server rejected connection: client protocol version {}, server protocol version {}",
+                params.protocol_version, version
+            );
+            Err(Error::error())
+        }
+        r => {
+            debug!("Handshake failed - got={:?}", r);
+            Err(Error::error())
+        }
+    }
+}
+
 #[derive(Default)]
 struct DeviceCollectionCallback {
     cb: ffi::cubeb_device_collection_changed_callback,
@@ -141,8 +534,11 @@ impl rpc::Server for DeviceCollectionServer {
     type Request = DeviceCollectionReq;
     type Response = DeviceCollectionResp;
     type Future = CpuFuture<Self::Response, ()>;
-    type Transport =
-        Framed<audioipc::AsyncMessageStream, LengthDelimitedCodec<Self::Response, Self::Request>>;
+    type Transport = Framed<
+        audioipc::AsyncMessageStream,
+        LengthDelimitedCodec<rpc::Envelope<Self::Response>, rpc::Envelope<Self::Request>>,
+    >;
+    const MAX_MESSAGE_LEN: usize = MAX_CALLBACK_MESSAGE_LEN;
 
     fn process(&mut self, req: Self::Request) -> Self::Future {
         match req {
@@ -164,16 +560,19 @@ impl rpc::Server for DeviceCollectionServer {
                 };
 
                 self.cpu_pool.spawn_fn(move || {
+                    // A callback may have been unregistered while the
+                    // notification was in flight.
                     run_in_callback(|| {
-                        if devtype.contains(cubeb_backend::DeviceType::INPUT) {
-                            unsafe {
-                                input_cb.unwrap()(ptr::null_mut(), input_user_ptr as *mut c_void)
-                            }
+                        if let (true, Some(cb)) =
+                            (devtype.contains(cubeb_backend::DeviceType::INPUT), input_cb)
+                        {
+                            unsafe { cb(ptr::null_mut(), input_user_ptr as *mut c_void) }
                         }
-                        if devtype.contains(cubeb_backend::DeviceType::OUTPUT) {
-                            unsafe {
-                                output_cb.unwrap()(ptr::null_mut(), output_user_ptr as *mut c_void)
-                            }
+                        if let (true, Some(cb)) = (
+                            devtype.contains(cubeb_backend::DeviceType::OUTPUT),
+                            output_cb,
+                        ) {
+                            unsafe { cb(ptr::null_mut(), output_user_ptr as *mut c_void) }
                         }
                     });
 
@@ -184,30 +583,22 @@ impl rpc::Server for DeviceCollectionServer {
     }
 }
 
-impl ContextOps for ClientContext {
-    fn init(_context_name: Option<&CStr>) -> Result<Context> {
-        fn bind_and_send_client(
-            stream: audioipc::AsyncMessageStream,
-            tx_rpc: &mpsc::Sender<rpc::ClientProxy<ServerMessage, ClientMessage>>,
-        ) -> io::Result<()> {
-            let transport = framed_with_platformhandles(stream, Default::default());
-            let rpc = rpc::bind_client::<CubebClient>(transport);
-            // If send fails then the rx end has closed
-            // which is unlikely here.
-            let _ = tx_rpc.send(rpc);
-            Ok(())
-        }
-
+impl ClientContext {
+    /// Create a cubeb context using the server at the other end of
+    /// `server_stream`, such as a stream returned by the server's
+    /// `new_client`.
+    pub fn connect(
+        server_stream: audioipc::MessageStream,
+        options: ClientOptions,
+    ) -> Result<Context> {
         assert_not_in_callback();
 
         let (tx_rpc, rx_rpc) = mpsc::channel();
+        let limit = MessageLimit::new(MAX_MESSAGE_LEN as usize);
+        let codec_limit = limit.clone();
 
-        let params = AUDIOIPC_INIT_PARAMS.with(|p| p.replace(None).unwrap());
-        let thread_create_callback = params.thread_create_callback;
-        let thread_destroy_callback = params.thread_destroy_callback;
-
-        let server_stream =
-            unsafe { audioipc::MessageStream::from_raw_fd(params.server_connection) };
+        let thread_create_callback = options.thread_create_callback;
+        let thread_destroy_callback = options.thread_destroy_callback;
 
         let core = core::spawn_thread(
             "AudioIPC Client RPC",
@@ -218,43 +609,77 @@ impl ContextOps for ClientContext {
 
                 server_stream
                     .into_tokio_ipc(&handle)
-                    .and_then(|stream| bind_and_send_client(stream, &tx_rpc))
+                    .and_then(|stream| bind_and_send_client(stream, codec_limit, &tx_rpc))
             },
             move || unregister_thread(thread_destroy_callback),
         )
         .map_err(|_| Error::default())?;
 
-        let rpc = rx_rpc.recv().map_err(|_| Error::default())?;
-        let rpc2 = rpc.clone();
-
-        // Don't let errors bubble from here.  Later calls against this context
-        // will return errors the caller expects to handle.
-        let _ = send_recv!(rpc, ClientConnect(std::process::id()) => ClientConnected);
+        let mut rpc = rx_rpc.recv().map_err(|_| Error::default())?;
+        rpc.set_timeout(options.rpc_timeout);
+        let connection = connect(&rpc, &limit)?;
 
         let backend_id = send_recv!(rpc, ContextGetBackendId => ContextBackendId())
             .unwrap_or_else(|_| "(remote error)".to_string());
         let backend_id = CString::new(backend_id).expect("backend_id query failed");
 
-        let cpu_pool = futures_cpupool::Builder::new()
+        let server = Arc::new(Mutex::new(ServerConnection {
+            rpc,
+            params: connection,
+            generation: 0,
+        }));
+        let pool_server = server.clone();
+
+        let mut cpu_pool = futures_cpupool::Builder::new();
+        cpu_pool
             .name_prefix("AudioIPC")
-            .after_start(move || promote_and_register_thread(&rpc2, thread_create_callback))
             .before_stop(move || unregister_thread(thread_destroy_callback))
-            .pool_size(params.pool_size)
-            .stack_size(params.stack_size)
-            .create();
+            .pool_size(options.pool_size)
+            .stack_size(options.stack_size);
+        // With per-stream callback threads the pool runs no data callbacks,
+        // so is left at normal priority.
+        if options.stream_callback_threads {
+            cpu_pool.after_start(move || register_thread(thread_create_callback));
+        } else {
+            cpu_pool.after_start(move || {
+                promote_and_register_thread(&pool_server, thread_create_callback)
+            });
+        }
+        let cpu_pool = cpu_pool.create();
 
         let ctx = Box::new(ClientContext {
             _ops: &CLIENT_OPS as *const _,
-            rpc,
+            server,
             core,
             cpu_pool,
+            output_ring_buffer_frames: options.output_ring_buffer_frames,
+            shm_data_signal: options.shm_data_signal,
+            stream_callback_threads: options.stream_callback_threads,
+            stack_size: options.stack_size,
+            rpc_timeout: options.rpc_timeout,
+            thread_create_callback,
+            thread_destroy_callback,
+            reconnect_callback: options.reconnect_callback,
+            reconnect_user_data: options.reconnect_user_data,
+            reconnect_lock: Mutex::new(()),
+            recovery_threads: Mutex::new(Some(Vec::new())),
+            streams: Mutex::new(Vec::new()),
             backend_id,
-            device_collection_rpc: false,
+            device_collection_rpc: AtomicBool::new(false),
             input_device_callback: Arc::new(Mutex::new(Default::default())),
             output_device_callback: Arc::new(Mutex::new(Default::default())),
         });
         Ok(unsafe { Context::from_ptr(Box::into_raw(ctx) as *mut _) })
     }
+}
+
+impl ContextOps for ClientContext {
+    fn init(_context_name: Option<&CStr>) -> Result<Context> {
+        let params = AUDIOIPC_INIT_PARAMS.with(|p| p.replace(None).unwrap());
+        let server_stream =
+            unsafe { audioipc::MessageStream::from_raw_fd(params.server_connection) };
+        ClientContext::connect(server_stream, ClientOptions::from(&params))
+    }
 
     fn backend_id(&mut self) -> &CStr {
         assert_not_in_callback();
@@ -263,18 +688,22 @@ impl ContextOps for ClientContext {
 
     fn max_channel_count(&mut self) -> Result<u32> {
         assert_not_in_callback();
-        send_recv!(self.rpc(), ContextGetMaxChannelCount => ContextMaxChannelCount())
+        self.call_server(
+            |rpc| send_recv!(rpc, ContextGetMaxChannelCount => ContextMaxChannelCount()),
+        )
     }
 
     fn min_latency(&mut self, params: StreamParams) -> Result<u32> {
         assert_not_in_callback();
         let params = messages::StreamParams::from(params.as_ref());
-        send_recv!(self.rpc(), ContextGetMinLatency(params) => ContextMinLatency())
+        self.call_server(|rpc| send_recv!(rpc, ContextGetMinLatency(params) => ContextMinLatency()))
     }
 
     fn preferred_sample_rate(&mut self) -> Result<u32> {
         assert_not_in_callback();
-        send_recv!(self.rpc(), ContextGetPreferredSampleRate => ContextPreferredSampleRate())
+        self.call_server(
+            |rpc| send_recv!(rpc, ContextGetPreferredSampleRate => ContextPreferredSampleRate()),
+        )
     }
 
     fn enumerate_devices(
@@ -283,10 +712,11 @@ impl ContextOps for ClientContext {
         collection: &DeviceCollectionRef,
     ) -> Result<()> {
         assert_not_in_callback();
-        let v: Vec<ffi::cubeb_device_info> = match send_recv!(self.rpc(),
-                             ContextGetDeviceEnumeration(devtype.bits()) =>
-                             ContextEnumeratedDevices())
-        {
+        let v: Vec<ffi::cubeb_device_info> = match self.call_server(|rpc| {
+            send_recv!(rpc,
+                       ContextGetDeviceEnumeration(devtype.bits()) =>
+                       ContextEnumeratedDevices())
+        }) {
             Ok(mut v) => v.drain(..).map(|i| i.into()).collect(),
             Err(e) => return Err(e),
         };
@@ -378,38 +808,6 @@ impl ContextOps for ClientContext {
     ) -> Result<()> {
         assert_not_in_callback();
 
-        if !self.device_collection_rpc {
-            let fds = send_recv!(self.rpc(),
-                                 ContextSetupDeviceCollectionCallback =>
-                                 ContextSetupDeviceCollectionCallback())?;
-
-            // TODO: The lowest comms layer expects exactly 3 PlatformHandles, but we only
-            // need one here.  The server sent two dummy valid handles, ignore those (closed on drop)
-            // and use the one we need.
-            let stream =
-                unsafe { audioipc::MessageStream::from_raw_fd(fds.platform_handles[0].into_raw()) };
-
-            let server = DeviceCollectionServer {
-                input_device_callback: self.input_device_callback.clone(),
-                output_device_callback: self.output_device_callback.clone(),
-                cpu_pool: self.cpu_pool(),
-            };
-
-            let (wait_tx, wait_rx) = mpsc::channel();
-            self.handle()
-                .spawn(futures::future::lazy(move || {
-                    let handle = reactor::Handle::default();
-                    let stream = stream.into_tokio_ipc(&handle).unwrap();
-                    let transport = framed(stream, Default::default());
-                    rpc::bind_server(transport, server);
-                    wait_tx.send(()).unwrap();
-                    Ok(())
-                }))
-                .expect("Failed to spawn DeviceCollectionServer");
-            wait_rx.recv().unwrap();
-            self.device_collection_rpc = true;
-        }
-
         if devtype.contains(cubeb_backend::DeviceType::INPUT) {
             let mut cb = self.input_device_callback.lock().unwrap();
             cb.cb = collection_changed_callback;
@@ -422,15 +820,31 @@ impl ContextOps for ClientContext {
         }
 
         let enable = collection_changed_callback.is_some();
-        send_recv!(self.rpc(),
-                   ContextRegisterDeviceCollectionChanged(devtype.bits(), enable) =>
-                   ContextRegisteredDeviceCollectionChanged)
+        let (rpc, generation) = self.rpc_and_generation();
+        let r = self.setup_device_collection_rpc(&rpc).and_then(|_| {
+            send_recv!(rpc,
+                       ContextRegisterDeviceCollectionChanged(devtype.bits(), enable) =>
+                       ContextRegisteredDeviceCollectionChanged)
+        });
+        match r {
+            // Reconnecting re-registers the callbacks stored above.
+            Err(_) if rpc.is_closed() => self.reconnect(generation),
+            r => r,
+        }
     }
 }
 
 impl Drop for ClientContext {
     fn drop(&mut self) {
         debug!("ClientContext dropped...");
+        // Recovery threads hold a raw pointer to the context.  Clearing
+        // `recovery_threads` under its lock stops new ones starting, since
+        // `StreamRecovery::start` only starts one while it's set, and those
+        // started are joined before the context goes away.
+        let threads = self.recovery_threads.lock().unwrap().take();
+        for thread in threads.into_iter().flatten() {
+            let _ = thread.join();
+        }
         let _ = send_recv!(self.rpc(), ClientDisconnect => ClientDisconnected);
     }
 }
@@ -439,7 +853,7 @@ impl fmt::Debug for ClientContext {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("ClientContext")
             .field("_ops", &self._ops)
-            .field("rpc", &self.rpc)
+            .field("rpc", &self.rpc())
             .field("core", &self.core)
             .field("cpu_pool", &"...")
             .finish()
@@ -14,11 +14,13 @@ mod send_recv;
 mod context;
 mod stream;
 
-use crate::context::ClientContext;
-use crate::stream::ClientStream;
+pub use crate::context::ClientContext;
+pub use crate::stream::{ClientStream, StreamStats};
 use audioipc::PlatformHandleType;
 use cubeb_backend::{capi, ffi};
-use std::os::raw::{c_char, c_int};
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+use std::time::Duration;
 
 thread_local!(static IN_CALLBACK: std::cell::RefCell<bool> = std::cell::RefCell::new(false));
 thread_local!(static AUDIOIPC_INIT_PARAMS: std::cell::RefCell<Option<AudioIpcInitParams>> = std::cell::RefCell::new(None));
@@ -34,10 +36,99 @@ pub struct AudioIpcInitParams {
     pub stack_size: usize,
     pub thread_create_callback: Option<extern "C" fn(*const ::std::os::raw::c_char)>,
     pub thread_destroy_callback: Option<extern "C" fn()>,
+    // Frames of output rendered ahead into a shared memory ring buffer for
+    // output-only streams, or 0 to call back over RPC for every callback.
+    pub output_ring_buffer_frames: u32,
+    // Signal data callbacks through shared memory rather than over RPC,
+    // where the server supports it.
+    pub shm_data_signal: bool,
+    // Run each stream's data callbacks on a dedicated realtime thread, and
+    // leave the pool of `pool_size` threads to the other callbacks.
+    pub stream_callback_threads: bool,
+    // Milliseconds a cubeb API call waits for the server to answer before
+    // failing, or 0 to wait forever.
+    pub rpc_timeout_ms: u32,
+    // Called with `reconnect_user_data` to obtain a new server connection
+    // after the server has gone away.  Returns an invalid handle if the
+    // server can't be reached.  `None` disables reconnection.
+    pub reconnect_callback: Option<extern "C" fn(*mut c_void) -> PlatformHandleType>,
+    pub reconnect_user_data: *mut c_void,
 }
 
 unsafe impl Send for AudioIpcInitParams {}
 
+/// Options for `ClientContext::connect`.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientOptions {
+    /// Number of threads running stream and device callbacks.
+    pub pool_size: usize,
+    /// Stack size of the callback threads, including any per-stream ones.
+    pub stack_size: usize,
+    /// Called on each thread the client creates, with the thread's name.
+    pub thread_create_callback: Option<extern "C" fn(*const ::std::os::raw::c_char)>,
+    /// Called before each thread the client creates exits.
+    pub thread_destroy_callback: Option<extern "C" fn()>,
+    /// Frames of output rendered ahead into a shared memory ring buffer for
+    /// output-only streams, or 0 to call back over RPC for every callback.
+    pub output_ring_buffer_frames: u32,
+    /// Signal data callbacks through shared memory rather than over RPC,
+    /// where the server supports it.  Doesn't apply to streams using the
+    /// output ring buffer.
+    pub shm_data_signal: bool,
+    /// Run each stream's data callbacks on a dedicated thread promoted to
+    /// realtime, rather than on the shared pool.  The shared pool is then
+    /// left with state, device change and device collection callbacks and
+    /// isn't promoted.
+    pub stream_callback_threads: bool,
+    /// Longest a cubeb API call waits for the server to answer before
+    /// failing, so a hung server can't hang the calling thread.  `None`
+    /// waits forever.
+    pub rpc_timeout: Option<Duration>,
+    /// Called with `reconnect_user_data` to obtain a new server connection
+    /// after the server has gone away.  `None` disables reconnection.
+    pub reconnect_callback: Option<extern "C" fn(*mut c_void) -> PlatformHandleType>,
+    /// Passed to `reconnect_callback`, which may be called from any of the
+    /// client's threads until the context is destroyed.
+    pub reconnect_user_data: *mut c_void,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions {
+            pool_size: 1,
+            stack_size: 256 * 1024,
+            thread_create_callback: None,
+            thread_destroy_callback: None,
+            output_ring_buffer_frames: 0,
+            shm_data_signal: false,
+            stream_callback_threads: false,
+            rpc_timeout: Some(Duration::from_secs(10)),
+            reconnect_callback: None,
+            reconnect_user_data: ptr::null_mut(),
+        }
+    }
+}
+
+impl<'a> From<&'a AudioIpcInitParams> for ClientOptions {
+    fn from(params: &'a AudioIpcInitParams) -> Self {
+        ClientOptions {
+            pool_size: params.pool_size,
+            stack_size: params.stack_size,
+            thread_create_callback: params.thread_create_callback,
+            thread_destroy_callback: params.thread_destroy_callback,
+            output_ring_buffer_frames: params.output_ring_buffer_frames,
+            shm_data_signal: params.shm_data_signal,
+            stream_callback_threads: params.stream_callback_threads,
+            rpc_timeout: match params.rpc_timeout_ms {
+                0 => None,
+                ms => Some(Duration::from_millis(u64::from(ms))),
+            },
+            reconnect_callback: params.reconnect_callback,
+            reconnect_user_data: params.reconnect_user_data,
+        }
+    }
+}
+
 fn set_in_callback(in_callback: bool) {
     IN_CALLBACK.with(|b| {
         assert_eq!(*b.borrow(), !in_callback);
@@ -46,10 +46,18 @@ macro_rules! send_recv {
         match $resp.wait() {
             Ok(ClientMessage::$rmsg) => Ok(()),
             Ok(ClientMessage::Error(e)) => Err($crate::send_recv::_err(e)),
+            Ok(ClientMessage::LimitExceeded(limit)) => {
+                warn!("{} refused: server limit on {:?} reached", stringify!($rmsg), limit);
+                Err($crate::send_recv::_err(None))
+            },
             Ok(m) => {
                 debug!("received wrong message - got={:?}", m);
                 Err($crate::send_recv::_err(None))
             },
+            Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                warn!("rpc timed out waiting for {}", stringify!($rmsg));
+                Err($crate::send_recv::_err(None))
+            },
             Err(e) => {
                 debug!("received error from rpc - got={:?}", e);
                 Err($crate::send_recv::_err(None))
@@ -60,10 +68,18 @@ macro_rules! send_recv {
         match $resp.wait() {
             Ok(ClientMessage::$rmsg(v)) => Ok(v),
             Ok(ClientMessage::Error(e)) => Err($crate::send_recv::_err(e)),
+            Ok(ClientMessage::LimitExceeded(limit)) => {
+                warn!("{} refused: server limit on {:?} reached", stringify!($rmsg), limit);
+                Err($crate::send_recv::_err(None))
+            },
             Ok(m) => {
                 debug!("received wrong message - got={:?}", m);
                 Err($crate::send_recv::_err(None))
             },
+            Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                warn!("rpc timed out waiting for {}", stringify!($rmsg));
+                Err($crate::send_recv::_err(None))
+            },
             Err(e) => {
                 debug!("received error - got={:?}", e);
                 Err($crate::send_recv::_err(None))
@@ -3,21 +3,28 @@
 // This program is made available under an ISC-style license.  See the
 // accompanying file LICENSE for details
 
+use crate::context::{promote_and_register_thread, unregister_thread, StreamRecovery};
 use crate::ClientContext;
 use crate::{assert_not_in_callback, run_in_callback};
+use audioipc::codec::{LengthDelimitedCodec, MAX_CALLBACK_MESSAGE_LEN};
 use audioipc::frame::{framed, Framed};
 use audioipc::messages::{self, CallbackReq, CallbackResp, ClientMessage, ServerMessage};
-use audioipc::rpc;
-use audioipc::shm::SharedMem;
-use audioipc::{codec::LengthDelimitedCodec, messages::StreamCreateParams};
-use cubeb_backend::{ffi, DeviceRef, Error, Result, Stream, StreamOps};
+use audioipc::messages::{AtomicCallbackStats, CallbackStats, StreamCreateParams};
+use audioipc::region::{self, Region};
+use audioipc::shm::{Access, SharedMem};
+use audioipc::{ringbuf, rpc};
+use cubeb_backend::{ffi, DeviceRef, Error, Result, Stream, StreamOps, StreamRef};
 use futures::Future;
 use futures_cpupool::{CpuFuture, CpuPool};
 use std::ffi::{CStr, CString};
-use std::os::raw::c_void;
-use std::ptr;
+#[cfg(target_os = "linux")]
+use std::fmt;
+use std::os::raw::{c_long, c_void};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use std::{cmp, ptr, thread};
 use tokio::reactor;
 
 pub struct Device(ffi::cubeb_device);
@@ -44,30 +51,434 @@ pub struct ClientStream<'ctx> {
     // stream methods via stream->context->ops
     context: &'ctx ClientContext,
     user_ptr: *mut c_void,
-    token: usize,
+    shared: Arc<StreamShared>,
+}
+
+// The parts of a stream that outlive a connection to the server, used to
+// recreate the stream after reconnecting.  Registered with ClientContext.
+#[derive(Debug)]
+pub(crate) struct StreamShared {
+    init_params: messages::StreamInitParams,
+    data_cb: ffi::cubeb_data_callback,
+    state_cb: ffi::cubeb_state_callback,
+    user_ptr: usize,
     device_change_cb: Arc<Mutex<ffi::cubeb_device_changed_callback>>,
+    state: Mutex<StreamState>,
+    // Notified when `StreamState::recreating` is cleared.
+    recreated: Condvar,
+    // Data callbacks run by this client, across reconnections.
+    stats: Arc<AtomicCallbackStats>,
+}
+
+/// Data callback statistics for a stream, as returned by
+/// `ClientStream::stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StreamStats {
+    /// Recorded by the server around its calls to the client.
+    pub server: CallbackStats,
+    /// Recorded by the client around calls to the data callback.  Unlike
+    /// the server's, these survive the stream being recreated after a
+    /// reconnection.
+    pub client: CallbackStats,
+}
+
+#[derive(Debug, Default)]
+struct StreamState {
+    // `None` once the stream is destroyed or couldn't be recovered, and
+    // while it's being recreated.
+    connection: Option<StreamConnection>,
+    // Set while the stream is recreated on a new connection, which is done
+    // without holding the lock.
+    recreating: bool,
+    settings: StreamSettings,
+}
+
+// Settings applied to the stream, replayed when it's recreated.
+#[derive(Clone, Debug, Default)]
+struct StreamSettings {
+    started: bool,
+    volume: Option<f32>,
+    name: Option<CString>,
+    device_change_registered: bool,
+}
+
+// The stream as created on the current server connection.
+#[derive(Debug)]
+struct StreamConnection {
+    token: usize,
+    // Renders output ahead when the ring buffer transport is in use.
+    output_ring: Option<OutputRingFiller>,
+    // Answers data callbacks when they're signalled through shared memory.
+    #[cfg(target_os = "linux")]
+    data_signal: Option<DataSignalThread>,
     // Signals ClientStream that CallbackServer has dropped.
     shutdown_rx: mpsc::Receiver<()>,
+    // Set once the connection is given up, so CallbackServer doesn't take
+    // the callback connection closing for the server going away.
+    closed: Arc<AtomicBool>,
+}
+
+impl Drop for StreamConnection {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+}
+
+// A stream created on the server, destroyed again unless kept, so a stream
+// that fails to connect doesn't hold on to the client's share of the
+// server's resources.
+struct CreatedStream<'a> {
+    rpc: &'a rpc::ClientProxy<ServerMessage, ClientMessage>,
+    token: Option<usize>,
+}
+
+impl<'a> CreatedStream<'a> {
+    fn keep(mut self) {
+        self.token = None;
+    }
+}
+
+impl<'a> Drop for CreatedStream<'a> {
+    fn drop(&mut self) {
+        if let Some(token) = self.token {
+            debug!("Destroying stream {} that failed to connect", token);
+            let rpc = self.rpc;
+            let _ = send_recv!(rpc, StreamDestroy(token) => StreamDestroyed);
+        }
+    }
+}
+
+// Runs the data callback on a dedicated thread to keep the output ring
+// buffer filled while the stream is started.
+#[derive(Debug)]
+struct OutputRingFiller {
+    running: Arc<AtomicBool>,
+    // Held by the thread while it renders, so `set_running` can wait for a
+    // chunk in progress.
+    rendering: Arc<Mutex<()>>,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+struct OutputRingParams {
+    frame_size: usize,
+    chunk_frames: usize,
+    rate: u32,
+}
+
+impl OutputRingFiller {
+    fn spawn(
+        ctx: &ClientContext,
+        shm: SharedMem,
+        mut producer: ringbuf::Producer,
+        params: OutputRingParams,
+        data_cb: ffi::cubeb_data_callback,
+        user_ptr: usize,
+        stats: Arc<AtomicCallbackStats>,
+    ) -> Result<OutputRingFiller> {
+        let running = Arc::new(AtomicBool::new(false));
+        let rendering = Arc::new(Mutex::new(()));
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let cb = data_cb.ok_or_else(Error::error)?;
+        let server = ctx.server_connection();
+        let (thread_create_callback, thread_destroy_callback) = ctx.thread_callbacks();
+
+        let thread_running = running.clone();
+        let thread_rendering = rendering.clone();
+        let thread_shutdown = shutdown.clone();
+        let thread = thread::Builder::new()
+            .name("AudioIPC Output Ring".into())
+            .spawn(move || {
+                promote_and_register_thread(&server, thread_create_callback);
+
+                // The producer writes into this mapping, keep it alive.
+                let _shm = shm;
+                let chunk_bytes = params.chunk_frames * params.frame_size;
+                let mut buffer = vec![0u8; chunk_bytes];
+                let period =
+                    Duration::from_secs(1) * params.chunk_frames as u32 / cmp::max(1, params.rate);
+
+                while !thread_shutdown.load(Ordering::SeqCst) {
+                    let rendering = thread_rendering.lock().unwrap();
+                    if !thread_running.load(Ordering::SeqCst) || producer.free_space() < chunk_bytes
+                    {
+                        drop(rendering);
+                        thread::park_timeout(period / 2);
+                        continue;
+                    }
+
+                    let start = Instant::now();
+                    let nframes = run_in_callback(|| unsafe {
+                        cb(
+                            ptr::null_mut(),
+                            user_ptr as *mut c_void,
+                            ptr::null(),
+                            buffer.as_mut_ptr() as *mut _,
+                            params.chunk_frames as c_long,
+                        )
+                    });
+                    record_callback(
+                        &stats,
+                        start,
+                        nframes as isize,
+                        params.chunk_frames as isize,
+                    );
+                    if nframes < 0 {
+                        warn!("Data callback returned error {}", nframes);
+                        producer.set_drained();
+                        break;
+                    }
+
+                    let nframes = cmp::min(nframes as usize, params.chunk_frames);
+                    producer.push(&buffer[..nframes * params.frame_size]);
+                    if nframes < params.chunk_frames {
+                        // Short write, the stream is draining.
+                        producer.set_drained();
+                        break;
+                    }
+                }
+
+                unregister_thread(thread_destroy_callback);
+            })
+            .map_err(|_| Error::error())?;
+
+        Ok(OutputRingFiller {
+            running,
+            rendering,
+            shutdown,
+            thread: Some(thread),
+        })
+    }
+
+    // Once this returns false, nothing more is written to the ring until
+    // it's set running again.
+    fn set_running(&self, running: bool) {
+        self.running.store(running, Ordering::SeqCst);
+        if !running {
+            drop(self.rendering.lock().unwrap());
+        }
+        if let Some(ref thread) = self.thread {
+            thread.thread().unpark();
+        }
+    }
+}
+
+impl Drop for OutputRingFiller {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+// Runs the data callback on a dedicated thread when the server signals
+// requests through the stream's region rather than over the callback
+// connection.
+#[cfg(target_os = "linux")]
+struct DataSignalThread {
+    region: Region,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+    // Outlives the thread, which uses the region.
+    _shm: SharedMem,
+}
+
+#[cfg(target_os = "linux")]
+impl DataSignalThread {
+    fn spawn(
+        ctx: &ClientContext,
+        shm: SharedMem,
+        region: Region,
+        data_cb: ffi::cubeb_data_callback,
+        user_ptr: usize,
+        stats: Arc<AtomicCallbackStats>,
+    ) -> Result<DataSignalThread> {
+        data_cb.ok_or_else(Error::error)?;
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let server = ctx.server_connection();
+        let (thread_create_callback, thread_destroy_callback) = ctx.thread_callbacks();
+
+        let thread_shutdown = shutdown.clone();
+        let thread = thread::Builder::new()
+            .name("AudioIPC Data Signal".into())
+            .spawn(move || {
+                promote_and_register_thread(&server, thread_create_callback);
+
+                let layout = *region.layout();
+                let mut last = region.request_sequence();
+                while !thread_shutdown.load(Ordering::SeqCst) {
+                    let (seq, nframes) = match region.wait_request(last, Duration::from_millis(100))
+                    {
+                        Some(request) => request,
+                        None => continue,
+                    };
+                    last = seq;
+                    trace!("Data Signal: seq={} nframes={}", seq, nframes);
+                    let frames = run_data_callback(
+                        data_cb,
+                        user_ptr,
+                        Some(region),
+                        nframes as isize,
+                        layout.input_frame_size as usize,
+                        layout.output_frame_size as usize,
+                        &stats,
+                    );
+                    region.respond(seq, frames as i32);
+                }
+
+                unregister_thread(thread_destroy_callback);
+            })
+            .map_err(|_| Error::error())?;
+
+        Ok(DataSignalThread {
+            region,
+            shutdown,
+            thread: Some(thread),
+            _shm: shm,
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for DataSignalThread {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            self.region.interrupt_wait_request();
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl fmt::Debug for DataSignalThread {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DataSignalThread")
+            .field("layout", self.region.layout())
+            .finish()
+    }
 }
 
 struct CallbackServer {
-    input_shm: Option<SharedMem>,
-    output_shm: Option<SharedMem>,
+    // The stream's region, unless owned by OutputRingFiller or
+    // DataSignalThread.
+    region: Option<Region>,
+    _shm: Option<SharedMem>,
     data_cb: ffi::cubeb_data_callback,
     state_cb: ffi::cubeb_state_callback,
     user_ptr: usize,
+    // Runs data callbacks, either shared with the context's other streams
+    // or this stream's own.
+    data_pool: CpuPool,
+    // Runs state and device change callbacks.
     cpu_pool: CpuPool,
     device_change_cb: Arc<Mutex<ffi::cubeb_device_changed_callback>>,
+    stats: Arc<AtomicCallbackStats>,
+    // See `StreamConnection::closed`.
+    closed: Arc<AtomicBool>,
+    // Started if the callback connection closes while the stream is open.
+    recovery: Option<StreamRecovery>,
     // Signals ClientStream that CallbackServer has dropped.
     _shutdown_tx: mpsc::Sender<()>,
 }
 
+impl Drop for CallbackServer {
+    fn drop(&mut self) {
+        // The server closed the connection without being asked to, so has
+        // most likely gone away.  Recover the stream rather than wait for
+        // the next call on it to find out.
+        if !self.closed.load(Ordering::SeqCst) {
+            if let Some(recovery) = self.recovery.take() {
+                recovery.start();
+            }
+        }
+    }
+}
+
+// Record a data callback that started at `start` and returned `frames` of
+// the `nframes` requested.
+fn record_callback(stats: &AtomicCallbackStats, start: Instant, frames: isize, nframes: isize) {
+    stats.record_callback();
+    stats.record_latency(start.elapsed());
+    if frames < 0 {
+        stats.record_error();
+    } else if frames < nframes {
+        stats.record_short_write();
+    }
+}
+
+// Run the data callback for `nframes` frames on the region's input and
+// output sections, and record it in `stats`.
+fn run_data_callback(
+    data_cb: ffi::cubeb_data_callback,
+    user_ptr: usize,
+    region: Option<Region>,
+    nframes: isize,
+    input_frame_size: usize,
+    output_frame_size: usize,
+    stats: &AtomicCallbackStats,
+) -> isize {
+    let start = Instant::now();
+    let input_shm = region.and_then(|region| region.input());
+    let output_shm = region.and_then(|region| region.output());
+    // The server is trusted to size requests to fit the shm areas, but fail
+    // the callback rather than panic if not.
+    let area_size = |frame_size: usize| {
+        (nframes as usize)
+            .checked_mul(frame_size)
+            .unwrap_or(usize::max_value())
+    };
+    let input_ptr = match input_shm {
+        Some(shm) => match unsafe { shm.get_slice(area_size(input_frame_size)) } {
+            Ok(slice) => slice.as_ptr(),
+            Err(e) => {
+                debug!("Data callback input: {}", e);
+                record_callback(stats, start, ffi::CUBEB_ERROR as isize, nframes);
+                return ffi::CUBEB_ERROR as isize;
+            }
+        },
+        None => ptr::null(),
+    };
+    let output_ptr = match output_shm {
+        Some(mut shm) => match unsafe { shm.get_mut_slice(area_size(output_frame_size)) } {
+            Ok(slice) => slice.as_mut_ptr(),
+            Err(e) => {
+                debug!("Data callback output: {}", e);
+                record_callback(stats, start, ffi::CUBEB_ERROR as isize, nframes);
+                return ffi::CUBEB_ERROR as isize;
+            }
+        },
+        None => ptr::null(),
+    };
+
+    let cb = data_cb.unwrap();
+    let frames = run_in_callback(|| unsafe {
+        cb(
+            ptr::null_mut(),
+            user_ptr as *mut c_void,
+            input_ptr as *const _,
+            output_ptr as *mut _,
+            nframes as _,
+        )
+    });
+    record_callback(stats, start, frames as isize, nframes);
+    if let (Some(region), Some(_)) = (region, output_shm) {
+        region.output_written();
+    }
+    frames as isize
+}
+
 impl rpc::Server for CallbackServer {
     type Request = CallbackReq;
     type Response = CallbackResp;
     type Future = CpuFuture<Self::Response, ()>;
-    type Transport =
-        Framed<audioipc::AsyncMessageStream, LengthDelimitedCodec<Self::Response, Self::Request>>;
+    type Transport = Framed<
+        audioipc::AsyncMessageStream,
+        LengthDelimitedCodec<rpc::Envelope<Self::Response>, rpc::Envelope<Self::Request>>,
+    >;
+    const MAX_MESSAGE_LEN: usize = MAX_CALLBACK_MESSAGE_LEN;
 
     fn process(&mut self, req: Self::Request) -> Self::Future {
         match req {
@@ -84,48 +495,22 @@ impl rpc::Server for CallbackServer {
                 );
 
                 // Clone values that need to be moved into the cpu pool thread.
-                let input_shm = match self.input_shm {
-                    Some(ref shm) => unsafe { Some(shm.unsafe_view()) },
-                    None => None,
-                };
-                let output_shm = match self.output_shm {
-                    Some(ref shm) => unsafe { Some(shm.unsafe_view()) },
-                    None => None,
-                };
+                let region = self.region;
                 let user_ptr = self.user_ptr;
-                let cb = self.data_cb.unwrap();
+                let data_cb = self.data_cb;
+                let stats = self.stats.clone();
 
-                self.cpu_pool.spawn_fn(move || {
-                    let input_ptr = match input_shm {
-                        Some(shm) => unsafe {
-                            shm.get_slice(nframes as usize * input_frame_size)
-                                .unwrap()
-                                .as_ptr()
-                        },
-                        None => ptr::null(),
-                    };
-                    let output_ptr = match output_shm {
-                        Some(mut shm) => unsafe {
-                            shm.get_mut_slice(nframes as usize * output_frame_size)
-                                .unwrap()
-                                .as_mut_ptr()
-                        },
-                        None => ptr::null(),
-                    };
-
-                    run_in_callback(|| {
-                        let nframes = unsafe {
-                            cb(
-                                ptr::null_mut(),
-                                user_ptr as *mut c_void,
-                                input_ptr as *const _,
-                                output_ptr as *mut _,
-                                nframes as _,
-                            )
-                        };
-
-                        Ok(CallbackResp::Data(nframes as isize))
-                    })
+                self.data_pool.spawn_fn(move || {
+                    let frames = run_data_callback(
+                        data_cb,
+                        user_ptr,
+                        region,
+                        nframes,
+                        input_frame_size,
+                        output_frame_size,
+                        &stats,
+                    );
+                    Ok(CallbackResp::Data(frames))
                 })
             }
             CallbackReq::State(state) => {
@@ -162,75 +547,146 @@ impl rpc::Server for CallbackServer {
     }
 }
 
-impl<'ctx> ClientStream<'ctx> {
-    fn init(
-        ctx: &'ctx ClientContext,
-        init_params: messages::StreamInitParams,
-        data_callback: ffi::cubeb_data_callback,
-        state_callback: ffi::cubeb_state_callback,
-        user_ptr: *mut c_void,
-    ) -> Result<Stream> {
-        assert_not_in_callback();
-
-        let rpc = ctx.rpc();
+impl StreamShared {
+    // Create the stream on the server behind `rpc`, the connection of
+    // `generation`.
+    fn connect(
+        self: &Arc<Self>,
+        ctx: &ClientContext,
+        rpc: &rpc::ClientProxy<ServerMessage, ClientMessage>,
+        generation: usize,
+    ) -> Result<StreamConnection> {
+        let output_ring_buffer_frames = match (
+            self.init_params.input_stream_params,
+            self.init_params.output_stream_params,
+        ) {
+            (None, Some(_)) => ctx.output_ring_buffer_frames(),
+            _ => None,
+        };
+        let shm_data_signal = output_ring_buffer_frames.is_none() && ctx.shm_data_signal();
         let create_params = StreamCreateParams {
-            input_stream_params: init_params.input_stream_params,
-            output_stream_params: init_params.output_stream_params,
+            input_stream_params: self.init_params.input_stream_params,
+            output_stream_params: self.init_params.output_stream_params,
+            output_ring_buffer_frames,
+            latency_frames: self.init_params.latency_frames,
+            shm_data_signal,
         };
         let data = send_recv!(rpc, StreamCreate(create_params) => StreamCreated())?;
+        // Declared before everything using the stream, so dropped after.
+        let created = CreatedStream {
+            rpc,
+            token: Some(data.token),
+        };
 
         debug!(
             "token = {}, handles = {:?}",
             data.token, data.platform_handles
         );
 
-        let has_input = init_params.input_stream_params.is_some();
-        let has_output = init_params.output_stream_params.is_some();
+        let has_input = self.init_params.input_stream_params.is_some();
+        let has_output = self.init_params.output_stream_params.is_some();
 
         let stream =
             unsafe { audioipc::MessageStream::from_raw_fd(data.platform_handles[0].into_raw()) };
 
-        let input_shm = if has_input {
-            match unsafe { SharedMem::from(&data.platform_handles[1], audioipc::SHM_AREA_SIZE) } {
-                Ok(shm) => Some(shm),
-                Err(e) => {
-                    debug!("Client failed to set up input shmem: {}", e);
-                    return Err(Error::error());
-                }
+        if data.shm_size > region::MAX_REGION_SIZE {
+            debug!("Client received invalid shmem size {}", data.shm_size);
+            return Err(Error::error());
+        }
+        let mut shm = match unsafe {
+            SharedMem::from(&data.platform_handles[1], data.shm_size, Access::ReadWrite)
+        } {
+            Ok(shm) => shm,
+            Err(e) => {
+                debug!("Client failed to set up shmem: {}", e);
+                return Err(Error::error());
+            }
+        };
+        let region = match unsafe { Region::attach(&mut shm) } {
+            Ok(region) => region,
+            Err(e) => {
+                debug!("Client failed to attach shmem region: {}", e);
+                return Err(Error::error());
             }
-        } else {
-            None
         };
 
-        let output_shm = if has_output {
-            match unsafe { SharedMem::from(&data.platform_handles[2], audioipc::SHM_AREA_SIZE) } {
-                Ok(shm) => Some(shm),
-                Err(e) => {
-                    debug!("Client failed to set up output shmem: {}", e);
-                    return Err(Error::error());
-                }
+        // The sections must be those of the stream requested.
+        let frame_size =
+            |params: Option<&messages::StreamParams>| params.map_or(Some(0), |p| p.frame_size());
+        let layout = *region.layout();
+        let layout_valid = (layout.input_size > 0) == has_input
+            && (layout.output_size > 0) == has_output
+            && frame_size(self.init_params.input_stream_params.as_ref())
+                == Some(layout.input_frame_size as usize)
+            && frame_size(self.init_params.output_stream_params.as_ref())
+                == Some(layout.output_frame_size as usize)
+            && layout.output_ring == output_ring_buffer_frames.is_some()
+            && layout.data_signal == shm_data_signal;
+        if !layout_valid {
+            debug!("Client received unexpected shmem layout: {:?}", layout);
+            return Err(Error::error());
+        }
+
+        // In ring buffer mode the region is owned by OutputRingFiller
+        // rather than CallbackServer.
+        let (region, shm, output_ring) = match output_ring_buffer_frames {
+            Some(ring_frames) => {
+                let producer = unsafe {
+                    let mut section = region.output().unwrap();
+                    section.get_mut_slice(layout.output_size).and_then(|area| {
+                        ringbuf::Producer::attach(area.as_mut_ptr(), layout.output_size)
+                    })
+                };
+                let producer = match producer {
+                    Ok(producer) => producer,
+                    Err(e) => {
+                        debug!("Client failed to attach output ring buffer: {}", e);
+                        return Err(Error::error());
+                    }
+                };
+                let params = self.init_params.output_stream_params.unwrap();
+                let ring_params = OutputRingParams {
+                    frame_size: params.frame_size().ok_or_else(Error::invalid_format)?,
+                    chunk_frames: cmp::max(
+                        1,
+                        cmp::min(self.init_params.latency_frames, ring_frames / 2),
+                    ) as usize,
+                    rate: params.rate,
+                };
+                (None, None, Some((shm, producer, ring_params)))
             }
-        } else {
-            None
+            None => (Some(region), Some(shm), None),
         };
 
-        let user_data = user_ptr as usize;
+        // With data signalling the region is owned by DataSignalThread, and
+        // CallbackServer is left with the state and device change callbacks.
+        #[cfg(target_os = "linux")]
+        let (region, shm, data_signal) = match (region, shm) {
+            (Some(region), Some(shm)) if shm_data_signal => (None, None, Some((region, shm))),
+            (region, shm) => (region, shm, None),
+        };
 
         let cpu_pool = ctx.cpu_pool();
-
-        let null_cb: ffi::cubeb_device_changed_callback = None;
-        let device_change_cb = Arc::new(Mutex::new(null_cb));
+        // Only needed if CallbackServer runs the data callbacks.
+        let data_pool = region
+            .and_then(|_| ctx.stream_callback_pool())
+            .unwrap_or_else(|| cpu_pool.clone());
 
         let (_shutdown_tx, shutdown_rx) = mpsc::channel();
+        let closed = Arc::new(AtomicBool::new(false));
 
         let server = CallbackServer {
-            input_shm,
-            output_shm,
-            data_cb: data_callback,
-            state_cb: state_callback,
-            user_ptr: user_data,
+            region,
+            _shm: shm,
+            data_cb: self.data_cb,
+            state_cb: self.state_cb,
+            user_ptr: self.user_ptr,
+            data_pool,
             cpu_pool,
-            device_change_cb: device_change_cb.clone(),
+            device_change_cb: self.device_change_cb.clone(),
+            stats: self.stats.clone(),
+            closed: closed.clone(),
+            recovery: Some(ctx.stream_recovery(self, generation)),
             _shutdown_tx,
         };
 
@@ -239,7 +695,7 @@ impl<'ctx> ClientStream<'ctx> {
             .spawn(futures::future::lazy(move || {
                 let handle = reactor::Handle::default();
                 let stream = stream.into_tokio_ipc(&handle).unwrap();
-                let transport = framed(stream, Default::default());
+                let transport = framed(stream, rpc::server_codec::<CallbackServer>());
                 rpc::bind_server(transport, server);
                 wait_tx.send(()).unwrap();
                 Ok(())
@@ -247,31 +703,291 @@ impl<'ctx> ClientStream<'ctx> {
             .expect("Failed to spawn CallbackServer");
         wait_rx.recv().unwrap();
 
-        send_recv!(rpc, StreamInit(data.token, init_params) => StreamInitialized)?;
+        // Dropped on failure from here, which closes it.
+        let mut connection = StreamConnection {
+            token: data.token,
+            output_ring: None,
+            #[cfg(target_os = "linux")]
+            data_signal: None,
+            shutdown_rx,
+            closed,
+        };
+
+        connection.output_ring = match output_ring {
+            Some((shm, producer, ring_params)) => Some(OutputRingFiller::spawn(
+                ctx,
+                shm,
+                producer,
+                ring_params,
+                self.data_cb,
+                self.user_ptr,
+                self.stats.clone(),
+            )?),
+            None => None,
+        };
+
+        #[cfg(target_os = "linux")]
+        {
+            connection.data_signal = match data_signal {
+                Some((region, shm)) => Some(DataSignalThread::spawn(
+                    ctx,
+                    shm,
+                    region,
+                    self.data_cb,
+                    self.user_ptr,
+                    self.stats.clone(),
+                )?),
+                None => None,
+            };
+        }
+
+        send_recv!(rpc, StreamInit(data.token, self.init_params.clone()) => StreamInitialized)?;
+
+        created.keep();
+        Ok(connection)
+    }
+
+    // The state, once any recreation in progress is done.
+    fn lock_state(&self) -> MutexGuard<StreamState> {
+        let mut state = self.state.lock().unwrap();
+        while state.recreating {
+            state = self.recreated.wait(state).unwrap();
+        }
+        state
+    }
+
+    fn token(&self) -> Result<usize> {
+        let state = self.lock_state();
+        match state.connection {
+            Some(ref connection) => Ok(connection.token),
+            None => Err(Error::error()),
+        }
+    }
+
+    fn set_started(&self, started: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.settings.started = started;
+        if let Some(ring) = state
+            .connection
+            .as_ref()
+            .and_then(|c| c.output_ring.as_ref())
+        {
+            ring.set_running(started);
+        }
+    }
+
+    // Recreate the stream on a new server connection and restore its
+    // settings.  Called by ClientContext after reconnecting.
+    pub(crate) fn recreate(self: &Arc<Self>, ctx: &ClientContext) {
+        // The old connection went away with the server.
+        let old = {
+            let mut state = self.lock_state();
+            let old = state.connection.take();
+            state.recreating = old.is_some();
+            old
+        };
+        if old.is_none() {
+            return;
+        }
+        drop(old);
+
+        // Calls on the stream wait for `recreating` to clear rather than
+        // for these RPCs under the lock.
+        let (rpc, generation) = ctx.rpc_and_generation();
+        let recreated = self.connect(ctx, &rpc, generation).map(|connection| {
+            let settings = self.state.lock().unwrap().settings.clone();
+            let restored = settings.restore(&rpc, &connection);
+            (connection, restored)
+        });
+
+        let mut state = self.state.lock().unwrap();
+        state.recreating = false;
+        let restored = match recreated {
+            Ok((connection, restored)) => {
+                // Settings may have changed while unlocked.
+                if let Some(ref ring) = connection.output_ring {
+                    ring.set_running(state.settings.started);
+                }
+                state.connection = Some(connection);
+                Some(restored)
+            }
+            Err(e) => {
+                warn!("Failed to recreate stream: {:?}", e);
+                None
+            }
+        };
+        drop(state);
+        self.recreated.notify_all();
+
+        match restored {
+            Some(Ok(())) => {}
+            Some(Err(e)) => {
+                warn!("Failed to restore stream state: {:?}", e);
+                self.fail(ctx);
+            }
+            None => self.signal_error(ctx),
+        }
+    }
+
+    // Give up on the stream, which is left in the error state.  Called by
+    // ClientContext when the server can't be reconnected or has dropped the
+    // stream.
+    pub(crate) fn fail(&self, ctx: &ClientContext) {
+        let connection = self.lock_state().connection.take();
+        if connection.is_some() {
+            drop(connection);
+            self.signal_error(ctx);
+        }
+    }
+
+    fn signal_error(&self, ctx: &ClientContext) {
+        let cb = match self.state_cb {
+            Some(cb) => cb,
+            None => return,
+        };
+        let user_ptr = self.user_ptr;
+        ctx.cpu_pool()
+            .spawn_fn(move || {
+                run_in_callback(|| unsafe {
+                    cb(ptr::null_mut(), user_ptr as *mut _, ffi::CUBEB_STATE_ERROR);
+                });
+                Ok::<(), ()>(())
+            })
+            .forget();
+    }
+}
+
+impl StreamSettings {
+    // Replay the settings made on the stream onto a newly created connection.
+    fn restore(
+        &self,
+        rpc: &rpc::ClientProxy<ServerMessage, ClientMessage>,
+        connection: &StreamConnection,
+    ) -> Result<()> {
+        let token = connection.token;
+        if let Some(volume) = self.volume {
+            send_recv!(rpc, StreamSetVolume(token, volume) => StreamVolumeSet)?;
+        }
+        if let Some(ref name) = self.name {
+            send_recv!(rpc, StreamSetName(token, name.clone()) => StreamNameSet)?;
+        }
+        if self.device_change_registered {
+            send_recv!(rpc, StreamRegisterDeviceChangeCallback(token, true) => StreamRegisterDeviceChangeCallback)?;
+        }
+        if self.started {
+            if let Some(ref ring) = connection.output_ring {
+                ring.set_running(true);
+            }
+            send_recv!(rpc, StreamStart(token) => StreamStarted)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'ctx> ClientStream<'ctx> {
+    fn init(
+        ctx: &'ctx ClientContext,
+        init_params: messages::StreamInitParams,
+        data_callback: ffi::cubeb_data_callback,
+        state_callback: ffi::cubeb_state_callback,
+        user_ptr: *mut c_void,
+    ) -> Result<Stream> {
+        assert_not_in_callback();
+
+        let null_cb: ffi::cubeb_device_changed_callback = None;
+        let shared = Arc::new(StreamShared {
+            init_params,
+            data_cb: data_callback,
+            state_cb: state_callback,
+            user_ptr: user_ptr as usize,
+            device_change_cb: Arc::new(Mutex::new(null_cb)),
+            state: Mutex::new(StreamState::default()),
+            recreated: Condvar::new(),
+            stats: Arc::new(AtomicCallbackStats::default()),
+        });
+
+        let (rpc, generation) = ctx.rpc_and_generation();
+        let connection = match shared.connect(ctx, &rpc, generation) {
+            Err(_) if rpc.is_closed() => {
+                ctx.reconnect(generation)?;
+                let (rpc, generation) = ctx.rpc_and_generation();
+                shared.connect(ctx, &rpc, generation)?
+            }
+            r => r?,
+        };
+        shared.state.lock().unwrap().connection = Some(connection);
+        ctx.register_stream(&shared);
 
         let stream = Box::into_raw(Box::new(ClientStream {
             context: ctx,
             user_ptr,
-            token: data.token,
-            device_change_cb,
-            shutdown_rx,
+            shared,
         }));
         Ok(unsafe { Stream::from_ptr(stream as *mut _) })
     }
+
+    /// The stream behind a `cubeb` stream created through a `ClientContext`.
+    ///
+    /// # Safety
+    ///
+    /// `stream` must have been created by a `ClientContext`.
+    pub unsafe fn from_stream(stream: &StreamRef) -> &ClientStream {
+        &*(stream.as_ptr() as *const ClientStream)
+    }
+
+    /// Data callback statistics recorded by the server and by this client.
+    pub fn stats(&self) -> Result<StreamStats> {
+        assert_not_in_callback();
+        let server =
+            self.call_server(|rpc, token| send_recv!(rpc, StreamGetStats(token) => StreamStats()))?;
+        Ok(StreamStats {
+            server,
+            client: self.shared.stats.snapshot(),
+        })
+    }
+
+    // Run `f` with the stream's current token.  If it fails because the
+    // server has gone away, reconnect, which recreates the stream, and
+    // retry once.
+    fn call_server<T, F>(&self, f: F) -> Result<T>
+    where
+        F: Fn(&rpc::ClientProxy<ServerMessage, ClientMessage>, usize) -> Result<T>,
+    {
+        let (rpc, generation) = self.context.rpc_and_generation();
+        match f(&rpc, self.shared.token()?) {
+            Err(_) if rpc.is_closed() => {
+                self.context.reconnect(generation)?;
+                f(&self.context.rpc(), self.shared.token()?)
+            }
+            r => r,
+        }
+    }
 }
 
 impl<'ctx> Drop for ClientStream<'ctx> {
     fn drop(&mut self) {
         debug!("ClientStream drop");
-        let rpc = self.context.rpc();
-        let _ = send_recv!(rpc, StreamDestroy(self.token) => StreamDestroyed);
-        debug!("ClientStream drop - stream destroyed");
-        // Wait for CallbackServer to shutdown.  The remote server drops the RPC
-        // connection during StreamDestroy, which will cause CallbackServer to drop
-        // once the connection close is detected.  Dropping CallbackServer will
-        // cause the shutdown channel to error on recv, which we rely on to
-        // synchronize with CallbackServer dropping.
-        let _ = self.shutdown_rx.recv();
+        let connection = self.shared.lock_state().connection.take();
+        if let Some(mut connection) = connection {
+            // The server closes the callback connection on destroying the
+            // stream.
+            connection.closed.store(true, Ordering::SeqCst);
+            // Stop rendering ahead before the stream and its callbacks go away.
+            drop(connection.output_ring.take());
+            let rpc = self.context.rpc();
+            let _ = send_recv!(rpc, StreamDestroy(connection.token) => StreamDestroyed);
+            debug!("ClientStream drop - stream destroyed");
+            // The server makes no more data requests once the stream is
+            // destroyed.
+            #[cfg(target_os = "linux")]
+            drop(connection.data_signal.take());
+            // Wait for CallbackServer to shutdown.  The remote server drops the RPC
+            // connection during StreamDestroy, which will cause CallbackServer to drop
+            // once the connection close is detected.  Dropping CallbackServer will
+            // cause the shutdown channel to error on recv, which we rely on to
+            // synchronize with CallbackServer dropping.
+            let _ = connection.shutdown_rx.recv();
+        }
         debug!("ClientStream dropped");
     }
 }
@@ -279,50 +995,56 @@ impl<'ctx> Drop for ClientStream<'ctx> {
 impl<'ctx> StreamOps for ClientStream<'ctx> {
     fn start(&mut self) -> Result<()> {
         assert_not_in_callback();
-        let rpc = self.context.rpc();
-        send_recv!(rpc, StreamStart(self.token) => StreamStarted)
+        self.shared.set_started(true);
+        self.call_server(|rpc, token| send_recv!(rpc, StreamStart(token) => StreamStarted))
     }
 
     fn stop(&mut self) -> Result<()> {
         assert_not_in_callback();
-        let rpc = self.context.rpc();
-        send_recv!(rpc, StreamStop(self.token) => StreamStopped)
+        // Stop rendering ahead first, so the server discards everything
+        // in the ring buffer when it stops the stream.
+        self.shared.set_started(false);
+        self.call_server(|rpc, token| send_recv!(rpc, StreamStop(token) => StreamStopped))
     }
 
     fn position(&mut self) -> Result<u64> {
         assert_not_in_callback();
-        let rpc = self.context.rpc();
-        send_recv!(rpc, StreamGetPosition(self.token) => StreamPosition())
+        self.call_server(|rpc, token| send_recv!(rpc, StreamGetPosition(token) => StreamPosition()))
     }
 
     fn latency(&mut self) -> Result<u32> {
         assert_not_in_callback();
-        let rpc = self.context.rpc();
-        send_recv!(rpc, StreamGetLatency(self.token) => StreamLatency())
+        self.call_server(|rpc, token| send_recv!(rpc, StreamGetLatency(token) => StreamLatency()))
     }
 
     fn input_latency(&mut self) -> Result<u32> {
         assert_not_in_callback();
-        let rpc = self.context.rpc();
-        send_recv!(rpc, StreamGetInputLatency(self.token) => StreamInputLatency())
+        self.call_server(
+            |rpc, token| send_recv!(rpc, StreamGetInputLatency(token) => StreamInputLatency()),
+        )
     }
 
     fn set_volume(&mut self, volume: f32) -> Result<()> {
         assert_not_in_callback();
-        let rpc = self.context.rpc();
-        send_recv!(rpc, StreamSetVolume(self.token, volume) => StreamVolumeSet)
+        self.shared.state.lock().unwrap().settings.volume = Some(volume);
+        self.call_server(
+            |rpc, token| send_recv!(rpc, StreamSetVolume(token, volume) => StreamVolumeSet),
+        )
     }
 
     fn set_name(&mut self, name: &CStr) -> Result<()> {
         assert_not_in_callback();
-        let rpc = self.context.rpc();
-        send_recv!(rpc, StreamSetName(self.token, name.to_owned()) => StreamNameSet)
+        self.shared.state.lock().unwrap().settings.name = Some(name.to_owned());
+        self.call_server(
+            |rpc, token| send_recv!(rpc, StreamSetName(token, name.to_owned()) => StreamNameSet),
+        )
     }
 
     fn current_device(&mut self) -> Result<&DeviceRef> {
         assert_not_in_callback();
-        let rpc = self.context.rpc();
-        match send_recv!(rpc, StreamGetCurrentDevice(self.token) => StreamCurrentDevice()) {
+        match self.call_server(
+            |rpc, token| send_recv!(rpc, StreamGetCurrentDevice(token) => StreamCurrentDevice()),
+        ) {
             Ok(d) => Ok(unsafe { DeviceRef::from_ptr(Box::into_raw(Box::new(d.into()))) }),
             Err(e) => Err(e),
         }
@@ -346,10 +1068,17 @@ impl<'ctx> StreamOps for ClientStream<'ctx> {
         device_changed_callback: ffi::cubeb_device_changed_callback,
     ) -> Result<()> {
         assert_not_in_callback();
-        let rpc = self.context.rpc();
         let enable = device_changed_callback.is_some();
-        *self.device_change_cb.lock().unwrap() = device_changed_callback;
-        send_recv!(rpc, StreamRegisterDeviceChangeCallback(self.token, enable) => StreamRegisterDeviceChangeCallback)
+        *self.shared.device_change_cb.lock().unwrap() = device_changed_callback;
+        self.shared
+            .state
+            .lock()
+            .unwrap()
+            .settings
+            .device_change_registered = enable;
+        self.call_server(|rpc, token| {
+            send_recv!(rpc, StreamRegisterDeviceChangeCallback(token, enable) => StreamRegisterDeviceChangeCallback)
+        })
     }
 }
 
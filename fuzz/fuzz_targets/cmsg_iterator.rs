@@ -0,0 +1,16 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details
+
+// Control messages received alongside data on the Unix transport.
+#![no_main]
+
+use bytes::Bytes;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    for fds in audioipc::cmsg::iterator(Bytes::from(data)) {
+        let _ = fds.iter().count();
+    }
+});
@@ -0,0 +1,20 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details
+
+// Data and state callback responses a client sends to the server.
+#![no_main]
+
+use audioipc::codec::{Codec, LengthDelimitedCodec, MAX_CALLBACK_MESSAGE_LEN};
+use audioipc::messages::CallbackResp;
+use audioipc::rpc;
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut codec =
+        LengthDelimitedCodec::<(), rpc::Envelope<CallbackResp>>::new(MAX_CALLBACK_MESSAGE_LEN);
+    let mut buf = BytesMut::from(data);
+    while let Ok(Some(_)) = codec.decode(&mut buf) {}
+});
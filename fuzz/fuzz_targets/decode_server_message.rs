@@ -0,0 +1,19 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details
+
+// Requests a client sends to the server.
+#![no_main]
+
+use audioipc::codec::{Codec, LengthDelimitedCodec};
+use audioipc::messages::ServerMessage;
+use audioipc::rpc;
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut codec = LengthDelimitedCodec::<(), rpc::Envelope<ServerMessage>>::default();
+    let mut buf = BytesMut::from(data);
+    while let Ok(Some(_)) = codec.decode(&mut buf) {}
+});
@@ -0,0 +1,51 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details
+
+// Drives a CubebServer backed by the fake cubeb backend with the sequence
+// of requests decoded from the input, following a valid handshake.
+#![no_main]
+
+use audioipc::codec::MAX_MESSAGE_LEN;
+use audioipc::core;
+use audioipc::messages::{ClientConnectParams, ServerMessage};
+use audioipc::rpc::Server as _;
+use audioipc_server::{fake, CubebServer, ServerConfig};
+use futures::Future;
+use libfuzzer_sys::fuzz_target;
+use std::ffi::CString;
+
+thread_local!(static CALLBACK_THREAD: core::CoreThread = {
+    // CubebServer creates its context on this thread.
+    let backend_name = CString::new(fake::BACKEND_NAME).unwrap();
+    audioipc_server::set_context_params(None, Some(&backend_name));
+    core::spawn_thread("AudioIPC Fuzz Callback RPC", || Ok(()), || {}).unwrap()
+});
+
+fn allowed(msg: &ServerMessage) -> bool {
+    match *msg {
+        // Would promote arbitrary threads to real-time.
+        #[cfg(target_os = "linux")]
+        ServerMessage::PromoteThreadToRealTime(_) => false,
+        _ => true,
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    CALLBACK_THREAD.with(|thread| {
+        let mut server = CubebServer::new(thread.handle(), ServerConfig::default());
+        let connect = ClientConnectParams::new(std::process::id());
+        let _ = server.process(ServerMessage::ClientConnect(connect)).wait();
+
+        let mut data = data;
+        while let Ok(msg) = bincode::config()
+            .limit(MAX_MESSAGE_LEN)
+            .deserialize_from::<_, ServerMessage>(&mut data)
+        {
+            if allowed(&msg) {
+                let _ = server.process(msg).wait();
+            }
+        }
+    });
+});
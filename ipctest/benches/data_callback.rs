@@ -0,0 +1,95 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details.
+
+// Compares data callback round-trip times, from the fake backend calling the
+// server's data callback to the server returning the client's output, when
+// callbacks are requested over RPC and when they're signalled through
+// shared memory.
+//
+// Run with 'cargo bench -p ipctest --bench data_callback'.
+
+#[cfg(target_os = "linux")]
+fn main() {
+    use audioipc_client::{ClientContext, ClientOptions, ClientStream};
+    use audioipc_server::fake;
+    use std::ffi::CString;
+    use std::thread;
+    use std::time::Duration;
+
+    const RATE: u32 = 48000;
+    const LATENCY_FRAMES: u32 = 128;
+    const DURATION: Duration = Duration::from_secs(2);
+
+    const STREAM_NAME: &str = "data callback bench";
+
+    // The `percentile`th of `durations`, sorted, in microseconds.
+    fn percentile(durations: &[Duration], percentile: usize) -> u128 {
+        let i = (durations.len() * percentile + 99) / 100;
+        durations[i.saturating_sub(1)].as_micros()
+    }
+
+    fn run(name: &str, options: ClientOptions) {
+        let backend_name = CString::new(fake::BACKEND_NAME).unwrap();
+        let server = audioipc_server::Server::start(None, Some(&backend_name)).unwrap();
+        let ctx = ClientContext::connect(server.new_client().unwrap(), options).unwrap();
+
+        let params = cubeb::StreamParamsBuilder::new()
+            .format(cubeb::SampleFormat::S16NE)
+            .rate(RATE)
+            .channels(1)
+            .layout(cubeb::ChannelLayout::MONO)
+            .take();
+        let mut builder = cubeb::StreamBuilder::<cubeb::MonoFrame<i16>>::new();
+        builder
+            .name(STREAM_NAME)
+            .default_output(&params)
+            .latency(LATENCY_FRAMES)
+            .data_callback(|_, output| output.len() as isize)
+            .state_callback(|_| {});
+        let stream = builder.init(&ctx).expect("stream init failed");
+
+        stream.start().unwrap();
+        thread::sleep(DURATION);
+        stream.stop().unwrap();
+
+        let stats = unsafe { ClientStream::from_stream(&stream) }
+            .stats()
+            .unwrap()
+            .server;
+        let mut durations = fake::take_callback_durations(STREAM_NAME);
+        durations.sort();
+        if durations.is_empty() {
+            println!("{:>6}: no callbacks", name);
+        } else {
+            println!(
+                "{:>6}: {} callbacks, {} missed, p50 {} us, p99 {} us, max {} us",
+                name,
+                stats.callbacks,
+                stats.missed_deadlines,
+                percentile(&durations, 50),
+                percentile(&durations, 99),
+                durations[durations.len() - 1].as_micros(),
+            );
+        }
+
+        drop(stream);
+        drop(ctx);
+        drop(server);
+    }
+
+    run("rpc", ClientOptions::default());
+    run(
+        "signal",
+        ClientOptions {
+            shm_data_signal: true,
+            ..ClientOptions::default()
+        },
+    );
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {
+    println!("Data signalling is only supported on Linux.");
+}
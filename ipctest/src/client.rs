@@ -4,10 +4,8 @@
 // accompanying file LICENSE for details.
 
 use audioipc_client;
-use cubeb::{self, ffi, Sample};
+use cubeb::{self, Sample};
 use std::f32::consts::PI;
-use std::ffi::CString;
-use std::ptr;
 use std::thread;
 use std::time::Duration;
 
@@ -141,7 +139,7 @@ fn enumerate_devices(ctx: &cubeb::Context) -> Result<()> {
     Ok(())
 }
 
-pub fn client_test(fd: audioipc::PlatformHandleType) -> Result<()> {
+pub fn client_test(server_stream: audioipc::MessageStream) -> Result<()> {
     macro_rules! query(
         ($e: expr) => (match $e {
             Ok(v) => v,
@@ -149,23 +147,14 @@ pub fn client_test(fd: audioipc::PlatformHandleType) -> Result<()> {
         })
         );
 
-    // Bootstrap connection to server by calling direction into client
-    // init function to get a raw cubeb pointer.
-    let context_name = CString::new("AudioIPC").unwrap();
-    let mut c: *mut ffi::cubeb = ptr::null_mut();
-    let init_params = audioipc_client::AudioIpcInitParams {
-        server_connection: fd,
-        pool_size: 1,
+    let options = audioipc_client::ClientOptions {
         stack_size: 64 * 1024,
-        thread_create_callback: None,
-        thread_destroy_callback: None,
+        ..Default::default()
+    };
+    let ctx = match audioipc_client::ClientContext::connect(server_stream, options) {
+        Ok(ctx) => ctx,
+        Err(_) => return Err("Failed to connect to remote cubeb server.".into()),
     };
-    if unsafe { audioipc_client::audioipc_client_init(&mut c, context_name.as_ptr(), &init_params) }
-        < 0
-    {
-        return Err("Failed to connect to remote cubeb server.".into());
-    }
-    let ctx = unsafe { cubeb::Context::from_ptr(c) };
 
     let format = cubeb::SampleFormat::S16NE;
     let rate = query!(ctx.preferred_sample_rate());
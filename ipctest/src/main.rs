@@ -28,10 +28,11 @@ use crate::errors::*;
 #[cfg(unix)]
 fn run() -> Result<()> {
     use std::ffi::CString;
+    use std::os::unix::io::IntoRawFd;
 
-    let handle =
-        unsafe { audioipc_server::audioipc_server_start(std::ptr::null(), std::ptr::null()) };
-    let fd = audioipc_server::audioipc_server_new_client(handle);
+    let server = audioipc_server::Server::start(None, None)?;
+    let fd = server.new_client()?.into_raw_fd();
+    // The child inherits a duplicate without close-on-exec set.
     let fd = unsafe {
         let new_fd = libc::dup(fd);
         libc::close(fd);
@@ -76,7 +77,7 @@ fn run() -> Result<()> {
         },
     };
 
-    audioipc_server::audioipc_server_stop(handle);
+    drop(server);
 
     Ok(())
 }
@@ -86,14 +87,13 @@ fn run_client() -> Result<()> {
     let args: Vec<String> = std::env::args().collect();
     assert_eq!(args[2], "--fd");
     let target_fd: i32 = args[3].parse().unwrap();
-    client::client_test(target_fd)
+    client::client_test(unsafe { audioipc::MessageStream::from_raw_fd(target_fd) })
 }
 
 #[cfg(windows)]
 fn run() -> Result<()> {
-    let handle =
-        unsafe { audioipc_server::audioipc_server_start(std::ptr::null(), std::ptr::null()) };
-    let fd = audioipc_server::audioipc_server_new_client(handle);
+    let server = audioipc_server::Server::start(None, None)?;
+    let fd = unsafe { audioipc::PlatformHandle::from(server.new_client()?).into_raw() };
 
     let args: Vec<String> = std::env::args().collect();
 
@@ -106,7 +106,7 @@ fn run() -> Result<()> {
 
     child.wait().expect("child process wait failed");
 
-    audioipc_server::audioipc_server_stop(handle);
+    drop(server);
 
     Ok(())
 }
@@ -139,7 +139,7 @@ fn run_client() -> Result<()> {
         }
     }
 
-    client::client_test(target_handle)
+    client::client_test(unsafe { audioipc::MessageStream::from_raw_fd(target_handle) })
 }
 
 fn main() {
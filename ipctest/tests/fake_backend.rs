@@ -0,0 +1,471 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details.
+
+// Drives the client and server end to end against the fake backend, so runs
+// without audio hardware.
+#![cfg(unix)]
+
+use audioipc_client::ClientStream;
+use audioipc_server::fake;
+use cubeb::{self, ffi};
+use std::ffi::CString;
+use std::os::raw::c_void;
+use std::os::unix::io::IntoRawFd;
+use std::ptr;
+use std::sync::atomic::{AtomicI16, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const RATE: u32 = 48000;
+const OUTPUT_VALUE: i16 = 1000;
+
+static DEVICE_COLLECTION_CHANGES: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn device_collection_changed(_: *mut ffi::cubeb, _: *mut c_void) {
+    DEVICE_COLLECTION_CHANGES.fetch_add(1, Ordering::SeqCst);
+}
+
+fn wait_for<F: Fn() -> bool>(f: F) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !f() {
+        if Instant::now() > deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    true
+}
+
+fn params(format: cubeb::SampleFormat) -> cubeb::StreamParams {
+    cubeb::StreamParamsBuilder::new()
+        .format(format)
+        .rate(RATE)
+        .channels(1)
+        .layout(cubeb::ChannelLayout::MONO)
+        .take()
+}
+
+fn start_server() -> audioipc_server::Server {
+    let backend_name = CString::new(fake::BACKEND_NAME).unwrap();
+    audioipc_server::Server::start(None, Some(&backend_name)).unwrap()
+}
+
+fn connect(
+    server: &audioipc_server::Server,
+    options: audioipc_client::ClientOptions,
+) -> cubeb::Context {
+    audioipc_client::ClientContext::connect(server.new_client().unwrap(), options).unwrap()
+}
+
+// Play an output stream named `name`, which must be unique as tests run in
+// parallel against the same fake backend.
+fn test_output(ctx: &cubeb::Context, name: &str) {
+    let (state_tx, state_rx) = mpsc::channel();
+    let (device_tx, device_rx) = mpsc::channel();
+    let mut builder = cubeb::StreamBuilder::<cubeb::MonoFrame<i16>>::new();
+    builder
+        .name(name)
+        .default_output(&params(cubeb::SampleFormat::S16NE))
+        .latency(512)
+        .data_callback(|_, output| {
+            for f in output.iter_mut() {
+                f.m = OUTPUT_VALUE;
+            }
+            output.len() as isize
+        })
+        .state_callback(move |state| drop(state_tx.send(state)))
+        .device_changed_cb(move || drop(device_tx.send(())));
+    let stream = builder.init(ctx).expect("output stream init failed");
+
+    stream.start().unwrap();
+    assert_eq!(
+        state_rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+        cubeb::State::Started
+    );
+    thread::sleep(Duration::from_millis(200));
+    assert!(stream.position().unwrap() > 0);
+
+    fake::inject_device_changed();
+    device_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    stream.stop().unwrap();
+    assert_eq!(
+        state_rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+        cubeb::State::Stopped
+    );
+
+    let stats = unsafe { ClientStream::from_stream(&stream) }
+        .stats()
+        .unwrap();
+    assert!(stats.server.callbacks > 0);
+    assert!(stats.client.callbacks > 0);
+    assert_eq!(stats.client.errors, 0);
+    assert_eq!(stats.client.short_writes, 0);
+    assert_eq!(stats.server.missed_deadlines, 0);
+    assert!(stats.server.latency_histogram.iter().sum::<u64>() <= stats.server.callbacks);
+
+    let captured = fake::take_captured_output(name);
+    assert!(!captured.is_empty());
+    assert!(captured
+        .chunks(2)
+        .all(|s| i16::from_ne_bytes([s[0], s[1]]) == OUTPUT_VALUE));
+}
+
+fn test_input(ctx: &cubeb::Context) {
+    let (tx, rx) = mpsc::channel();
+    let mut builder = cubeb::StreamBuilder::<cubeb::MonoFrame<f32>>::new();
+    builder
+        .name("fake input")
+        .default_input(&params(cubeb::SampleFormat::Float32NE))
+        .latency(512)
+        .data_callback(move |input, _| {
+            let peak = input.iter().fold(0.0f32, |peak, f| peak.max(f.m.abs()));
+            drop(tx.send(peak));
+            input.len() as isize
+        })
+        .state_callback(|_| {});
+    let stream = builder.init(ctx).expect("input stream init failed");
+
+    stream.start().unwrap();
+    let peak = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    stream.stop().unwrap();
+    // The fake backend records a tone at half scale.
+    assert!(peak > 0.4 && peak <= 0.5, "unexpected input peak {}", peak);
+}
+
+fn test_status(server: &audioipc_server::Server, ctx: &cubeb::Context) {
+    let builder = || {
+        let mut builder = cubeb::StreamBuilder::<cubeb::MonoFrame<i16>>::new();
+        builder
+            .name("fake status")
+            .default_output(&params(cubeb::SampleFormat::S16NE))
+            .latency(512)
+            .data_callback(|_, output| output.len() as isize);
+        builder
+    };
+    // Leaves its areas to the next stream.
+    let mut first = builder();
+    first.state_callback(|_| {});
+    drop(first.init(ctx).expect("status stream init failed"));
+
+    let (tx, rx) = mpsc::channel();
+    let mut builder = builder();
+    builder.state_callback(move |state| drop(tx.send(state)));
+    let stream = builder.init(ctx).expect("status stream init failed");
+    stream.start().unwrap();
+    rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    let status = server.status().unwrap();
+    assert_eq!(status.clients.len(), 1);
+    let client = &status.clients[0];
+    assert_eq!(client.pid, Some(std::process::id()));
+    assert_eq!(client.streams.len(), 1);
+    let stream_status = &client.streams[0];
+    assert!(stream_status.initialized);
+    assert_eq!(stream_status.state, Some("started"));
+    assert_eq!(stream_status.output_params.unwrap().rate, RATE);
+    assert!(stream_status.input_params.is_none());
+    assert!(stream_status.output_shm_size > 0);
+    // The first stream's areas were recycled for this one.
+    assert!(client.shm_pool.reused > 0);
+    assert!(status.to_json().contains("\"latency_frames\": 512"));
+
+    stream.stop().unwrap();
+}
+
+fn test_device_collection_changed(ctx: &cubeb::Context) {
+    unsafe {
+        ctx.register_device_collection_changed(
+            cubeb::DeviceType::OUTPUT,
+            Some(device_collection_changed),
+            ptr::null_mut(),
+        )
+        .unwrap();
+    }
+
+    fake::inject_device_collection_changed(cubeb::DeviceType::OUTPUT);
+    assert!(wait_for(|| DEVICE_COLLECTION_CHANGES
+        .load(Ordering::SeqCst)
+        == 1));
+    // Not subscribed to input changes.
+    fake::inject_device_collection_changed(cubeb::DeviceType::INPUT);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(DEVICE_COLLECTION_CHANGES.load(Ordering::SeqCst), 1);
+
+    unsafe {
+        ctx.register_device_collection_changed(cubeb::DeviceType::OUTPUT, None, ptr::null_mut())
+            .unwrap();
+    }
+}
+
+// Play `stream` for a while, then stop it and return the samples the
+// backend played.
+fn play(
+    stream: &cubeb::Stream<cubeb::MonoFrame<i16>>,
+    states: &mpsc::Receiver<cubeb::State>,
+    name: &str,
+) -> Vec<i16> {
+    stream.start().unwrap();
+    assert_eq!(
+        states.recv_timeout(Duration::from_secs(5)).unwrap(),
+        cubeb::State::Started
+    );
+    thread::sleep(Duration::from_millis(300));
+    stream.stop().unwrap();
+    assert_eq!(
+        states.recv_timeout(Duration::from_secs(5)).unwrap(),
+        cubeb::State::Stopped
+    );
+    fake::take_captured_output(name)
+        .chunks(2)
+        .map(|s| i16::from_ne_bytes([s[0], s[1]]))
+        .collect()
+}
+
+#[test]
+fn fake_backend_output_ring() {
+    let server = start_server();
+    let ctx = connect(
+        &server,
+        audioipc_client::ClientOptions {
+            output_ring_buffer_frames: 4096,
+            ..audioipc_client::ClientOptions::default()
+        },
+    );
+
+    let value = Arc::new(AtomicI16::new(1));
+    let cb_value = value.clone();
+    let (state_tx, state_rx) = mpsc::channel();
+    let mut builder = cubeb::StreamBuilder::<cubeb::MonoFrame<i16>>::new();
+    builder
+        .name("fake ring")
+        .default_output(&params(cubeb::SampleFormat::S16NE))
+        .latency(512)
+        .data_callback(move |_, output| {
+            for f in output.iter_mut() {
+                f.m = cb_value.load(Ordering::SeqCst);
+            }
+            output.len() as isize
+        })
+        .state_callback(move |state| drop(state_tx.send(state)));
+    let stream = builder.init(&ctx).expect("ring stream init failed");
+
+    let samples = play(&stream, &state_rx, "fake ring");
+    assert!(samples.iter().any(|&s| s == 1));
+
+    // Audio rendered ahead before the stop isn't played after a restart.
+    value.store(2, Ordering::SeqCst);
+    let samples = play(&stream, &state_rx, "fake ring");
+    assert!(samples.iter().any(|&s| s == 2));
+    assert!(samples.iter().all(|&s| s == 2 || s == 0));
+
+    drop(stream);
+    drop(ctx);
+    drop(server);
+}
+
+// Connects to the server in the `Mutex<Option<Server>>` at `user_data`, if
+// any.
+extern "C" fn reconnect(user_data: *mut c_void) -> audioipc::PlatformHandleType {
+    let server = unsafe { &*(user_data as *const Mutex<Option<audioipc_server::Server>>) };
+    match *server.lock().unwrap() {
+        Some(ref server) => server.new_client().unwrap().into_raw_fd(),
+        None => audioipc::INVALID_HANDLE_VALUE,
+    }
+}
+
+// Wait for the fake backend to play `value` for the stream `name`.
+fn wait_for_output(name: &str, value: i16) -> bool {
+    wait_for(|| {
+        fake::take_captured_output(name)
+            .chunks(2)
+            .any(|s| i16::from_ne_bytes([s[0], s[1]]) == value)
+    })
+}
+
+#[test]
+fn fake_backend_reconnect() {
+    let first = start_server();
+    let next: Mutex<Option<audioipc_server::Server>> = Mutex::new(None);
+    let ctx = connect(
+        &first,
+        audioipc_client::ClientOptions {
+            reconnect_callback: Some(reconnect),
+            reconnect_user_data: &next as *const _ as *mut c_void,
+            ..audioipc_client::ClientOptions::default()
+        },
+    );
+
+    let (state_tx, state_rx) = mpsc::channel();
+    let mut builder = cubeb::StreamBuilder::<cubeb::MonoFrame<i16>>::new();
+    builder
+        .name("fake reconnect")
+        .default_output(&params(cubeb::SampleFormat::S16NE))
+        .latency(512)
+        .data_callback(|_, output| {
+            for f in output.iter_mut() {
+                f.m = OUTPUT_VALUE;
+            }
+            output.len() as isize
+        })
+        .state_callback(move |state| drop(state_tx.send(state)));
+    let stream = builder.init(&ctx).expect("reconnect stream init failed");
+    stream.set_volume(0.5).unwrap();
+    stream.start().unwrap();
+    assert_eq!(
+        state_rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+        cubeb::State::Started
+    );
+    assert!(wait_for_output("fake reconnect", OUTPUT_VALUE));
+
+    // The stream is recreated on the next server and restarted, without
+    // any call on it.
+    *next.lock().unwrap() = Some(start_server());
+    drop(first);
+    assert_eq!(
+        state_rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+        cubeb::State::Started
+    );
+    fake::take_captured_output("fake reconnect");
+    assert!(wait_for_output("fake reconnect", OUTPUT_VALUE));
+    assert!(stream.position().unwrap() > 0);
+
+    // With no server to reconnect to, the stream errors.
+    let last = next.lock().unwrap().take();
+    drop(last);
+    assert_eq!(
+        state_rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+        cubeb::State::Error
+    );
+    assert!(stream.position().is_err());
+
+    drop(stream);
+    drop(ctx);
+}
+
+#[test]
+fn fake_backend_context() {
+    let server = start_server();
+    let ctx = connect(&server, audioipc_client::ClientOptions::default());
+    assert_eq!(ctx.backend_id(), fake::BACKEND_NAME);
+    assert_eq!(ctx.preferred_sample_rate().unwrap(), RATE);
+    let devices = ctx.enumerate_devices(cubeb::DeviceType::OUTPUT).unwrap();
+    assert_eq!(devices.len(), 1);
+}
+
+#[test]
+fn fake_backend_output() {
+    let server = start_server();
+    let ctx = connect(&server, audioipc_client::ClientOptions::default());
+    test_output(&ctx, "fake output");
+}
+
+#[test]
+fn fake_backend_input() {
+    let server = start_server();
+    let ctx = connect(&server, audioipc_client::ClientOptions::default());
+    test_input(&ctx);
+}
+
+#[test]
+fn fake_backend_status() {
+    let server = start_server();
+    let ctx = connect(&server, audioipc_client::ClientOptions::default());
+    test_status(&server, &ctx);
+}
+
+#[test]
+fn fake_backend_device_collection_changed() {
+    let server = start_server();
+    let ctx = connect(&server, audioipc_client::ClientOptions::default());
+    test_device_collection_changed(&ctx);
+}
+
+// Data callbacks on a dedicated realtime thread per stream, rather than on
+// the shared pool, which runs the other callbacks and isn't promoted.
+#[test]
+fn fake_backend_stream_callback_threads() {
+    let server = start_server();
+    let ctx = connect(
+        &server,
+        audioipc_client::ClientOptions {
+            stream_callback_threads: true,
+            ..audioipc_client::ClientOptions::default()
+        },
+    );
+    test_output(&ctx, "fake output callback threads");
+
+    let (data_tx, data_rx) = mpsc::channel();
+    let (state_tx, state_rx) = mpsc::channel();
+    let mut builder = cubeb::StreamBuilder::<cubeb::MonoFrame<i16>>::new();
+    builder
+        .name("fake callback threads")
+        .default_output(&params(cubeb::SampleFormat::S16NE))
+        .latency(512)
+        .data_callback(move |_, output| {
+            drop(data_tx.send(thread::current().id()));
+            output.len() as isize
+        })
+        .state_callback(move |_| drop(state_tx.send(thread::current().id())));
+    let stream = builder.init(&ctx).expect("output stream init failed");
+    stream.start().unwrap();
+    let state_thread = state_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    let data_thread = data_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    stream.stop().unwrap();
+    assert_ne!(data_thread, state_thread);
+    drop(fake::take_captured_output("fake callback threads"));
+
+    // Linux clients have the server promote their threads.  Only the two
+    // streams' threads asked to be; the shared pool's threads started
+    // before either stream without asking.
+    #[cfg(target_os = "linux")]
+    {
+        let promoted_threads = || server.status().unwrap().clients[0].promoted_threads;
+        assert!(wait_for(|| promoted_threads() >= 2));
+        assert_eq!(promoted_threads(), 2);
+    }
+}
+
+// Data callbacks signalled through shared memory rather than over RPC.
+#[cfg(target_os = "linux")]
+#[test]
+fn fake_backend_shm_data_signal() {
+    let server = start_server();
+    let ctx = connect(
+        &server,
+        audioipc_client::ClientOptions {
+            shm_data_signal: true,
+            ..audioipc_client::ClientOptions::default()
+        },
+    );
+    test_output(&ctx, "fake output data signal");
+}
+
+#[test]
+fn fake_backend_restart_after_drain() {
+    let server = start_server();
+    let ctx = connect(&server, audioipc_client::ClientOptions::default());
+
+    let (state_tx, state_rx) = mpsc::channel();
+    let mut builder = cubeb::StreamBuilder::<cubeb::MonoFrame<i16>>::new();
+    builder
+        .name("fake drain")
+        .default_output(&params(cubeb::SampleFormat::S16NE))
+        .latency(512)
+        .data_callback(|_, _| 0)
+        .state_callback(move |state| drop(state_tx.send(state)));
+    let stream = builder.init(&ctx).expect("drain stream init failed");
+
+    for _ in 0..2 {
+        stream.start().unwrap();
+        for expected in &[cubeb::State::Started, cubeb::State::Drained] {
+            assert_eq!(
+                state_rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+                *expected
+            );
+        }
+    }
+}
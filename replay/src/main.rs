@@ -0,0 +1,257 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details.
+#![warn(unused_extern_crates)]
+#[macro_use]
+extern crate log;
+
+// Replays the requests recorded in a capture (see `audioipc::capture`)
+// against a server using the fake cubeb backend, and reports responses that
+// differ from those recorded.
+//
+// Run with 'cargo run -p audioipc-replay -- <capture file>'.
+
+use audioipc::capture::{self, Message, Record};
+use audioipc::codec::LengthDelimitedCodec;
+use audioipc::messages::{ClientMessage, ServerMessage};
+use audioipc::platformhandle_passing::{framed_with_platformhandles, FramedWithPlatformHandles};
+use audioipc::{core, rpc};
+use audioipc_server::fake;
+use futures::Future;
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::BufReader;
+use std::process::exit;
+use std::sync::mpsc;
+use std::time::Duration;
+use tokio::reactor;
+
+// Longest to wait for the server to answer a replayed request.
+const REPLAY_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct ReplayClient;
+
+impl rpc::Client for ReplayClient {
+    type Request = ServerMessage;
+    type Response = ClientMessage;
+    type Transport = FramedWithPlatformHandles<
+        audioipc::AsyncMessageStream,
+        LengthDelimitedCodec<rpc::Envelope<Self::Request>, rpc::Envelope<Self::Response>>,
+    >;
+}
+
+// A request recorded in the capture, and the response recorded for it if
+// the capture has one.
+struct Exchange {
+    connection: u32,
+    id: u32,
+    request: ServerMessage,
+    response: Option<ClientMessage>,
+}
+
+fn exchanges(records: Vec<Record>) -> Vec<Exchange> {
+    let mut exchanges: Vec<Exchange> = Vec::new();
+    let mut pending = HashMap::new();
+    for record in records {
+        match record.message {
+            Message::Server(request) => {
+                pending.insert((record.connection, record.id), exchanges.len());
+                exchanges.push(Exchange {
+                    connection: record.connection,
+                    id: record.id,
+                    request,
+                    response: None,
+                });
+            }
+            Message::Client(response) => {
+                if let Some(i) = pending.remove(&(record.connection, record.id)) {
+                    exchanges[i].response = Some(response);
+                }
+            }
+            // Callbacks depend on the timing of the original run, so aren't
+            // replayed.
+            Message::Callback(_) => {}
+        }
+    }
+    exchanges
+}
+
+// Adapt a recorded request to be replayed from this process.  Returns
+// `None` for requests that can't be replayed.
+fn replayable(request: ServerMessage) -> Option<ServerMessage> {
+    match request {
+        // Handles are duplicated into the client's process, which must be
+        // this one.
+        ServerMessage::ClientConnect(mut params) => {
+            params.pid = std::process::id();
+            Some(ServerMessage::ClientConnect(params))
+        }
+        // Would promote a thread of this process to real-time.
+        #[cfg(target_os = "linux")]
+        ServerMessage::PromoteThreadToRealTime(_) => None,
+        request => Some(request),
+    }
+}
+
+// Describe `response` without the details that legitimately differ
+// between the recording and the replay.
+fn describe(mut response: ClientMessage) -> String {
+    drop(capture::take_platform_handles(&mut response));
+    match response {
+        ClientMessage::StreamCreated(ref mut data) => data.target_pid = 0,
+        ClientMessage::ContextSetupDeviceCollectionCallback(ref mut data) => data.target_pid = 0,
+        _ => {}
+    }
+    format!("{:?}", response)
+}
+
+fn connect(
+    server: &audioipc_server::Server,
+    thread: &core::CoreThread,
+) -> Result<rpc::ClientProxy<ServerMessage, ClientMessage>, Box<dyn Error>> {
+    let stream = server.new_client()?;
+    let (tx, rx) = mpsc::channel();
+    thread
+        .handle()
+        .spawn(futures::future::lazy(move || {
+            let handle = reactor::Handle::default();
+            match stream.into_tokio_ipc(&handle) {
+                Ok(stream) => {
+                    let transport =
+                        framed_with_platformhandles(stream, rpc::client_codec::<ReplayClient>());
+                    drop(tx.send(rpc::bind_client::<ReplayClient>(transport)));
+                }
+                Err(e) => error!("Failed to bind replay connection: {:?}", e),
+            }
+            Ok(())
+        }))
+        .map_err(|_| "Failed to spawn replay client")?;
+    let mut rpc = rx.recv()?;
+    rpc.set_timeout(Some(REPLAY_TIMEOUT));
+    Ok(rpc)
+}
+
+// Replay the capture at `path`, returning the number of divergent responses.
+fn run(path: &str) -> Result<usize, Box<dyn Error>> {
+    let records = capture::read(BufReader::new(File::open(path)?))?;
+    let exchanges = exchanges(records);
+
+    let backend_name = CString::new(fake::BACKEND_NAME).unwrap();
+    let server = audioipc_server::Server::start(None, Some(&backend_name))?;
+    let thread = core::spawn_thread("AudioIPC Replay RPC", || Ok(()), || {})?;
+
+    let mut connections = HashMap::new();
+    let mut replayed = 0;
+    let mut divergent = 0;
+    for exchange in exchanges {
+        let request_description = format!("{:?}", exchange.request);
+        let request = match replayable(exchange.request) {
+            Some(request) => request,
+            None => {
+                info!("Skipping {}", request_description);
+                continue;
+            }
+        };
+
+        if !connections.contains_key(&exchange.connection) {
+            let rpc = connect(&server, &thread)?;
+            connections.insert(exchange.connection, rpc);
+        }
+        let rpc = &connections[&exchange.connection];
+
+        let response = match rpc.call(request).wait() {
+            Ok(response) => describe(response),
+            Err(e) => format!("error: {}", e),
+        };
+        replayed += 1;
+
+        let recorded = match exchange.response {
+            Some(recorded) => describe(recorded),
+            None => {
+                info!(
+                    "Connection {} request {}: no recorded response to {}",
+                    exchange.connection, exchange.id, request_description
+                );
+                continue;
+            }
+        };
+        if response != recorded {
+            divergent += 1;
+            println!(
+                "Connection {} request {}: {}\n  recorded: {}\n  replayed: {}",
+                exchange.connection, exchange.id, request_description, recorded, response
+            );
+        }
+    }
+
+    println!(
+        "{} requests replayed, {} divergent responses",
+        replayed, divergent
+    );
+
+    drop(connections);
+    drop(server);
+    Ok(divergent)
+}
+
+fn main() {
+    env_logger::init().unwrap();
+
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 2 {
+        eprintln!("usage: {} <capture file>", args[0]);
+        exit(2);
+    }
+
+    match run(&args[1]) {
+        Ok(0) => {}
+        Ok(_) => exit(1),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            exit(2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{connect, run};
+    use audioipc::core;
+    use audioipc::messages::{ClientConnectParams, ClientMessage, ServerMessage};
+    use audioipc_server::fake;
+    use futures::Future;
+    use std::ffi::CString;
+
+    #[test]
+    fn replay_capture() {
+        let path = std::env::temp_dir().join(format!("audioipc-replay-{}", std::process::id()));
+        let backend_name = CString::new(fake::BACKEND_NAME).unwrap();
+        let mut server = audioipc_server::Server::start(None, Some(&backend_name)).unwrap();
+        server.set_capture(&path).unwrap();
+        let thread = core::spawn_thread("Replay Test RPC", || Ok(()), || {}).unwrap();
+
+        let rpc = connect(&server, &thread).unwrap();
+        let connect = ServerMessage::ClientConnect(ClientConnectParams::new(std::process::id()));
+        match rpc.call(connect).wait() {
+            Ok(ClientMessage::ClientConnected(_)) => {}
+            r => panic!("Handshake failed: {:?}", r),
+        }
+        for request in [
+            ServerMessage::ContextGetBackendId,
+            ServerMessage::ContextGetMaxChannelCount,
+            ServerMessage::ContextGetPreferredSampleRate,
+        ] {
+            assert!(rpc.call(request).wait().is_ok());
+        }
+        // Dropping the server finishes the capture.
+        drop(rpc);
+        drop(server);
+
+        let divergent = run(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(divergent.unwrap(), 0);
+    }
+}
@@ -0,0 +1,634 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details
+
+//! A cubeb backend that doesn't need audio hardware, for testing the server
+//! and client end to end.
+//!
+//! Selected by passing `BACKEND_NAME` as the `backend_name` to
+//! `audioipc_server_start`.  Each started stream is driven by a timer thread
+//! calling the data callback every `latency_frames` frames.  Input streams
+//! are fed a sine tone, and output is captured into memory where tests can
+//! retrieve it with `take_captured_output`.  Device change and device
+//! collection change events are injected with `inject_device_changed` and
+//! `inject_device_collection_changed`, and a slow stream initialization is
+//! simulated with `hold_stream_init`.
+
+use audioipc::messages::{DeviceInfo, StreamParams};
+use cubeb_backend::{
+    ffi, Context, ContextOps, DeviceCollectionRef, DeviceId, DeviceRef, DeviceType, Error, Ops,
+    Result, Stream, StreamOps, StreamParamsRef,
+};
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, HashSet};
+use std::f32::consts::PI;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_long, c_void};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::time::{Duration, Instant};
+use std::{cmp, ptr, thread};
+
+/// Name selecting the fake backend.
+pub const BACKEND_NAME: &str = "audioipc-fake";
+
+const FAKE_OPS: Ops = capi_new!(FakeContext, FakeStream);
+
+const MAX_CHANNELS: u32 = 2;
+const PREFERRED_RATE: u32 = 48_000;
+const MIN_LATENCY_FRAMES: u32 = 256;
+const TONE_FREQUENCY: f32 = 440.0;
+// Output captured beyond this is discarded.
+const MAX_CAPTURED_BYTES: usize = 16 * 1024 * 1024;
+// Data callback durations recorded beyond this are discarded.
+const MAX_CALLBACK_DURATIONS: usize = 1024 * 1024;
+
+const INPUT_DEVID: usize = 1;
+const OUTPUT_DEVID: usize = 2;
+
+// Registered callbacks of live contexts and streams, the output captured
+// from streams and how long their data callbacks took, and the streams
+// whose initialization is held, by stream name.
+struct Registry {
+    contexts: Vec<Weak<Mutex<CollectionCallbacks>>>,
+    streams: Vec<Weak<StreamShared>>,
+    captured: HashMap<String, Vec<u8>>,
+    callback_durations: HashMap<String, Vec<Duration>>,
+    held_inits: HashSet<String>,
+}
+
+static REGISTRY: Lazy<Mutex<Registry>> = Lazy::new(|| {
+    Mutex::new(Registry {
+        contexts: Vec::new(),
+        streams: Vec::new(),
+        captured: HashMap::new(),
+        callback_durations: HashMap::new(),
+        held_inits: HashSet::new(),
+    })
+});
+
+// Signalled when a held stream initialization is released.
+static INIT_RELEASED: Lazy<Condvar> = Lazy::new(Condvar::new);
+
+/// Remove and return the output captured so far from streams named
+/// `stream_name`, in the stream's sample format.
+pub fn take_captured_output(stream_name: &str) -> Vec<u8> {
+    let mut registry = REGISTRY.lock().unwrap();
+    registry.captured.remove(stream_name).unwrap_or_default()
+}
+
+/// Remove and return how long each data callback of streams named
+/// `stream_name` took so far, from the backend calling it to it returning.
+pub fn take_callback_durations(stream_name: &str) -> Vec<Duration> {
+    let mut registry = REGISTRY.lock().unwrap();
+    registry
+        .callback_durations
+        .remove(stream_name)
+        .unwrap_or_default()
+}
+
+/// Block the initialization of streams named `stream_name` until
+/// `release_stream_init` is called, as a slow device would.
+pub fn hold_stream_init(stream_name: &str) {
+    let mut registry = REGISTRY.lock().unwrap();
+    registry.held_inits.insert(stream_name.to_owned());
+}
+
+/// Let the initialization of streams named `stream_name` complete.
+pub fn release_stream_init(stream_name: &str) {
+    let mut registry = REGISTRY.lock().unwrap();
+    registry.held_inits.remove(stream_name);
+    INIT_RELEASED.notify_all();
+}
+
+/// Call the device changed callback of every fake stream that registered
+/// one.
+pub fn inject_device_changed() {
+    let callbacks: Vec<_> = {
+        let registry = REGISTRY.lock().unwrap();
+        registry
+            .streams
+            .iter()
+            .filter_map(|s| s.upgrade())
+            .filter_map(|s| {
+                s.device_changed_cb
+                    .lock()
+                    .unwrap()
+                    .map(|cb| (cb, s.user_ptr))
+            })
+            .collect()
+    };
+    // Called without the registry locked, as real backends call back from
+    // their own threads.
+    for (cb, user_ptr) in callbacks {
+        unsafe { cb(user_ptr as *mut c_void) };
+    }
+}
+
+/// Call the device collection changed callbacks registered on every fake
+/// context for any of the types in `devtype`.
+pub fn inject_device_collection_changed(devtype: DeviceType) {
+    let callbacks: Vec<_> = {
+        let registry = REGISTRY.lock().unwrap();
+        registry
+            .contexts
+            .iter()
+            .filter_map(|c| c.upgrade())
+            .flat_map(|c| {
+                let c = c.lock().unwrap();
+                let mut cbs = Vec::new();
+                if devtype.contains(DeviceType::INPUT) {
+                    cbs.extend(c.input.map(|(cb, user_ptr)| (cb, c.context, user_ptr)));
+                }
+                if devtype.contains(DeviceType::OUTPUT) {
+                    cbs.extend(c.output.map(|(cb, user_ptr)| (cb, c.context, user_ptr)));
+                }
+                cbs
+            })
+            .collect()
+    };
+    for (cb, context, user_ptr) in callbacks {
+        unsafe { cb(context as *mut ffi::cubeb, user_ptr as *mut c_void) };
+    }
+}
+
+pub fn init(context_name: Option<&CStr>) -> Result<Context> {
+    FakeContext::init(context_name)
+}
+
+type CollectionCallback = unsafe extern "C" fn(*mut ffi::cubeb, *mut c_void);
+
+#[derive(Default)]
+struct CollectionCallbacks {
+    context: usize,
+    input: Option<(CollectionCallback, usize)>,
+    output: Option<(CollectionCallback, usize)>,
+}
+
+// FakeContext's layout *must* match cubeb.c's `struct cubeb` for the
+// common fields.
+#[repr(C)]
+pub struct FakeContext {
+    _ops: *const Ops,
+    collection: Arc<Mutex<CollectionCallbacks>>,
+}
+
+impl FakeContext {
+    fn devices(devtype: DeviceType) -> Vec<DeviceInfo> {
+        let device = |devid: usize, name: &str, device_type| DeviceInfo {
+            devid,
+            device_id: Some(name.as_bytes().to_vec()),
+            friendly_name: Some(name.as_bytes().to_vec()),
+            group_id: Some(b"fake".to_vec()),
+            vendor_name: Some(b"AudioIPC".to_vec()),
+            device_type,
+            state: ffi::CUBEB_DEVICE_STATE_ENABLED,
+            preferred: ffi::CUBEB_DEVICE_PREF_ALL,
+            format: ffi::CUBEB_DEVICE_FMT_ALL,
+            default_format: ffi::CUBEB_DEVICE_FMT_F32NE,
+            max_channels: MAX_CHANNELS,
+            default_rate: PREFERRED_RATE,
+            max_rate: 192_000,
+            min_rate: 8_000,
+            latency_lo: MIN_LATENCY_FRAMES,
+            latency_hi: PREFERRED_RATE,
+        };
+        let mut devices = Vec::new();
+        if devtype.contains(DeviceType::INPUT) {
+            devices.push(device(
+                INPUT_DEVID,
+                "Fake Input",
+                ffi::CUBEB_DEVICE_TYPE_INPUT,
+            ));
+        }
+        if devtype.contains(DeviceType::OUTPUT) {
+            devices.push(device(
+                OUTPUT_DEVID,
+                "Fake Output",
+                ffi::CUBEB_DEVICE_TYPE_OUTPUT,
+            ));
+        }
+        devices
+    }
+}
+
+impl ContextOps for FakeContext {
+    fn init(_context_name: Option<&CStr>) -> Result<Context> {
+        let collection = Arc::new(Mutex::new(CollectionCallbacks::default()));
+        let ctx = Box::into_raw(Box::new(FakeContext {
+            _ops: &FAKE_OPS as *const _,
+            collection: collection.clone(),
+        }));
+        collection.lock().unwrap().context = ctx as usize;
+
+        let mut registry = REGISTRY.lock().unwrap();
+        registry.contexts.retain(|c| c.upgrade().is_some());
+        registry.contexts.push(Arc::downgrade(&collection));
+
+        Ok(unsafe { Context::from_ptr(ctx as *mut _) })
+    }
+
+    fn backend_id(&mut self) -> &CStr {
+        CStr::from_bytes_with_nul(b"audioipc-fake\0").unwrap()
+    }
+
+    fn max_channel_count(&mut self) -> Result<u32> {
+        Ok(MAX_CHANNELS)
+    }
+
+    fn min_latency(&mut self, _params: cubeb_backend::StreamParams) -> Result<u32> {
+        Ok(MIN_LATENCY_FRAMES)
+    }
+
+    fn preferred_sample_rate(&mut self) -> Result<u32> {
+        Ok(PREFERRED_RATE)
+    }
+
+    fn enumerate_devices(
+        &mut self,
+        devtype: DeviceType,
+        collection: &DeviceCollectionRef,
+    ) -> Result<()> {
+        let v: Vec<ffi::cubeb_device_info> = FakeContext::devices(devtype)
+            .into_iter()
+            .map(|i| i.into())
+            .collect();
+        let mut vs = v.into_boxed_slice();
+        let coll = unsafe { &mut *collection.as_ptr() };
+        coll.device = vs.as_mut_ptr();
+        coll.count = vs.len();
+        // Giving away the memory owned by vs.  Don't free it!
+        // Reclaimed in `device_collection_destroy`.
+        std::mem::forget(vs);
+        Ok(())
+    }
+
+    fn device_collection_destroy(&mut self, collection: &mut DeviceCollectionRef) -> Result<()> {
+        unsafe {
+            let coll = &mut *collection.as_ptr();
+            let mut devices = Vec::from_raw_parts(
+                coll.device as *mut ffi::cubeb_device_info,
+                coll.count,
+                coll.count,
+            );
+            for dev in &mut devices {
+                for s in &[
+                    dev.device_id,
+                    dev.group_id,
+                    dev.vendor_name,
+                    dev.friendly_name,
+                ] {
+                    if !s.is_null() {
+                        let _ = CString::from_raw(*s as *mut _);
+                    }
+                }
+            }
+            coll.device = ptr::null_mut();
+            coll.count = 0;
+            Ok(())
+        }
+    }
+
+    fn stream_init(
+        &mut self,
+        stream_name: Option<&CStr>,
+        input_device: DeviceId,
+        input_stream_params: Option<&StreamParamsRef>,
+        output_device: DeviceId,
+        output_stream_params: Option<&StreamParamsRef>,
+        latency_frames: u32,
+        data_callback: ffi::cubeb_data_callback,
+        state_callback: ffi::cubeb_state_callback,
+        user_ptr: *mut c_void,
+    ) -> Result<Stream> {
+        fn check_params(
+            params: Option<&StreamParamsRef>,
+            device: DeviceId,
+            devid: usize,
+        ) -> Result<Option<StreamParams>> {
+            let params = match params {
+                Some(params) => StreamParams::from(params),
+                None => return Ok(None),
+            };
+            if !device.is_null() && device as usize != devid {
+                return Err(Error::device_unavailable());
+            }
+            if params.frame_size().is_none()
+                || params.channels == 0
+                || params.channels > MAX_CHANNELS
+                || params.rate == 0
+            {
+                return Err(Error::invalid_format());
+            }
+            Ok(Some(params))
+        }
+
+        let input = check_params(input_stream_params, input_device, INPUT_DEVID)?;
+        let output = check_params(output_stream_params, output_device, OUTPUT_DEVID)?;
+        let rate = match (input, output) {
+            (Some(i), Some(o)) if i.rate != o.rate => return Err(Error::invalid_format()),
+            (_, Some(p)) | (Some(p), None) => p.rate,
+            (None, None) => return Err(Error::invalid_parameter()),
+        };
+        let data_cb = data_callback.ok_or_else(Error::invalid_parameter)?;
+
+        let name = stream_name
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        {
+            let mut registry = REGISTRY.lock().unwrap();
+            while registry.held_inits.contains(&name) {
+                registry = INIT_RELEASED.wait(registry).unwrap();
+            }
+        }
+
+        let shared = Arc::new(StreamShared {
+            name,
+            input,
+            output,
+            rate,
+            period_frames: cmp::min(cmp::max(latency_frames, 1), rate),
+            user_ptr: user_ptr as usize,
+            data_cb,
+            state_cb: state_callback,
+            device_changed_cb: Mutex::new(None),
+            running: AtomicBool::new(false),
+            position: AtomicU64::new(0),
+        });
+        {
+            let mut registry = REGISTRY.lock().unwrap();
+            registry.streams.retain(|s| s.upgrade().is_some());
+            registry.streams.push(Arc::downgrade(&shared));
+        }
+
+        let stream = Box::into_raw(Box::new(FakeStream {
+            _context: self,
+            _user_ptr: user_ptr,
+            shared,
+            thread: None,
+        }));
+        Ok(unsafe { Stream::from_ptr(stream as *mut _) })
+    }
+
+    fn register_device_collection_changed(
+        &mut self,
+        devtype: DeviceType,
+        collection_changed_callback: ffi::cubeb_device_collection_changed_callback,
+        user_ptr: *mut c_void,
+    ) -> Result<()> {
+        let cb = collection_changed_callback.map(|cb| (cb, user_ptr as usize));
+        let mut collection = self.collection.lock().unwrap();
+        if devtype.contains(DeviceType::INPUT) {
+            collection.input = cb;
+        }
+        if devtype.contains(DeviceType::OUTPUT) {
+            collection.output = cb;
+        }
+        Ok(())
+    }
+}
+
+type DataCallback = unsafe extern "C" fn(
+    *mut ffi::cubeb_stream,
+    *mut c_void,
+    *const c_void,
+    *mut c_void,
+    c_long,
+) -> c_long;
+
+// Stream state shared with the timer thread and the registry.
+struct StreamShared {
+    name: String,
+    input: Option<StreamParams>,
+    output: Option<StreamParams>,
+    rate: u32,
+    period_frames: u32,
+    user_ptr: usize,
+    data_cb: DataCallback,
+    state_cb: ffi::cubeb_state_callback,
+    device_changed_cb: Mutex<ffi::cubeb_device_changed_callback>,
+    running: AtomicBool,
+    position: AtomicU64,
+}
+
+impl StreamShared {
+    fn state_changed(&self, stream: usize, state: ffi::cubeb_state) {
+        if let Some(cb) = self.state_cb {
+            unsafe { cb(stream as *mut _, self.user_ptr as *mut c_void, state) };
+        }
+    }
+
+    // Timer thread body: call the data callback once per period until
+    // stopped, drained or errored.  `running` is cleared on drain or error.
+    fn run(&self, stream: usize) {
+        self.state_changed(stream, ffi::CUBEB_STATE_STARTED);
+
+        let frames = self.period_frames as usize;
+        let buffer = |params: Option<StreamParams>| {
+            params.map_or_else(Vec::new, |p| vec![0u8; p.frame_size().unwrap() * frames])
+        };
+        let mut input = buffer(self.input);
+        let mut output = buffer(self.output);
+        let period = Duration::from_nanos(frames as u64 * 1_000_000_000 / u64::from(self.rate));
+        let mut tone_position = 0;
+        let mut next = Instant::now();
+
+        while self.running.load(Ordering::SeqCst) {
+            if let Some(params) = self.input {
+                write_tone(&params, tone_position, &mut input);
+                tone_position += frames;
+            }
+            for b in output.iter_mut() {
+                *b = 0;
+            }
+
+            let buffer_ptr = |buf: &mut Vec<u8>| {
+                if buf.is_empty() {
+                    ptr::null_mut()
+                } else {
+                    buf.as_mut_ptr() as *mut c_void
+                }
+            };
+            let start = Instant::now();
+            let got = unsafe {
+                (self.data_cb)(
+                    stream as *mut _,
+                    self.user_ptr as *mut c_void,
+                    buffer_ptr(&mut input),
+                    buffer_ptr(&mut output),
+                    frames as c_long,
+                )
+            };
+            self.record_duration(start.elapsed());
+            if got < 0 {
+                self.running.store(false, Ordering::SeqCst);
+                self.state_changed(stream, ffi::CUBEB_STATE_ERROR);
+                return;
+            }
+
+            let got = cmp::min(got as usize, frames);
+            self.position.fetch_add(got as u64, Ordering::SeqCst);
+            if let Some(params) = self.output {
+                self.capture(&output[..got * params.frame_size().unwrap()]);
+            }
+            if got < frames {
+                self.running.store(false, Ordering::SeqCst);
+                self.state_changed(stream, ffi::CUBEB_STATE_DRAINED);
+                return;
+            }
+
+            next += period;
+            let now = Instant::now();
+            if next > now {
+                thread::sleep(next - now);
+            } else {
+                next = now;
+            }
+        }
+    }
+
+    fn capture(&self, data: &[u8]) {
+        let mut registry = REGISTRY.lock().unwrap();
+        let captured = registry.captured.entry(self.name.clone()).or_default();
+        let n = cmp::min(
+            data.len(),
+            MAX_CAPTURED_BYTES.saturating_sub(captured.len()),
+        );
+        captured.extend_from_slice(&data[..n]);
+    }
+
+    fn record_duration(&self, duration: Duration) {
+        let mut registry = REGISTRY.lock().unwrap();
+        let durations = registry
+            .callback_durations
+            .entry(self.name.clone())
+            .or_default();
+        if durations.len() < MAX_CALLBACK_DURATIONS {
+            durations.push(duration);
+        }
+    }
+}
+
+// Fill `buf` with a sine tone in `params`' format, the same on every channel.
+fn write_tone(params: &StreamParams, position: usize, buf: &mut [u8]) {
+    let sample_size = params.frame_size().unwrap() / params.channels as usize;
+    for (i, frame) in buf
+        .chunks_mut(sample_size * params.channels as usize)
+        .enumerate()
+    {
+        let t = (position + i) as f32 / params.rate as f32;
+        let v = 0.5 * (2.0 * PI * TONE_FREQUENCY * t).sin();
+        for sample in frame.chunks_mut(sample_size) {
+            match params.format {
+                ffi::CUBEB_SAMPLE_S16LE => {
+                    sample.copy_from_slice(&((v * 32767.0) as i16).to_le_bytes())
+                }
+                ffi::CUBEB_SAMPLE_S16BE => {
+                    sample.copy_from_slice(&((v * 32767.0) as i16).to_be_bytes())
+                }
+                ffi::CUBEB_SAMPLE_FLOAT32LE => sample.copy_from_slice(&v.to_bits().to_le_bytes()),
+                ffi::CUBEB_SAMPLE_FLOAT32BE => sample.copy_from_slice(&v.to_bits().to_be_bytes()),
+                _ => unreachable!(),
+            }
+        }
+    }
+}
+
+// FakeStream's layout *must* match cubeb.c's `struct cubeb_stream` for the
+// common fields.
+#[repr(C)]
+pub struct FakeStream<'ctx> {
+    _context: &'ctx FakeContext,
+    _user_ptr: *mut c_void,
+    shared: Arc<StreamShared>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl<'ctx> FakeStream<'ctx> {
+    // Stop the timer thread, returning false if it wasn't running.
+    fn stop_thread(&mut self) -> bool {
+        self.shared.running.store(false, Ordering::SeqCst);
+        match self.thread.take() {
+            Some(thread) => {
+                let _ = thread.join();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl<'ctx> Drop for FakeStream<'ctx> {
+    fn drop(&mut self) {
+        self.stop_thread();
+    }
+}
+
+impl<'ctx> StreamOps for FakeStream<'ctx> {
+    fn start(&mut self) -> Result<()> {
+        if self.shared.running.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        // The thread exits by itself once drained or errored, reap it.
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        self.shared.running.store(true, Ordering::SeqCst);
+        let shared = self.shared.clone();
+        let stream = self as *mut FakeStream as usize;
+        let thread = thread::Builder::new()
+            .name("AudioIPC Fake Stream".into())
+            .spawn(move || shared.run(stream))
+            .map_err(|_| Error::error())?;
+        self.thread = Some(thread);
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        if self.stop_thread() {
+            let stream = self as *mut FakeStream as usize;
+            self.shared.state_changed(stream, ffi::CUBEB_STATE_STOPPED);
+        }
+        Ok(())
+    }
+
+    fn position(&mut self) -> Result<u64> {
+        Ok(self.shared.position.load(Ordering::SeqCst))
+    }
+
+    fn latency(&mut self) -> Result<u32> {
+        Ok(self.shared.period_frames)
+    }
+
+    fn input_latency(&mut self) -> Result<u32> {
+        match self.shared.input {
+            Some(_) => Ok(self.shared.period_frames),
+            None => Err(Error::error()),
+        }
+    }
+
+    fn set_volume(&mut self, _volume: f32) -> Result<()> {
+        Ok(())
+    }
+
+    fn set_name(&mut self, _name: &CStr) -> Result<()> {
+        Ok(())
+    }
+
+    fn current_device(&mut self) -> Result<&DeviceRef> {
+        Err(Error::not_supported())
+    }
+
+    fn device_destroy(&mut self, _device: &DeviceRef) -> Result<()> {
+        Err(Error::not_supported())
+    }
+
+    fn register_device_changed_callback(
+        &mut self,
+        device_changed_callback: ffi::cubeb_device_changed_callback,
+    ) -> Result<()> {
+        *self.shared.device_changed_cb.lock().unwrap() = device_changed_callback;
+        Ok(())
+    }
+}
@@ -4,38 +4,41 @@
 // accompanying file LICENSE for details
 #![warn(unused_extern_crates)]
 
+#[macro_use]
+extern crate cubeb_backend;
 #[macro_use]
 extern crate error_chain;
 #[macro_use]
 extern crate log;
+#[macro_use]
+extern crate serde_derive;
 
 use audio_thread_priority::promote_current_thread_to_real_time;
+use audioipc::capture;
+use audioipc::codec::{LengthDelimitedCodec, MessageLimit, MAX_MESSAGE_LEN};
 use audioipc::core;
 use audioipc::platformhandle_passing::framed_with_platformhandles;
 use audioipc::rpc;
 use audioipc::{MessageStream, PlatformHandle, PlatformHandleType};
 use futures::sync::oneshot;
 use futures::Future;
-use once_cell::sync::Lazy;
 use std::ffi::{CStr, CString};
-use std::os::raw::c_void;
+use std::os::raw::{c_char, c_int, c_void};
+use std::path::{Path, PathBuf};
 use std::ptr;
-use std::sync::Mutex;
+use std::sync::Arc;
 use tokio::reactor;
 
+pub mod fake;
 mod server;
+mod shm_pool;
+mod wav;
 
-struct CubebContextParams {
-    context_name: CString,
-    backend_name: Option<CString>,
-}
+pub use crate::server::{ClientIdentity, ClientLimits, ClientStatus, ServerConfig, StreamStatus};
+pub use crate::shm_pool::{ShmPoolLimits, ShmPoolStats};
 
-static G_CUBEB_CONTEXT_PARAMS: Lazy<Mutex<CubebContextParams>> = Lazy::new(|| {
-    Mutex::new(CubebContextParams {
-        context_name: CString::new("AudioIPC Server").unwrap(),
-        backend_name: None,
-    })
-});
+#[cfg(feature = "fuzzing")]
+pub use crate::server::{set_context_params, CubebServer};
 
 #[allow(deprecated)]
 pub mod errors {
@@ -53,12 +56,141 @@ pub mod errors {
 
 use crate::errors::*;
 
-struct ServerWrapper {
+/// A running server.  Stopped when dropped.
+pub struct Server {
     core_thread: core::CoreThread,
     callback_thread: core::CoreThread,
+    config: ServerConfig,
+    capture: Option<Arc<capture::Writer>>,
+    audio_tap_dir: Option<PathBuf>,
+}
+
+impl Server {
+    /// Start a server creating cubeb contexts named `context_name`, by
+    /// default "AudioIPC Server", using the cubeb backend `backend_name`, by
+    /// default cubeb's choice.  `fake::BACKEND_NAME` selects the fake
+    /// backend.
+    pub fn start(context_name: Option<&CStr>, backend_name: Option<&CStr>) -> Result<Server> {
+        run(server::CubebContextParams::new(context_name, backend_name))
+    }
+
+    /// Configuration applied to clients created by later calls to
+    /// `new_client`.
+    pub fn config(&self) -> ServerConfig {
+        self.config
+    }
+
+    pub fn set_config(&mut self, config: ServerConfig) {
+        self.config = config;
+    }
+
+    /// Record the messages exchanged with clients created by later calls to
+    /// `new_client` to a new capture file at `path`.  See
+    /// `audioipc::capture`.
+    pub fn set_capture(&mut self, path: &Path) -> Result<()> {
+        self.capture = Some(capture::Writer::create(path)?);
+        Ok(())
+    }
+
+    /// Record the input and output audio of streams created by clients from
+    /// later calls to `new_client` to WAV files in `dir`, named by the
+    /// client's pid and the stream's token.
+    pub fn set_audio_tap(&mut self, dir: &Path) {
+        self.audio_tap_dir = Some(dir.to_owned());
+    }
+
+    /// Create a new client connection, returning the client's end.
+    pub fn new_client(&self) -> Result<MessageStream> {
+        let (wait_tx, wait_rx) = oneshot::channel();
+
+        let core_handle = self.callback_thread.handle();
+        let config = self.config;
+        let capture = self.capture.clone();
+        let audio_tap_dir = self.audio_tap_dir.clone();
+
+        // We create a connected pair of anonymous IPC endpoints. One side
+        // is registered with the reactor core, the other side is returned
+        // to the caller.
+        let (ipc_server, ipc_client) = MessageStream::anonymous_ipc_pair()?;
+        // The pair's own credentials describe us, so have the client's
+        // messages carry its credentials to check its claimed pid against.
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        ipc_server.set_pass_credentials()?;
+
+        // Spawn closure to run on same thread as reactor::Core
+        // via remote handle.
+        self.core_thread
+            .handle()
+            .spawn(futures::future::lazy(|| {
+                trace!("Incoming connection");
+                let handle = reactor::Handle::default();
+                ipc_server.into_tokio_ipc(&handle)
+                .and_then(|sock| {
+                    // Lowered to the limit negotiated in the handshake.
+                    let limit = MessageLimit::new(MAX_MESSAGE_LEN as usize);
+                    let transport = framed_with_platformhandles(
+                        sock,
+                        LengthDelimitedCodec::with_limit(limit.clone()),
+                    );
+                    let mut server = server::CubebServer::new(core_handle, config);
+                    server.set_message_limit(limit);
+                    if let Some(dir) = audio_tap_dir {
+                        server.set_audio_tap(&dir);
+                    }
+                    match capture {
+                        Some(capture) => {
+                            server.set_capture(capture.clone());
+                            rpc::bind_server_with_tap(
+                                transport,
+                                server::RegisteredCubebServer::new(server),
+                                Box::new(capture.tap()),
+                            );
+                        }
+                        None => {
+                            rpc::bind_server(transport, server::RegisteredCubebServer::new(server))
+                        }
+                    };
+                    Ok(())
+                }).map_err(|_| ())
+                // Notify waiting thread that server has been registered.
+                .and_then(|_| wait_tx.send(()))
+            }))
+            .map_err(|_| "Failed to spawn CubebServer")?;
+        // Wait for notification that server has been registered
+        // with reactor::Core.
+        wait_rx.wait()?;
+        Ok(ipc_client)
+    }
+
+    /// Snapshot of the server's clients and their streams.
+    pub fn status(&self) -> Result<ServerStatus> {
+        let (tx, rx) = oneshot::channel();
+        self.core_thread
+            .handle()
+            .spawn(futures::future::lazy(move || {
+                drop(tx.send(server::clients_status()));
+                Ok(())
+            }))
+            .map_err(|_| "Failed to query server status")?;
+        Ok(ServerStatus {
+            clients: rx.wait()?,
+        })
+    }
+}
+
+/// State of a server, for attaching to bug reports.
+#[derive(Debug, Serialize)]
+pub struct ServerStatus {
+    pub clients: Vec<ClientStatus>,
+}
+
+impl ServerStatus {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Failed to serialize server status")
+    }
 }
 
-fn run() -> Result<ServerWrapper> {
+fn run(context_params: server::CubebContextParams) -> Result<Server> {
     trace!("Starting up cubeb audio server event loop thread...");
 
     let callback_thread = core::spawn_thread(
@@ -87,6 +219,7 @@ fn run() -> Result<ServerWrapper> {
         "AudioIPC Server RPC",
         move || {
             audioipc::server_platform_init();
+            server::init_context_params(context_params);
             Ok(())
         },
         || {},
@@ -96,9 +229,12 @@ fn run() -> Result<ServerWrapper> {
         Err(e)
     })?;
 
-    Ok(ServerWrapper {
+    Ok(Server {
         core_thread,
         callback_thread,
+        config: Default::default(),
+        capture: None,
+        audio_tap_dir: None,
     })
 }
 
@@ -107,15 +243,14 @@ pub unsafe extern "C" fn audioipc_server_start(
     context_name: *const std::os::raw::c_char,
     backend_name: *const std::os::raw::c_char,
 ) -> *mut c_void {
-    let mut params = G_CUBEB_CONTEXT_PARAMS.lock().unwrap();
-    if !context_name.is_null() {
-        params.context_name = CStr::from_ptr(context_name).to_owned();
-    }
-    if !backend_name.is_null() {
-        let backend_string = CStr::from_ptr(backend_name).to_owned();
-        params.backend_name = Some(backend_string);
-    }
-    match run() {
+    let opt_cstr = |s: *const std::os::raw::c_char| {
+        if s.is_null() {
+            None
+        } else {
+            Some(CStr::from_ptr(s))
+        }
+    };
+    match Server::start(opt_cstr(context_name), opt_cstr(backend_name)) {
         Ok(server) => Box::into_raw(Box::new(server)) as *mut _,
         Err(_) => ptr::null_mut() as *mut _,
     }
@@ -123,44 +258,102 @@ pub unsafe extern "C" fn audioipc_server_start(
 
 #[no_mangle]
 pub extern "C" fn audioipc_server_new_client(p: *mut c_void) -> PlatformHandleType {
-    let (wait_tx, wait_rx) = oneshot::channel();
-    let wrapper: &ServerWrapper = unsafe { &*(p as *mut _) };
-
-    let core_handle = wrapper.callback_thread.handle();
-
-    // We create a connected pair of anonymous IPC endpoints. One side
-    // is registered with the reactor core, the other side is returned
-    // to the caller.
-    MessageStream::anonymous_ipc_pair()
-        .and_then(|(ipc_server, ipc_client)| {
-            // Spawn closure to run on same thread as reactor::Core
-            // via remote handle.
-            wrapper
-                .core_thread
-                .handle()
-                .spawn(futures::future::lazy(|| {
-                    trace!("Incoming connection");
-                    let handle = reactor::Handle::default();
-                    ipc_server.into_tokio_ipc(&handle)
-                    .and_then(|sock| {
-                        let transport = framed_with_platformhandles(sock, Default::default());
-                        rpc::bind_server(transport, server::CubebServer::new(core_handle));
-                        Ok(())
-                    }).map_err(|_| ())
-                    // Notify waiting thread that server has been registered.
-                    .and_then(|_| wait_tx.send(()))
-                }))
-                .expect("Failed to spawn CubebServer");
-            // Wait for notification that server has been registered
-            // with reactor::Core.
-            let _ = wait_rx.wait();
-            Ok(unsafe { PlatformHandle::from(ipc_client).into_raw() })
-        })
+    let server: &Server = unsafe { &*(p as *mut _) };
+    server
+        .new_client()
+        .map(|stream| unsafe { PlatformHandle::from(stream).into_raw() })
         .unwrap_or(audioipc::INVALID_HANDLE_VALUE)
 }
 
+/// Put a client's stream in the error state after it misses `limit`
+/// consecutive data callback deadlines.  Zero disables the limit.  Applies
+/// to clients created after the call.
+#[no_mangle]
+pub unsafe extern "C" fn audioipc_server_set_callback_miss_limit(p: *mut c_void, limit: u32) {
+    let server: &mut Server = &mut *(p as *mut _);
+    server.config.max_callback_misses = if limit > 0 { Some(limit) } else { None };
+}
+
+/// Limit the resources each client may use.  Zero leaves a resource
+/// unlimited.  Applies to clients created after the call.  See
+/// `ClientLimits`.
+#[no_mangle]
+pub unsafe extern "C" fn audioipc_server_set_client_limits(
+    p: *mut c_void,
+    max_streams: u32,
+    max_shm_size: usize,
+    max_device_collection_setups: u32,
+    max_messages_per_second: u32,
+) {
+    let server: &mut Server = &mut *(p as *mut _);
+    let limit = |max: u32| if max > 0 { Some(max) } else { None };
+    server.config.limits = ClientLimits {
+        max_streams: limit(max_streams).map(|max| max as usize),
+        max_shm_size: if max_shm_size > 0 {
+            Some(max_shm_size)
+        } else {
+            None
+        },
+        max_device_collection_setups: limit(max_device_collection_setups),
+        max_messages_per_second: limit(max_messages_per_second),
+    };
+}
+
+/// Record the messages exchanged with clients created after the call to a
+/// new capture file at `path`.  Returns 0 on success, or -1 if the file
+/// can't be created.
+#[no_mangle]
+pub unsafe extern "C" fn audioipc_server_set_capture(p: *mut c_void, path: *const c_char) -> c_int {
+    let server: &mut Server = &mut *(p as *mut _);
+    let path = match CStr::from_ptr(path).to_str() {
+        Ok(path) => path,
+        Err(_) => return -1,
+    };
+    match server.set_capture(Path::new(path)) {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// Record the audio of streams created by clients created after the call to
+/// WAV files in the directory `dir`.
+#[no_mangle]
+pub unsafe extern "C" fn audioipc_server_set_audio_tap(
+    p: *mut c_void,
+    dir: *const c_char,
+) -> c_int {
+    let server: &mut Server = &mut *(p as *mut _);
+    match CStr::from_ptr(dir).to_str() {
+        Ok(dir) => {
+            server.set_audio_tap(Path::new(dir));
+            0
+        }
+        Err(_) => -1,
+    }
+}
+
+/// Describe the server's clients and their streams as a JSON document.
+/// Returns null on failure.  The result must be freed with
+/// `audioipc_server_free_status`.
+#[no_mangle]
+pub unsafe extern "C" fn audioipc_server_status(p: *mut c_void) -> *mut c_char {
+    let server: &Server = &*(p as *mut _);
+    server
+        .status()
+        .ok()
+        .and_then(|status| CString::new(status.to_json()).ok())
+        .map_or(ptr::null_mut(), CString::into_raw)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn audioipc_server_free_status(status: *mut c_char) {
+    if !status.is_null() {
+        drop(CString::from_raw(status));
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn audioipc_server_stop(p: *mut c_void) {
-    let wrapper = unsafe { Box::<ServerWrapper>::from_raw(p as *mut _) };
-    drop(wrapper);
+    let server = unsafe { Box::<Server>::from_raw(p as *mut _) };
+    drop(server);
 }
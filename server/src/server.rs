@@ -5,41 +5,56 @@
 
 #[cfg(target_os = "linux")]
 use audio_thread_priority::{promote_thread_to_real_time, RtPriorityThreadInfo};
-use audioipc::codec::LengthDelimitedCodec;
+use audioipc::capture;
+use audioipc::codec::{LengthDelimitedCodec, MessageLimit, MAX_CALLBACK_MESSAGE_LEN};
 use audioipc::frame::{framed, Framed};
 use audioipc::messages::{
-    CallbackReq, CallbackResp, ClientMessage, Device, DeviceCollectionReq, DeviceCollectionResp,
-    DeviceInfo, RegisterDeviceCollectionChanged, ServerMessage, StreamCreate, StreamCreateParams,
-    StreamInitParams, StreamParams,
+    AtomicCallbackStats, CallbackReq, CallbackResp, CallbackStats, ClientConnectParams,
+    ClientLimit, ClientMessage, ConnectionParams, Device, DeviceCollectionReq,
+    DeviceCollectionResp, DeviceInfo, RegisterDeviceCollectionChanged, ServerMessage, StreamCreate,
+    StreamCreateParams, StreamInitParams, StreamParams, FEATURE_SHM_DATA_SIGNAL,
+    FEATURE_SHM_RING_BUFFER, PROTOCOL_VERSION,
 };
 use audioipc::platformhandle_passing::FramedWithPlatformHandles;
+use audioipc::region::{Layout, Region};
+use audioipc::ringbuf;
 use audioipc::rpc;
-use audioipc::shm::SharedMem;
-use audioipc::{MessageStream, PlatformHandle};
+use audioipc::shm::Access;
+use audioipc::{MessageStream, PeerCredentials, PlatformHandle};
 use cubeb_core as cubeb;
 use cubeb_core::ffi;
-use futures::future::{self, FutureResult};
+use futures::future;
 use futures::sync::oneshot;
 use futures::Future;
 use std::convert::From;
-use std::ffi::CStr;
+use std::ffi::{CStr, CString};
 use std::mem::size_of;
+use std::ops::Deref;
 use std::os::raw::{c_long, c_void};
-use std::rc::Rc;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::{cell::RefCell, sync::Mutex};
-use std::{panic, slice};
+use std::path::{Path, PathBuf};
+use std::rc::{Rc, Weak};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{cell::RefCell, cmp};
+use std::{io, panic, slice, thread};
 use tokio::reactor;
 use tokio::runtime::current_thread;
 
 use crate::errors::*;
+use crate::fake;
+use crate::shm_pool::{ShmArea, ShmPool, ShmPoolLimits, ShmPoolStats};
+use crate::wav::AudioTap;
 
 fn error(error: cubeb::Error) -> ClientMessage {
     ClientMessage::Error(error.raw_code())
 }
 
+// Fans device collection changes reported by the thread's cubeb context out
+// to the clients subscribed to them.  Subscriptions are shared with the
+// cubeb thread delivering the changes, so must be thread-safe.
 struct CubebDeviceCollectionManager {
-    servers: Mutex<Vec<Rc<RefCell<CubebServerCallbacks>>>>,
+    servers: Mutex<Vec<Arc<Mutex<CubebServerCallbacks>>>>,
 }
 
 impl CubebDeviceCollectionManager {
@@ -52,37 +67,55 @@ impl CubebDeviceCollectionManager {
     fn register(
         &mut self,
         context: &cubeb::Context,
-        server: &Rc<RefCell<CubebServerCallbacks>>,
+        server: &Arc<Mutex<CubebServerCallbacks>>,
         devtype: cubeb::DeviceType,
     ) -> cubeb::Result<()> {
         let mut servers = self.servers.lock().unwrap();
         if servers.is_empty() {
             self.internal_register(context, true)?;
         }
-        server.borrow_mut().devtype.insert(devtype);
-        if servers.iter().find(|s| Rc::ptr_eq(s, server)).is_none() {
-            servers.push(server.clone());
-        }
+        Self::subscribe(&mut servers, server, devtype);
         Ok(())
     }
 
     fn unregister(
         &mut self,
         context: &cubeb::Context,
-        server: &Rc<RefCell<CubebServerCallbacks>>,
+        server: &Arc<Mutex<CubebServerCallbacks>>,
         devtype: cubeb::DeviceType,
     ) -> cubeb::Result<()> {
         let mut servers = self.servers.lock().unwrap();
-        server.borrow_mut().devtype.remove(devtype);
-        if server.borrow().devtype.is_empty() {
-            servers.retain(|s| !Rc::ptr_eq(&s, server));
-        }
-        if servers.is_empty() {
+        let was_empty = servers.is_empty();
+        Self::unsubscribe(&mut servers, server, devtype);
+        if servers.is_empty() && !was_empty {
             self.internal_register(context, false)?;
         }
         Ok(())
     }
 
+    fn subscribe(
+        servers: &mut Vec<Arc<Mutex<CubebServerCallbacks>>>,
+        server: &Arc<Mutex<CubebServerCallbacks>>,
+        devtype: cubeb::DeviceType,
+    ) {
+        server.lock().unwrap().devtype.insert(devtype);
+        if servers.iter().find(|s| Arc::ptr_eq(s, server)).is_none() {
+            servers.push(server.clone());
+        }
+    }
+
+    fn unsubscribe(
+        servers: &mut Vec<Arc<Mutex<CubebServerCallbacks>>>,
+        server: &Arc<Mutex<CubebServerCallbacks>>,
+        devtype: cubeb::DeviceType,
+    ) {
+        let mut cbs = server.lock().unwrap();
+        cbs.devtype.remove(devtype);
+        if cbs.devtype.is_empty() {
+            servers.retain(|s| !Arc::ptr_eq(&s, server));
+        }
+    }
+
     fn internal_register(&self, context: &cubeb::Context, enable: bool) -> cubeb::Result<()> {
         let user_ptr = if enable {
             self as *const CubebDeviceCollectionManager as *mut c_void
@@ -111,19 +144,15 @@ impl CubebDeviceCollectionManager {
     }
 
     // Warning: this is called from an internal cubeb thread, so we must not mutate unprotected shared state.
-    unsafe fn device_collection_changed_callback(&self, device_type: ffi::cubeb_device_type) {
+    fn device_collection_changed_callback(&self, device_type: ffi::cubeb_device_type) {
+        let devtype = cubeb::DeviceType::from_bits_truncate(device_type);
         let servers = self.servers.lock().unwrap();
-        servers.iter().for_each(|server| {
-            if server
-                .borrow()
-                .devtype
-                .contains(cubeb::DeviceType::from_bits_truncate(device_type))
-            {
-                server
-                    .borrow_mut()
-                    .device_collection_changed_callback(device_type)
+        for server in servers.iter() {
+            let cbs = server.lock().unwrap();
+            if cbs.devtype.intersects(devtype) {
+                cbs.device_collection_changed_callback(device_type & cbs.devtype.bits());
             }
-        });
+        }
     }
 }
 
@@ -158,28 +187,84 @@ impl DevIdMap {
     }
 
     // Given a handle produced by `to_handle`, return the associated
-    // cubeb_devid.  Invalid handles result in a panic.
-    fn from_handle(&self, handle: usize) -> usize {
-        self.devices[handle]
+    // cubeb_devid, or `None` for a handle that was never issued.
+    fn from_handle(&self, handle: usize) -> Option<usize> {
+        self.devices.get(handle).cloned()
+    }
+}
+
+// A context shared with the threads initializing streams.  cubeb allows
+// streams to be initialized from any thread, as Gecko does from its graph
+// threads.
+#[derive(Clone)]
+struct SharedContext(Arc<cubeb::Context>);
+
+unsafe impl Send for SharedContext {}
+
+impl SharedContext {
+    fn new(context: cubeb::Context) -> SharedContext {
+        SharedContext(Arc::new(context))
+    }
+}
+
+impl Deref for SharedContext {
+    type Target = cubeb::Context;
+
+    fn deref(&self) -> &cubeb::Context {
+        &self.0
     }
 }
 
 struct CubebContextState {
-    context: cubeb::Result<cubeb::Context>,
+    context: cubeb::Result<SharedContext>,
     manager: CubebDeviceCollectionManager,
 }
 
+// How the thread's context is created, set by the Server running on the
+// thread.
+#[derive(Clone)]
+pub(crate) struct CubebContextParams {
+    context_name: CString,
+    backend_name: Option<CString>,
+}
+
+impl CubebContextParams {
+    pub(crate) fn new(context_name: Option<&CStr>, backend_name: Option<&CStr>) -> Self {
+        CubebContextParams {
+            context_name: context_name
+                .map_or_else(|| CString::new("AudioIPC Server").unwrap(), CStr::to_owned),
+            backend_name: backend_name.map(CStr::to_owned),
+        }
+    }
+}
+
+thread_local!(static CONTEXT_PARAMS: RefCell<Option<CubebContextParams>> = RefCell::new(None));
 thread_local!(static CONTEXT_KEY: RefCell<Option<CubebContextState>> = RefCell::new(None));
 
+pub(crate) fn init_context_params(params: CubebContextParams) {
+    CONTEXT_PARAMS.with(|p| *p.borrow_mut() = Some(params));
+}
+
+/// Create the contexts of CubebServers on the current thread named
+/// `context_name` with the backend `backend_name`, as `Server::start` does
+/// for its own thread.
+#[cfg(feature = "fuzzing")]
+pub fn set_context_params(context_name: Option<&CStr>, backend_name: Option<&CStr>) {
+    init_context_params(CubebContextParams::new(context_name, backend_name));
+}
+
 fn cubeb_init_from_context_params() -> cubeb::Result<cubeb::Context> {
-    let params = super::G_CUBEB_CONTEXT_PARAMS.lock().unwrap();
+    let params = CONTEXT_PARAMS.with(|p| {
+        p.borrow_mut()
+            .get_or_insert_with(|| CubebContextParams::new(None, None))
+            .clone()
+    });
     let context_name = Some(params.context_name.as_c_str());
-    let backend_name = if let Some(ref name) = params.backend_name {
-        Some(name.as_c_str())
-    } else {
-        None
+    let backend_name = params.backend_name.as_ref().map(|name| name.as_c_str());
+    let r = match backend_name {
+        Some(name) if name.to_bytes() == fake::BACKEND_NAME.as_bytes() => fake::init(context_name),
+        _ => cubeb::Context::init(context_name, backend_name),
     };
-    let r = cubeb::Context::init(context_name, backend_name);
     r.map_err(|e| {
         info!("cubeb::Context::init failed r={:?}", e);
         e
@@ -188,20 +273,20 @@ fn cubeb_init_from_context_params() -> cubeb::Result<cubeb::Context> {
 
 fn with_local_context<T, F>(f: F) -> T
 where
-    F: FnOnce(&cubeb::Result<cubeb::Context>, &mut CubebDeviceCollectionManager) -> T,
+    F: FnOnce(&cubeb::Result<SharedContext>, &mut CubebDeviceCollectionManager) -> T,
 {
     CONTEXT_KEY.with(|k| {
         let mut state = k.borrow_mut();
         if state.is_none() {
             *state = Some(CubebContextState {
-                context: cubeb_init_from_context_params(),
+                context: cubeb_init_from_context_params().map(SharedContext::new),
                 manager: CubebDeviceCollectionManager::new(),
             });
         }
         let CubebContextState { context, manager } = state.as_mut().unwrap();
         // Always reattempt to initialize cubeb, OS config may have changed.
         if context.is_err() {
-            *context = cubeb_init_from_context_params();
+            *context = cubeb_init_from_context_params().map(SharedContext::new);
         }
         f(context, manager)
     })
@@ -212,8 +297,11 @@ struct DeviceCollectionClient;
 impl rpc::Client for DeviceCollectionClient {
     type Request = DeviceCollectionReq;
     type Response = DeviceCollectionResp;
-    type Transport =
-        Framed<audioipc::AsyncMessageStream, LengthDelimitedCodec<Self::Request, Self::Response>>;
+    type Transport = Framed<
+        audioipc::AsyncMessageStream,
+        LengthDelimitedCodec<rpc::Envelope<Self::Request>, rpc::Envelope<Self::Response>>,
+    >;
+    const MAX_MESSAGE_LEN: usize = MAX_CALLBACK_MESSAGE_LEN;
 }
 
 struct CallbackClient;
@@ -221,8 +309,11 @@ struct CallbackClient;
 impl rpc::Client for CallbackClient {
     type Request = CallbackReq;
     type Response = CallbackResp;
-    type Transport =
-        Framed<audioipc::AsyncMessageStream, LengthDelimitedCodec<Self::Request, Self::Response>>;
+    type Transport = Framed<
+        audioipc::AsyncMessageStream,
+        LengthDelimitedCodec<rpc::Envelope<Self::Request>, rpc::Envelope<Self::Response>>,
+    >;
+    const MAX_MESSAGE_LEN: usize = MAX_CALLBACK_MESSAGE_LEN;
 }
 
 struct ServerStreamCallbacks {
@@ -230,15 +321,149 @@ struct ServerStreamCallbacks {
     input_frame_size: u16,
     /// Size of output frame in bytes
     output_frame_size: u16,
-    /// Shared memory buffer for sending input data to client
-    input_shm: Option<SharedMem>,
-    /// Shared memory buffer for receiving output data from client
-    output_shm: Option<SharedMem>,
+    /// Shared memory region for sending input data to and receiving output
+    /// data from client.  Mapped by `ServerStream::shm`.
+    region: Region,
+    /// Ring buffer in the output section filled ahead by the client, if enabled
+    output_ring: Option<ringbuf::Consumer>,
     /// RPC interface to callback server running in client
     rpc: rpc::ClientProxy<CallbackReq, CallbackResp>,
+    /// Sample rate, used to derive the data callback deadline
+    rate: u32,
+    /// Activity reported in status queries
+    activity: Arc<StreamActivity>,
+    /// Number of consecutive data callbacks the client failed to answer in time
+    consecutive_misses: u32,
+    /// Consecutive misses after which the stream is put in the error state
+    max_callback_misses: Option<u32>,
+    /// Data callback the client failed to answer in time.  No further data
+    /// callback is requested until it has been answered, so the client's
+    /// callback never runs concurrently with itself nor writes output while
+    /// the server reads it.
+    outstanding: Option<OutstandingCallback>,
+    /// Records of the audio copied through the shared memory areas, if enabled
+    input_tap: Option<AudioTap>,
+    output_tap: Option<AudioTap>,
+}
+
+// A data callback requested of the client and not yet answered.
+enum OutstandingCallback {
+    Rpc(rpc::Response<CallbackResp>),
+    #[cfg(target_os = "linux")]
+    Signal(u32),
+}
+
+// The callbacks are called on cubeb's threads, and are handed to the thread
+// initializing their stream.
+unsafe impl Send for ServerStreamCallbacks {}
+
+// Queue `data` to be appended to the recording in `tap`, giving up on it
+// after an error.
+fn write_tap(tap: &mut Option<AudioTap>, data: &[u8]) {
+    let failed = match tap {
+        Some(tap) => !tap.write(data),
+        None => false,
+    };
+    if failed {
+        debug!("Failed to write audio tap, disabling");
+        *tap = None;
+    }
 }
 
 impl ServerStreamCallbacks {
+    // Fill `output` with silence, standing in for `nframes` frames the
+    // client didn't render, and record it like rendered output.
+    fn silence(&mut self, output: &mut [u8], nframes: isize) -> isize {
+        for b in output.iter_mut() {
+            *b = 0;
+        }
+        write_tap(&mut self.output_tap, output);
+        nframes
+    }
+
+    // The client must answer a data callback within the period it covers.
+    fn callback_deadline(&self, nframes: isize) -> Duration {
+        let period = Duration::from_micros(
+            (nframes.max(0) as u64).saturating_mul(1_000_000) / u64::from(self.rate.max(1)),
+        );
+        cmp::max(period, MIN_CALLBACK_DEADLINE)
+    }
+
+    // The client failed to answer a data callback in time.  Output silence
+    // rather than stall the backend, and give up on the stream after too
+    // many consecutive misses.
+    fn callback_deadline_missed(&mut self, output: &mut [u8], nframes: isize) -> isize {
+        self.activity.stats.record_missed_deadline();
+        self.consecutive_misses += 1;
+        debug!(
+            "Data callback missed deadline: {} consecutive",
+            self.consecutive_misses
+        );
+
+        if self
+            .max_callback_misses
+            .map_or(false, |max| self.consecutive_misses >= max)
+        {
+            warn!(
+                "Data callback missed {} consecutive deadlines, stopping stream",
+                self.consecutive_misses
+            );
+            self.consecutive_misses = 0;
+            self.activity.errored.store(true, Ordering::Relaxed);
+            self.activity.set_state(ffi::CUBEB_STATE_ERROR);
+            // Don't wait for the client, it's evidently not responding.
+            drop(self.rpc.call(CallbackReq::State(ffi::CUBEB_STATE_ERROR)));
+        }
+
+        self.silence(output, nframes)
+    }
+
+    // Ask the client for a data callback of `nframes` frames, once input has
+    // been written.  `None` if the client hasn't answered the last one,
+    // which `outstanding` should rule out.
+    fn request_data_callback(&self, nframes: isize) -> Option<OutstandingCallback> {
+        #[cfg(target_os = "linux")]
+        {
+            if self.region.layout().data_signal {
+                return self
+                    .region
+                    .signal_request(nframes as u32)
+                    .map(OutstandingCallback::Signal);
+            }
+        }
+        Some(OutstandingCallback::Rpc(self.rpc.call_with_timeout(
+            CallbackReq::Data {
+                nframes,
+                input_frame_size: self.input_frame_size as usize,
+                output_frame_size: self.output_frame_size as usize,
+            },
+            None,
+        )))
+    }
+
+    // Wait until `deadline` for the client to answer the outstanding data
+    // callback, answering as the callback rpc would.  The callback remains
+    // outstanding if it isn't answered in time.
+    fn wait_data_callback(&mut self, deadline: Instant) -> Option<io::Result<CallbackResp>> {
+        let r = match self.outstanding {
+            Some(OutstandingCallback::Rpc(ref mut response)) => response.wait_until(deadline),
+            #[cfg(target_os = "linux")]
+            Some(OutstandingCallback::Signal(seq)) => {
+                let timeout = deadline
+                    .checked_duration_since(Instant::now())
+                    .unwrap_or_default();
+                self.region
+                    .wait_response(seq, timeout)
+                    .map(|frames| Ok(CallbackResp::Data(frames as isize)))
+            }
+            None => return None,
+        };
+        if r.is_some() {
+            self.outstanding = None;
+        }
+        r
+    }
+
     fn data_callback(&mut self, input: &[u8], output: &mut [u8], nframes: isize) -> isize {
         trace!(
             "Stream data callback: {} {} {}",
@@ -246,32 +471,118 @@ impl ServerStreamCallbacks {
             input.len(),
             output.len()
         );
+        self.activity.stats.record_callback();
+
+        if let Some(ring) = &mut self.output_ring {
+            // Consume audio the client rendered ahead, without waiting on the client.
+            let nbytes = ring.pop(output);
+            if nbytes < output.len() {
+                if ring.is_drained() {
+                    write_tap(&mut self.output_tap, &output[..nbytes]);
+                    self.activity.stats.record_short_write();
+                    return (nbytes / self.output_frame_size as usize) as isize;
+                }
+                debug!(
+                    "Output ring buffer underrun: {} of {} bytes",
+                    nbytes,
+                    output.len()
+                );
+                self.activity.stats.record_missed_deadline();
+                for b in &mut output[nbytes..] {
+                    *b = 0;
+                }
+            }
+            write_tap(&mut self.output_tap, output);
+            return nframes;
+        }
 
-        unsafe {
-            if let Some(shm) = &mut self.input_shm {
-                shm.get_mut_slice(input.len())
-                    .unwrap()
-                    .copy_from_slice(input);
+        // Play silence until the stream is restarted, rather than drain it
+        // after reporting the error.
+        if self.activity.errored.load(Ordering::Relaxed) {
+            return self.silence(output, nframes);
+        }
+
+        // The client may still be working on a callback it failed to answer
+        // in time, and mustn't be asked for another until it's done.  A late
+        // answer is for a period already played as silence, so discarded.
+        if self.outstanding.is_some() {
+            match self.wait_data_callback(Instant::now()) {
+                Some(r) => debug!("Discarding late data callback response {:?}", r),
+                None => return self.callback_deadline_missed(output, nframes),
             }
         }
 
-        let r = self
-            .rpc
-            .call(CallbackReq::Data {
-                nframes,
-                input_frame_size: self.input_frame_size as usize,
-                output_frame_size: self.output_frame_size as usize,
-            })
-            .wait();
+        if let Some(mut section) = self.region.input() {
+            match unsafe { section.get_mut_slice(input.len()) } {
+                Ok(slice) => {
+                    slice.copy_from_slice(input);
+                    write_tap(&mut self.input_tap, input);
+                }
+                Err(_) => {
+                    debug!("Input of {} bytes exceeds shared memory area", input.len());
+                    self.activity.stats.record_error();
+                    // TODO: Return a CUBEB_ERROR result here once
+                    // https://github.com/kinetiknz/cubeb/issues/553 is
+                    // fixed.
+                    return 0;
+                }
+            }
+        }
+
+        if output.len() > self.region.layout().output_size {
+            debug!(
+                "Output of {} bytes exceeds shared memory area",
+                output.len()
+            );
+            self.activity.stats.record_error();
+            // TODO: Return a CUBEB_ERROR result here once
+            // https://github.com/kinetiknz/cubeb/issues/553 is
+            // fixed.
+            return 0;
+        }
+
+        let output_sequence = self.region.output_sequence();
+        let start = Instant::now();
+        self.outstanding = self.request_data_callback(nframes);
+        if self.outstanding.is_none() {
+            debug!("Data callback requested before the last was answered");
+            return self.callback_deadline_missed(output, nframes);
+        }
+        let r = match self.wait_data_callback(start + self.callback_deadline(nframes)) {
+            Some(r) => r,
+            None => return self.callback_deadline_missed(output, nframes),
+        };
 
         match r {
-            Ok(CallbackResp::Data(frames)) => {
+            Ok(CallbackResp::Data(frames)) if frames <= nframes => {
+                self.consecutive_misses = 0;
+                self.activity.stats.record_latency(start.elapsed());
+                if frames < 0 {
+                    self.activity.stats.record_error();
+                } else if frames < nframes {
+                    self.activity.stats.record_short_write();
+                }
                 if frames >= 0 {
                     let nbytes = frames as usize * self.output_frame_size as usize;
                     trace!("Reslice output to {}", nbytes);
-                    unsafe {
-                        if let Some(shm) = &self.output_shm {
-                            &mut output[..nbytes].copy_from_slice(shm.get_slice(nbytes).unwrap());
+                    if let Some(section) = self.region.output() {
+                        // The section still holds the previous callback's
+                        // output unless the client wrote it again.
+                        if frames > 0 && self.region.output_sequence() == output_sequence {
+                            debug!("Data callback answered without writing output");
+                            self.activity.stats.record_error();
+                            return 0;
+                        }
+                        match unsafe { section.get_slice(nbytes) } {
+                            Ok(slice) => {
+                                output[..nbytes].copy_from_slice(slice);
+                                write_tap(&mut self.output_tap, slice);
+                            }
+                            Err(_) => {
+                                debug!("Output of {} bytes exceeds shared memory area", nbytes);
+                                self.activity.stats.record_error();
+                                return 0;
+                            }
                         }
                     }
                 }
@@ -279,6 +590,7 @@ impl ServerStreamCallbacks {
             }
             _ => {
                 debug!("Unexpected message {:?} during data_callback", r);
+                self.activity.stats.record_error();
                 // TODO: Return a CUBEB_ERROR result here once
                 // https://github.com/kinetiknz/cubeb/issues/553 is
                 // fixed.
@@ -289,6 +601,7 @@ impl ServerStreamCallbacks {
 
     fn state_callback(&mut self, state: cubeb::State) {
         trace!("Stream state callback: {:?}", state);
+        self.activity.set_state(state.into());
         let r = self.rpc.call(CallbackReq::State(state.into())).wait();
         match r {
             Ok(CallbackResp::State) => {}
@@ -310,8 +623,115 @@ impl ServerStreamCallbacks {
     }
 }
 
+// What a stream's callbacks have seen, recorded on the backend's threads
+// for status queries.
+struct StreamActivity {
+    // Last state reported by the backend, or `NO_STATE`.
+    state: AtomicUsize,
+    // Set once the client missed too many data callbacks, until the stream
+    // is restarted.
+    errored: AtomicBool,
+    stats: AtomicCallbackStats,
+}
+
+const NO_STATE: usize = usize::max_value();
+
+impl StreamActivity {
+    fn new() -> StreamActivity {
+        StreamActivity {
+            state: AtomicUsize::new(NO_STATE),
+            errored: AtomicBool::new(false),
+            stats: AtomicCallbackStats::default(),
+        }
+    }
+
+    fn stats(&self) -> CallbackStats {
+        self.stats.snapshot()
+    }
+
+    fn set_state(&self, state: ffi::cubeb_state) {
+        self.state.store(state as usize, Ordering::Relaxed);
+    }
+
+    fn state(&self) -> Option<&'static str> {
+        match self.state.load(Ordering::Relaxed) {
+            NO_STATE => None,
+            s if s == ffi::CUBEB_STATE_STARTED as usize => Some("started"),
+            s if s == ffi::CUBEB_STATE_STOPPED as usize => Some("stopped"),
+            s if s == ffi::CUBEB_STATE_DRAINED as usize => Some("drained"),
+            _ => Some("error"),
+        }
+    }
+}
+
 static SHM_ID: AtomicUsize = AtomicUsize::new(0);
 
+// Lower bound on the time a client has to answer a data callback.
+const MIN_CALLBACK_DEADLINE: Duration = Duration::from_millis(1);
+
+// Backends don't report the largest callback they may make, so size the
+// shared memory areas for callbacks of up to this many times the requested
+// latency, and at least `SHM_MIN_CALLBACK_FRAMES` frames.
+const SHM_LATENCY_MULTIPLIER: u32 = 4;
+const SHM_MIN_CALLBACK_FRAMES: u32 = 8192;
+
+// Size of the shared memory area needed for callbacks on a stream with
+// `params`, or `None` if the stream would exceed `MAX_SHM_AREA_SIZE`.
+fn shm_area_size(params: &StreamParams, latency_frames: u32) -> Option<usize> {
+    let frames = cmp::max(
+        latency_frames.saturating_mul(SHM_LATENCY_MULTIPLIER),
+        SHM_MIN_CALLBACK_FRAMES,
+    );
+    params
+        .area_size(frames)
+        .filter(|&size| size > 0 && size <= audioipc::MAX_SHM_AREA_SIZE)
+}
+
+// Clients may be compromised, so everything they send is validated before
+// it reaches cubeb.  The bounds on stream parameters are generous; backends
+// reject anything the hardware can't do.
+const MAX_CHANNELS: u32 = 256;
+const MAX_RATE: u32 = 768_000;
+
+fn validate_stream_params(params: &StreamParams) -> cubeb::Result<()> {
+    if params.frame_size().is_none()
+        || params.channels == 0
+        || params.channels > MAX_CHANNELS
+        || params.rate == 0
+        || params.rate > MAX_RATE
+    {
+        warn!("Invalid stream params from client: {:?}", params);
+        return Err(cubeb::Error::invalid_parameter());
+    }
+    Ok(())
+}
+
+fn validate_stream_create_params(params: &StreamCreateParams) -> cubeb::Result<()> {
+    if params.input_stream_params.is_none() && params.output_stream_params.is_none() {
+        warn!("Stream with neither input nor output requested");
+        return Err(cubeb::Error::invalid_parameter());
+    }
+    for p in params
+        .input_stream_params
+        .iter()
+        .chain(params.output_stream_params.iter())
+    {
+        validate_stream_params(p)?;
+    }
+    Ok(())
+}
+
+// Only the input and output bits are meaningful, and at least one is needed.
+fn validate_device_type(device_type: ffi::cubeb_device_type) -> cubeb::Result<cubeb::DeviceType> {
+    match cubeb::DeviceType::from_bits(device_type) {
+        Some(devtype) if !devtype.is_empty() => Ok(devtype),
+        _ => {
+            warn!("Invalid device type from client: {:#x}", device_type);
+            Err(cubeb::Error::invalid_parameter())
+        }
+    }
+}
+
 // Generate a temporary shm_id fragment that is unique to the process.  This
 // path is used temporarily to create a shm segment, which is then
 // immediately deleted from the filesystem while retaining handles to the
@@ -326,7 +746,21 @@ fn get_shm_id() -> String {
 
 struct ServerStream {
     stream: Option<cubeb::Stream>,
-    cbs: Box<ServerStreamCallbacks>,
+    // Held by the initializing thread while `init` is pending.
+    cbs: Option<Box<ServerStreamCallbacks>>,
+    // Receives the stream and its callbacks from the thread initializing it.
+    init: Option<oneshot::Receiver<InitializedStream>>,
+    // Mapping of the region used by `cbs`.  Taken when the stream is
+    // destroyed, to be recycled.
+    shm: Option<ShmArea>,
+    // As requested in StreamCreate, for status queries.
+    input_params: Option<StreamParams>,
+    output_params: Option<StreamParams>,
+    latency_frames: u32,
+    input_shm_size: usize,
+    output_shm_size: usize,
+    shm_size: usize,
+    activity: Arc<StreamActivity>,
 }
 
 impl Drop for ServerStream {
@@ -338,65 +772,282 @@ impl Drop for ServerStream {
 
 type StreamSlab = slab::Slab<ServerStream>;
 
+// Result of initializing a stream off the RPC thread.  `stream` is `None` if
+// initialization failed.
+struct InitializedStream {
+    // `stream` *must* be dropped before `cbs`.
+    stream: Option<cubeb::Stream>,
+    cbs: Box<ServerStreamCallbacks>,
+}
+
+unsafe impl Send for InitializedStream {}
+
+type ClientResponse = Box<dyn Future<Item = ClientMessage, Error = ()>>;
+
 struct CubebServerCallbacks {
     rpc: rpc::ClientProxy<DeviceCollectionReq, DeviceCollectionResp>,
     devtype: cubeb::DeviceType,
 }
 
 impl CubebServerCallbacks {
-    fn device_collection_changed_callback(&mut self, device_type: ffi::cubeb_device_type) {
-        // TODO: Assert device_type is in devtype.
+    fn device_collection_changed_callback(&self, device_type: ffi::cubeb_device_type) {
+        debug_assert!(self
+            .devtype
+            .contains(cubeb::DeviceType::from_bits_truncate(device_type)));
         debug!(
             "Sending device collection ({:?}) changed event",
             device_type
         );
-        let _ = self
-            .rpc
-            .call(DeviceCollectionReq::DeviceChange(device_type))
-            .wait();
+        // Don't wait for the client.  This runs on a cubeb thread with the
+        // manager locked, and a slow client mustn't hold up the others.
+        drop(
+            self.rpc
+                .call(DeviceCollectionReq::DeviceChange(device_type)),
+        );
+    }
+}
+
+/// Server behaviour that can be tuned by the embedder.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ServerConfig {
+    /// Number of consecutive data callbacks a client may fail to answer
+    /// within the callback period before its stream is put in the error
+    /// state.  `None` outputs silence for as long as the client is late.
+    pub max_callback_misses: Option<u32>,
+    /// Consulted once a client has connected.  Clients for which it returns
+    /// false are answered with `ClientMessage::ClientRejected`.  `None`
+    /// accepts every client.
+    pub accept_client: Option<fn(ClientIdentity) -> bool>,
+    pub limits: ClientLimits,
+    /// Shared memory kept from each client's destroyed streams for reuse.
+    pub shm_pool: ShmPoolLimits,
+}
+
+/// Resources each client may use.  Requests that would exceed a limit are
+/// answered with `ClientMessage::LimitExceeded`.  `None` leaves a resource
+/// unlimited.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientLimits {
+    /// Streams open at once.
+    pub max_streams: Option<usize>,
+    /// Combined size in bytes of the shared memory regions of open streams.
+    pub max_shm_size: Option<usize>,
+    /// `ContextSetupDeviceCollectionCallback` requests over the life of the
+    /// connection.  Each creates a new callback connection, replacing the
+    /// previous one.
+    pub max_device_collection_setups: Option<u32>,
+    /// Requests in any one second window.  `ClientDisconnect` and
+    /// `StreamDestroy` are neither counted nor refused.
+    pub max_messages_per_second: Option<u32>,
+}
+
+impl Default for ClientLimits {
+    fn default() -> Self {
+        ClientLimits {
+            max_streams: Some(128),
+            max_shm_size: Some(256 * 1024 * 1024),
+            max_device_collection_setups: Some(64),
+            max_messages_per_second: Some(2000),
+        }
+    }
+}
+
+/// The process on the other end of a client connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClientIdentity {
+    pub pid: u32,
+    /// Reported by the operating system, if available.
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Whether `pid` was confirmed by the operating system.  When false it
+    /// is only what the client claimed in `ClientConnect`.
+    pub verified: bool,
+}
+
+impl ClientIdentity {
+    // Combine the pid claimed by a client with the credentials the
+    // operating system reported for the sender of its `ClientConnect`.
+    // Returns `None` if the two disagree.
+    fn new(claimed_pid: u32, peer: Option<PeerCredentials>) -> Option<ClientIdentity> {
+        let unverified = ClientIdentity {
+            pid: claimed_pid,
+            uid: None,
+            gid: None,
+            verified: false,
+        };
+        let peer = match peer {
+            Some(peer) => peer,
+            None => return Some(unverified),
+        };
+        match peer.pid {
+            Some(pid) if pid != claimed_pid => None,
+            Some(pid) => Some(ClientIdentity {
+                pid,
+                uid: Some(peer.uid),
+                gid: Some(peer.gid),
+                verified: true,
+            }),
+            None => Some(unverified),
+        }
     }
 }
 
+/// Snapshot of a client connection, for diagnostics.
+#[derive(Debug, Serialize)]
+pub struct ClientStatus {
+    /// `None` until the client completes the handshake.
+    pub pid: Option<u32>,
+    pub pid_verified: bool,
+    pub streams: Vec<StreamStatus>,
+    /// Device collection changes the client is subscribed to.
+    pub input_device_collection_changed: bool,
+    pub output_device_collection_changed: bool,
+    pub shm_pool: ShmPoolStats,
+    /// Threads the client asked to have promoted to real-time.
+    pub promoted_threads: u32,
+}
+
+/// Snapshot of a client's stream, for diagnostics.
+#[derive(Debug, Serialize)]
+pub struct StreamStatus {
+    pub token: usize,
+    pub input_params: Option<StreamParams>,
+    pub output_params: Option<StreamParams>,
+    pub latency_frames: u32,
+    pub initialized: bool,
+    /// Last state reported by the backend.
+    pub state: Option<&'static str>,
+    /// As reported by cubeb, if the stream is initialized and the backend
+    /// supports the query.
+    pub latency: Option<u32>,
+    pub position: Option<u64>,
+    pub stats: CallbackStats,
+    /// Sizes of the sections of the stream's shared memory region, and of
+    /// the whole region.
+    pub input_shm_size: usize,
+    pub output_shm_size: usize,
+    pub shm_size: usize,
+}
+
 pub struct CubebServer {
     handle: current_thread::Handle,
+    config: ServerConfig,
     streams: StreamSlab,
-    remote_pid: Option<u32>,
-    cbs: Option<Rc<RefCell<CubebServerCallbacks>>>,
+    identity: Option<ClientIdentity>,
+    connection: Option<ConnectionParams>,
+    device_collection_setups: u32,
+    promoted_threads: u32,
+    rate_window_start: Instant,
+    rate_window_messages: u32,
+    cbs: Option<Arc<Mutex<CubebServerCallbacks>>>,
     devidmap: DevIdMap,
+    capture: Option<Arc<capture::Writer>>,
+    audio_tap_dir: Option<PathBuf>,
+    shm_pool: ShmPool,
+    // Shared with the connection's codec, to apply the negotiated limit.
+    message_limit: Option<MessageLimit>,
+}
+
+impl Drop for CubebServer {
+    fn drop(&mut self) {
+        if self.cbs.is_some() {
+            with_local_context(|context, manager| {
+                if let Ok(ref context) = *context {
+                    self.unsubscribe_device_collection(context, manager);
+                }
+            });
+        }
+    }
 }
 
 impl rpc::Server for CubebServer {
     type Request = ServerMessage;
     type Response = ClientMessage;
-    type Future = FutureResult<Self::Response, ()>;
+    type Future = ClientResponse;
     type Transport = FramedWithPlatformHandles<
         audioipc::AsyncMessageStream,
-        LengthDelimitedCodec<Self::Response, Self::Request>,
+        LengthDelimitedCodec<rpc::Envelope<Self::Response>, rpc::Envelope<Self::Request>>,
     >;
 
     fn process(&mut self, req: Self::Request) -> Self::Future {
-        if let ServerMessage::ClientConnect(pid) = req {
-            self.remote_pid = Some(pid);
-        }
-        let resp = with_local_context(|context, manager| match *context {
-            Err(_) => error(cubeb::Error::error()),
-            Ok(ref context) => self.process_msg(context, manager, &req),
+        let resp = match req {
+            ServerMessage::ClientConnect(ref params) => self.process_client_connect(params),
+            _ if self.connection.is_none() => {
+                warn!("Client sent {:?} before completing the handshake", req);
+                error(cubeb::Error::error())
+            }
+            _ if !self.within_message_rate(&req) => self.limit_exceeded(ClientLimit::MessageRate),
+            ServerMessage::StreamInit(stm_tok, ref params) => {
+                return with_local_context(|context, _| -> ClientResponse {
+                    match *context {
+                        Err(_) => Box::new(future::ok(error(cubeb::Error::error()))),
+                        Ok(ref context) => self.process_stream_init(context, stm_tok, params),
+                    }
+                });
+            }
+            _ => with_local_context(|context, manager| match *context {
+                Err(_) => error(cubeb::Error::error()),
+                Ok(ref context) => self.process_msg(context, manager, &req),
+            }),
+        };
+        Box::new(future::ok(resp))
+    }
+}
+
+thread_local!(static CLIENTS: RefCell<Vec<Weak<RefCell<CubebServer>>>> = RefCell::new(Vec::new()));
+
+/// A `CubebServer` that can be found by `clients_status` on the thread
+/// serving it.
+pub struct RegisteredCubebServer(Rc<RefCell<CubebServer>>);
+
+impl RegisteredCubebServer {
+    /// Must be called on the thread that will serve the client.
+    pub fn new(server: CubebServer) -> Self {
+        let server = Rc::new(RefCell::new(server));
+        CLIENTS.with(|clients| {
+            let mut clients = clients.borrow_mut();
+            clients.retain(|client| client.strong_count() > 0);
+            clients.push(Rc::downgrade(&server));
         });
-        future::ok(resp)
+        RegisteredCubebServer(server)
+    }
+}
+
+impl rpc::Server for RegisteredCubebServer {
+    type Request = <CubebServer as rpc::Server>::Request;
+    type Response = <CubebServer as rpc::Server>::Response;
+    type Future = <CubebServer as rpc::Server>::Future;
+    type Transport = <CubebServer as rpc::Server>::Transport;
+
+    fn process(&mut self, req: Self::Request) -> Self::Future {
+        self.0.borrow_mut().process(req)
     }
 }
 
+/// Status of the clients served on the current thread.
+pub fn clients_status() -> Vec<ClientStatus> {
+    CLIENTS.with(|clients| {
+        clients
+            .borrow()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|server| server.borrow().status())
+            .collect()
+    })
+}
+
 // Debugging for BMO 1594216/1612044.
 macro_rules! try_stream {
     ($self:expr, $stm_tok:expr) => {
-        if $self.streams.contains($stm_tok) {
-            $self.streams[$stm_tok]
-                .stream
-                .as_mut()
-                .expect("uninitialized stream")
+        if let Some(stream) = $self
+            .streams
+            .get_mut($stm_tok)
+            .and_then(|s| s.stream.as_mut())
+        {
+            stream
         } else {
             error!(
-                "{}:{}:{} - Stream({}): invalid token",
+                "{}:{}:{} - Stream({}): invalid token or uninitialized stream",
                 file!(),
                 line!(),
                 column!(),
@@ -408,14 +1059,174 @@ macro_rules! try_stream {
 }
 
 impl CubebServer {
-    pub fn new(handle: current_thread::Handle) -> Self {
+    pub fn new(handle: current_thread::Handle, config: ServerConfig) -> Self {
         CubebServer {
             handle,
+            config,
             streams: StreamSlab::new(),
-            remote_pid: None,
+            identity: None,
+            connection: None,
+            device_collection_setups: 0,
+            promoted_threads: 0,
+            rate_window_start: Instant::now(),
+            rate_window_messages: 0,
             cbs: None,
             devidmap: DevIdMap::new(),
+            capture: None,
+            audio_tap_dir: None,
+            shm_pool: ShmPool::new(config.shm_pool),
+            message_limit: None,
+        }
+    }
+
+    /// Apply the message size limit negotiated with the client to the
+    /// connection's codec, which must have been created with `limit`.
+    pub fn set_message_limit(&mut self, limit: MessageLimit) {
+        self.message_limit = Some(limit);
+    }
+
+    /// Record the audio of the client's streams to WAV files in `dir`.
+    pub fn set_audio_tap(&mut self, dir: &Path) {
+        self.audio_tap_dir = Some(dir.to_owned());
+    }
+
+    // Start recording a direction of stream `token`, if audio taps are
+    // enabled.
+    fn audio_tap(
+        &self,
+        token: usize,
+        direction: &str,
+        params: Option<&StreamParams>,
+    ) -> Option<AudioTap> {
+        let (dir, params) = match (&self.audio_tap_dir, params) {
+            (Some(dir), Some(params)) => (dir, params),
+            _ => return None,
+        };
+        let pid = self.identity.map_or(0, |identity| identity.pid);
+        let path = dir.join(format!("{}-{}-{}.wav", pid, token, direction));
+        match AudioTap::create(&path, params) {
+            Ok(tap) => Some(tap),
+            Err(e) => {
+                warn!("Failed to create audio tap {:?}: {:?}", path, e);
+                None
+            }
+        }
+    }
+
+    /// Record the callback requests sent on the client's streams to `capture`.
+    pub fn set_capture(&mut self, capture: Arc<capture::Writer>) {
+        self.capture = Some(capture);
+    }
+
+    // The handshake must be the first message on a connection and is
+    // processed before cubeb is initialized.
+    fn process_client_connect(&mut self, params: &ClientConnectParams) -> ClientMessage {
+        if self.connection.is_some() {
+            warn!("Client {} sent a duplicate ClientConnect", params.pid);
+            return error(cubeb::Error::error());
+        }
+
+        let identity = match ClientIdentity::new(params.pid, params.peer) {
+            Some(identity) => identity,
+            None => {
+                warn!(
+                    "Rejecting client claiming pid {}: sent by {:?}",
+                    params.pid, params.peer
+                );
+                return ClientMessage::ClientRejected(PROTOCOL_VERSION);
+            }
+        };
+
+        if let Some(accept) = self.config.accept_client {
+            if !accept(identity) {
+                warn!("Rejecting client {:?}: refused by policy", identity);
+                return ClientMessage::ClientRejected(PROTOCOL_VERSION);
+            }
+        }
+
+        match ConnectionParams::negotiate(params) {
+            Some(connection) => {
+                debug!(
+                    "Client {:?} connected: {:?} (requested {:?})",
+                    identity, connection, params
+                );
+                self.identity = Some(identity);
+                self.connection = Some(connection);
+                if let Some(ref limit) = self.message_limit {
+                    limit.set(connection.max_message_len as usize);
+                }
+                ClientMessage::ClientConnected(connection)
+            }
+            None => {
+                warn!(
+                    "Rejecting client {}: protocol version {} (server speaks {})",
+                    params.pid, params.protocol_version, PROTOCOL_VERSION
+                );
+                ClientMessage::ClientRejected(PROTOCOL_VERSION)
+            }
+        }
+    }
+
+    fn status(&self) -> ClientStatus {
+        let devtype = self.cbs.as_ref().map_or(cubeb::DeviceType::empty(), |cbs| {
+            cbs.lock().unwrap().devtype
+        });
+        let streams = self
+            .streams
+            .iter()
+            .map(|(token, s)| StreamStatus {
+                token,
+                input_params: s.input_params,
+                output_params: s.output_params,
+                latency_frames: s.latency_frames,
+                initialized: s.stream.is_some(),
+                state: s.activity.state(),
+                latency: s.stream.as_ref().and_then(|stream| stream.latency().ok()),
+                position: s.stream.as_ref().and_then(|stream| stream.position().ok()),
+                stats: s.activity.stats(),
+                input_shm_size: s.input_shm_size,
+                output_shm_size: s.output_shm_size,
+                shm_size: s.shm_size,
+            })
+            .collect();
+        ClientStatus {
+            pid: self.identity.map(|identity| identity.pid),
+            pid_verified: self.identity.map_or(false, |identity| identity.verified),
+            streams,
+            input_device_collection_changed: devtype.contains(cubeb::DeviceType::INPUT),
+            output_device_collection_changed: devtype.contains(cubeb::DeviceType::OUTPUT),
+            shm_pool: self.shm_pool.stats(),
+            promoted_threads: self.promoted_threads,
+        }
+    }
+
+    fn limit_exceeded(&self, limit: ClientLimit) -> ClientMessage {
+        warn!(
+            "Client {} exceeded its {:?} limit",
+            self.identity.map_or(0, |identity| identity.pid),
+            limit
+        );
+        ClientMessage::LimitExceeded(limit)
+    }
+
+    // Count a request against the message rate limit.  Returns false if it
+    // exceeds the limit.  Teardown is exempt, so a client over the limit can
+    // still release what it holds.
+    fn within_message_rate(&mut self, msg: &ServerMessage) -> bool {
+        if let ServerMessage::ClientDisconnect | ServerMessage::StreamDestroy(_) = *msg {
+            return true;
+        }
+        let max = match self.config.limits.max_messages_per_second {
+            Some(max) => max,
+            None => return true,
+        };
+        let now = Instant::now();
+        if now.duration_since(self.rate_window_start) >= Duration::from_secs(1) {
+            self.rate_window_start = now;
+            self.rate_window_messages = 0;
         }
+        self.rate_window_messages = self.rate_window_messages.saturating_add(1);
+        self.rate_window_messages <= max
     }
 
     // Process a request coming from the client.
@@ -425,11 +1236,11 @@ impl CubebServer {
         manager: &mut CubebDeviceCollectionManager,
         msg: &ServerMessage,
     ) -> ClientMessage {
+        self.finish_stream_inits();
+
         let resp: ClientMessage = match *msg {
             ServerMessage::ClientConnect(_) => {
-                // remote_pid is set before cubeb initialization, just verify here.
-                assert!(self.remote_pid.is_some());
-                ClientMessage::ClientConnected
+                unreachable!("ClientConnect is handled by process_client_connect")
             }
 
             ServerMessage::ClientDisconnect => {
@@ -448,6 +1259,9 @@ impl CubebServer {
                 .unwrap_or_else(error),
 
             ServerMessage::ContextGetMinLatency(ref params) => {
+                if let Err(e) = validate_stream_params(params) {
+                    return error(e);
+                }
                 let format = cubeb::SampleFormat::from(params.format);
                 let layout = cubeb::ChannelLayout::from(params.layout);
 
@@ -469,34 +1283,41 @@ impl CubebServer {
                 .map(ClientMessage::ContextPreferredSampleRate)
                 .unwrap_or_else(error),
 
-            ServerMessage::ContextGetDeviceEnumeration(device_type) => context
-                .enumerate_devices(cubeb::DeviceType::from_bits_truncate(device_type))
-                .map(|devices| {
-                    let v: Vec<DeviceInfo> = devices
-                        .iter()
-                        .map(|i| {
-                            let mut tmp: DeviceInfo = i.as_ref().into();
-                            // Replace each cubeb_devid with a unique handle suitable for IPC.
-                            tmp.devid = self.devidmap.to_handle(tmp.devid);
-                            tmp
-                        })
-                        .collect();
-                    ClientMessage::ContextEnumeratedDevices(v)
-                })
-                .unwrap_or_else(error),
+            ServerMessage::ContextGetDeviceEnumeration(device_type) => {
+                validate_device_type(device_type)
+                    .and_then(|devtype| context.enumerate_devices(devtype))
+                    .map(|devices| {
+                        let v: Vec<DeviceInfo> = devices
+                            .iter()
+                            .map(|i| {
+                                let mut tmp: DeviceInfo = i.as_ref().into();
+                                // Replace each cubeb_devid with a unique handle suitable for IPC.
+                                tmp.devid = self.devidmap.to_handle(tmp.devid);
+                                tmp
+                            })
+                            .collect();
+                        ClientMessage::ContextEnumeratedDevices(v)
+                    })
+                    .unwrap_or_else(error)
+            }
 
-            ServerMessage::StreamCreate(ref params) => self
-                .process_stream_create(params)
-                .unwrap_or_else(|_| error(cubeb::Error::error())),
+            ServerMessage::StreamCreate(ref params) => {
+                match validate_stream_create_params(params) {
+                    Ok(()) => self
+                        .process_stream_create(params)
+                        .unwrap_or_else(|_| error(cubeb::Error::error())),
+                    Err(e) => error(e),
+                }
+            }
 
-            ServerMessage::StreamInit(stm_tok, ref params) => self
-                .process_stream_init(context, stm_tok, params)
-                .unwrap_or_else(|_| error(cubeb::Error::error())),
+            ServerMessage::StreamInit(..) => {
+                unreachable!("StreamInit is handled by process_stream_init")
+            }
 
             ServerMessage::StreamDestroy(stm_tok) => {
                 if self.streams.contains(stm_tok) {
                     debug!("Unregistering stream {:?}", stm_tok);
-                    self.streams.remove(stm_tok);
+                    self.remove_stream(stm_tok);
                 } else {
                     // Debugging for BMO 1594216/1612044.
                     error!("StreamDestroy({}): invalid token", stm_tok);
@@ -505,15 +1326,30 @@ impl CubebServer {
                 ClientMessage::StreamDestroyed
             }
 
-            ServerMessage::StreamStart(stm_tok) => try_stream!(self, stm_tok)
-                .start()
-                .map(|_| ClientMessage::StreamStarted)
-                .unwrap_or_else(error),
+            ServerMessage::StreamStart(stm_tok) => {
+                // A stream given up on after missed callbacks gets another
+                // chance when restarted.
+                if let Some(s) = self.streams.get(stm_tok) {
+                    s.activity.errored.store(false, Ordering::Relaxed);
+                }
+                try_stream!(self, stm_tok)
+                    .start()
+                    .map(|_| ClientMessage::StreamStarted)
+                    .unwrap_or_else(error)
+            }
 
-            ServerMessage::StreamStop(stm_tok) => try_stream!(self, stm_tok)
-                .stop()
-                .map(|_| ClientMessage::StreamStopped)
-                .unwrap_or_else(error),
+            ServerMessage::StreamStop(stm_tok) => {
+                let r = try_stream!(self, stm_tok).stop();
+                // Audio rendered ahead before the stop mustn't be played
+                // when the stream is restarted.  The client has stopped
+                // rendering by now.
+                let cbs = self.streams[stm_tok].cbs.as_mut();
+                if let Some(ring) = cbs.and_then(|cbs| cbs.output_ring.as_mut()) {
+                    ring.discard();
+                }
+                r.map(|_| ClientMessage::StreamStopped)
+                    .unwrap_or_else(error)
+            }
 
             ServerMessage::StreamGetPosition(stm_tok) => try_stream!(self, stm_tok)
                 .position()
@@ -556,7 +1392,22 @@ impl CubebServer {
                     .unwrap_or_else(error)
             }
 
+            ServerMessage::StreamGetStats(stm_tok) => match self.streams.get(stm_tok) {
+                Some(s) => ClientMessage::StreamStats(s.activity.stats()),
+                None => error(cubeb::Error::invalid_parameter()),
+            },
+
             ServerMessage::ContextSetupDeviceCollectionCallback => {
+                if let Some(max) = self.config.limits.max_device_collection_setups {
+                    if self.device_collection_setups >= max {
+                        return self.limit_exceeded(ClientLimit::DeviceCollectionSetups);
+                    }
+                }
+                self.device_collection_setups += 1;
+
+                // A repeated setup replaces the previous callback connection.
+                self.unsubscribe_device_collection(context, manager);
+
                 if let Ok((ipc_server, ipc_client)) = MessageStream::anonymous_ipc_pair() {
                     debug!(
                         "Created device collection RPC pair: {:?}-{:?}",
@@ -572,29 +1423,22 @@ impl CubebServer {
                         .spawn(futures::future::lazy(move || {
                             let handle = reactor::Handle::default();
                             let stream = ipc_server.into_tokio_ipc(&handle).unwrap();
-                            let transport = framed(stream, Default::default());
+                            let transport =
+                                framed(stream, rpc::client_codec::<DeviceCollectionClient>());
                             let rpc = rpc::bind_client::<DeviceCollectionClient>(transport);
                             drop(tx.send(rpc));
                             Ok(())
                         }))
                         .expect("Failed to spawn DeviceCollectionClient");
 
-                    // TODO: The lowest comms layer expects exactly 3 PlatformHandles, but we only
-                    // need one here.  Send some dummy handles over for the other side to discard.
-                    let (dummy1, dummy2) =
-                        MessageStream::anonymous_ipc_pair().expect("need dummy IPC pair");
                     if let Ok(rpc) = rx.wait() {
-                        self.cbs = Some(Rc::new(RefCell::new(CubebServerCallbacks {
+                        self.cbs = Some(Arc::new(Mutex::new(CubebServerCallbacks {
                             rpc,
                             devtype: cubeb::DeviceType::empty(),
                         })));
                         let fds = RegisterDeviceCollectionChanged {
-                            platform_handles: [
-                                PlatformHandle::from(ipc_client),
-                                PlatformHandle::from(dummy1),
-                                PlatformHandle::from(dummy2),
-                            ],
-                            target_pid: self.remote_pid.unwrap(),
+                            platform_handles: vec![PlatformHandle::from(ipc_client)],
+                            target_pid: self.identity.unwrap().pid,
                         };
 
                         ClientMessage::ContextSetupDeviceCollectionCallback(fds)
@@ -608,17 +1452,19 @@ impl CubebServer {
                 }
             }
 
-            ServerMessage::ContextRegisterDeviceCollectionChanged(device_type, enable) => self
-                .process_register_device_collection_changed(
-                    context,
-                    manager,
-                    cubeb::DeviceType::from_bits_truncate(device_type),
-                    enable,
-                )
-                .unwrap_or_else(error),
+            ServerMessage::ContextRegisterDeviceCollectionChanged(device_type, enable) => {
+                validate_device_type(device_type)
+                    .and_then(|devtype| {
+                        self.process_register_device_collection_changed(
+                            context, manager, devtype, enable,
+                        )
+                    })
+                    .unwrap_or_else(error)
+            }
 
             #[cfg(target_os = "linux")]
             ServerMessage::PromoteThreadToRealTime(thread_info) => {
+                self.promoted_threads = self.promoted_threads.saturating_add(1);
                 let info = RtPriorityThreadInfo::deserialize(thread_info);
                 match promote_thread_to_real_time(info, 0, 48000) {
                     Ok(_) => {
@@ -644,12 +1490,13 @@ impl CubebServer {
         devtype: cubeb::DeviceType,
         enable: bool,
     ) -> cubeb::Result<ClientMessage> {
-        if devtype == cubeb::DeviceType::UNKNOWN {
-            return Err(cubeb::Error::invalid_parameter());
-        }
-
-        assert!(self.cbs.is_some());
-        let cbs = self.cbs.as_ref().unwrap();
+        let cbs = match self.cbs {
+            Some(ref cbs) => cbs,
+            None => {
+                warn!("Device collection callback registered before setup");
+                return Err(cubeb::Error::error());
+            }
+        };
 
         if enable {
             manager.register(context, cbs, devtype)
@@ -659,8 +1506,30 @@ impl CubebServer {
         .map(|_| ClientMessage::ContextRegisteredDeviceCollectionChanged)
     }
 
+    // Drop all of this client's device collection subscriptions.
+    fn unsubscribe_device_collection(
+        &mut self,
+        context: &cubeb::Context,
+        manager: &mut CubebDeviceCollectionManager,
+    ) {
+        if let Some(cbs) = self.cbs.take() {
+            let devtype = cbs.lock().unwrap().devtype;
+            if !devtype.is_empty() {
+                if let Err(e) = manager.unregister(context, &cbs, devtype) {
+                    warn!("Failed to unregister device collection callback: {:?}", e);
+                }
+            }
+        }
+    }
+
     // Stream create is special, so it's been separated from process_msg.
     fn process_stream_create(&mut self, params: &StreamCreateParams) -> Result<ClientMessage> {
+        if let Some(max) = self.config.limits.max_streams {
+            if self.streams.len() >= max {
+                return Ok(self.limit_exceeded(ClientLimit::Streams));
+            }
+        }
+
         fn frame_size_in_bytes(params: Option<&StreamParams>) -> u16 {
             params
                 .map(|p| {
@@ -682,26 +1551,110 @@ impl CubebServer {
         // Create the callback handling struct which is attached the cubeb stream.
         let input_frame_size = frame_size_in_bytes(params.input_stream_params.as_ref());
         let output_frame_size = frame_size_in_bytes(params.output_stream_params.as_ref());
+        let rate = params
+            .output_stream_params
+            .or(params.input_stream_params)
+            .map_or(0, |p| p.rate);
+
+        let input_shm_size = match params.input_stream_params {
+            Some(ref p) => match shm_area_size(p, params.latency_frames) {
+                Some(size) => size,
+                None => bail!("Input shared memory area too large"),
+            },
+            None => 0,
+        };
+        let mut output_shm_size = match params.output_stream_params {
+            Some(ref p) => match shm_area_size(p, params.latency_frames) {
+                Some(size) => size,
+                None => bail!("Output shared memory area too large"),
+            },
+            None => 0,
+        };
+
+        // The ring buffer replaces the per-callback area, size for whichever is larger.
+        let ring_capacity = match params.output_ring_buffer_frames {
+            Some(frames) => {
+                let ring_supported = self
+                    .connection
+                    .map_or(false, |c| c.has_feature(FEATURE_SHM_RING_BUFFER));
+                if !ring_supported
+                    || params.input_stream_params.is_some()
+                    || params.output_stream_params.is_none()
+                    || frames == 0
+                {
+                    bail!("Output ring buffer requested for unsupported stream");
+                }
+                let capacity = match (frames as usize).checked_mul(output_frame_size as usize) {
+                    Some(capacity) if capacity <= audioipc::MAX_SHM_AREA_SIZE => capacity,
+                    _ => bail!("Output ring buffer too large"),
+                };
+                output_shm_size = cmp::max(output_shm_size, ringbuf::required_size(capacity));
+                Some(capacity)
+            }
+            None => None,
+        };
+
+        if params.shm_data_signal {
+            let signal_supported = self
+                .connection
+                .map_or(false, |c| c.has_feature(FEATURE_SHM_DATA_SIGNAL));
+            if !signal_supported || ring_capacity.is_some() {
+                bail!("Data signalling requested for unsupported stream");
+            }
+        }
+
+        let layout = Layout::new(
+            input_shm_size,
+            input_frame_size,
+            output_shm_size,
+            output_frame_size,
+            ring_capacity.is_some(),
+            params.shm_data_signal,
+        );
+        // Room left under the limit, which a region reused from the pool
+        // mustn't exceed either.
+        let max_shm_size = match self.config.limits.max_shm_size {
+            Some(max) => {
+                let in_use: usize = self.streams.iter().map(|(_, s)| s.shm_size).sum();
+                match max.checked_sub(in_use) {
+                    Some(available) if layout.size() <= available => available,
+                    _ => return Ok(self.limit_exceeded(ClientLimit::SharedMemory)),
+                }
+            }
+            None => usize::max_value(),
+        };
 
         let (ipc_server, ipc_client) = MessageStream::anonymous_ipc_pair()?;
         debug!("Created callback pair: {:?}-{:?}", ipc_server, ipc_client);
-        let shm_id = get_shm_id();
-        let (input_shm, input_file) =
-            SharedMem::new(&format!("{}-input", shm_id), audioipc::SHM_AREA_SIZE)?;
-        let (output_shm, output_file) =
-            SharedMem::new(&format!("{}-output", shm_id), audioipc::SHM_AREA_SIZE)?;
+        let (mut shm, shm_file) = self.shm_pool.allocate(
+            &get_shm_id(),
+            layout.size(),
+            max_shm_size,
+            Access::ReadWrite,
+        )?;
+        // A region reused from the pool may be larger than requested, and the
+        // client maps it whole.
+        let shm_size = shm.size();
+        let region = unsafe { Region::init(&mut shm, layout)? };
 
         // This code is currently running on the Client/Server RPC
         // handling thread.  We need to move the registration of the
         // bind_client to the callback RPC handling thread.  This is
         // done by spawning a future on `handle`.
         let (tx, rx) = oneshot::channel();
+        let capture = self.capture.clone();
         self.handle
             .spawn(futures::future::lazy(move || {
                 let handle = reactor::Handle::default();
                 let stream = ipc_server.into_tokio_ipc(&handle).unwrap();
-                let transport = framed(stream, Default::default());
-                let rpc = rpc::bind_client::<CallbackClient>(transport);
+                let transport = framed(stream, rpc::client_codec::<CallbackClient>());
+                let rpc = match capture {
+                    Some(capture) => rpc::bind_client_with_tap::<CallbackClient>(
+                        transport,
+                        Box::new(capture.tap()),
+                    ),
+                    None => rpc::bind_client::<CallbackClient>(transport),
+                };
                 drop(tx.send(rpc));
                 Ok(())
             }))
@@ -712,88 +1665,236 @@ impl CubebServer {
             Err(_) => bail!("Failed to create callback rpc."),
         };
 
-        // TODO: The lowest comms layer expects exactly 3 PlatformHandles, so we always configure both sides of the shm.
-        // ServerStreamCallbacks only needs the active shm, so drop any unused shm now.
-        let input_shm = params.input_stream_params.and(Some(input_shm));
-        let output_shm = params.output_stream_params.and(Some(output_shm));
+        let output_ring = match (ring_capacity, region.output()) {
+            (Some(capacity), Some(mut section)) => unsafe {
+                let area = section.get_mut_slice(output_shm_size)?.as_mut_ptr();
+                ringbuf::init(area, output_shm_size, capacity)?;
+                Some(ringbuf::Consumer::attach(area, output_shm_size)?)
+            },
+            _ => None,
+        };
+
+        let key = self.streams.vacant_entry().key();
+        let input_tap = self.audio_tap(key, "input", params.input_stream_params.as_ref());
+        let output_tap = self.audio_tap(key, "output", params.output_stream_params.as_ref());
 
+        let activity = Arc::new(StreamActivity::new());
         let cbs = Box::new(ServerStreamCallbacks {
             input_frame_size,
             output_frame_size,
-            input_shm,
-            output_shm,
+            region,
+            output_ring,
             rpc,
+            rate,
+            activity: activity.clone(),
+            consecutive_misses: 0,
+            max_callback_misses: self.config.max_callback_misses,
+            outstanding: None,
+            input_tap,
+            output_tap,
         });
 
-        let entry = self.streams.vacant_entry();
-        let key = entry.key();
         debug!("Registering stream {:?}", key);
-
-        entry.insert(ServerStream { stream: None, cbs });
+        let entry = self.streams.vacant_entry();
+        debug_assert_eq!(entry.key(), key);
+
+        entry.insert(ServerStream {
+            stream: None,
+            cbs: Some(cbs),
+            init: None,
+            shm: Some(shm),
+            input_params: params.input_stream_params,
+            output_params: params.output_stream_params,
+            latency_frames: params.latency_frames,
+            input_shm_size,
+            output_shm_size,
+            shm_size,
+            activity,
+        });
 
         Ok(ClientMessage::StreamCreated(StreamCreate {
             token: key,
-            platform_handles: [PlatformHandle::from(ipc_client), input_file, output_file],
-            target_pid: self.remote_pid.unwrap(),
+            platform_handles: vec![PlatformHandle::from(ipc_client), shm_file],
+            target_pid: self.identity.unwrap().pid,
+            shm_size,
         }))
     }
 
-    // Stream init is special, so it's been separated from process_msg.
+    // Stream init is special, so it's been separated from process_msg.  It
+    // can take long enough to hold up the client's other requests, so the
+    // stream is initialized on a thread of its own and the response is sent
+    // once that completes.
     fn process_stream_init(
         &mut self,
-        context: &cubeb::Context,
+        context: &SharedContext,
         stm_tok: usize,
         params: &StreamInitParams,
-    ) -> Result<ClientMessage> {
-        // Create cubeb stream from params
-        let stream_name = params
-            .stream_name
-            .as_ref()
-            .and_then(|name| CStr::from_bytes_with_nul(name).ok());
-
-        // Map IPC handle back to cubeb_devid.
-        let input_device = self.devidmap.from_handle(params.input_device) as *const _;
-        let input_stream_params = params.input_stream_params.as_ref().map(|isp| unsafe {
-            cubeb::StreamParamsRef::from_ptr(isp as *const StreamParams as *mut _)
-        });
+    ) -> ClientResponse {
+        match self.start_stream_init(context, stm_tok, params) {
+            Ok(done) => Box::new(done.then(|r| {
+                Ok::<_, ()>(match r {
+                    Ok(Ok(())) => ClientMessage::StreamInitialized,
+                    Ok(Err(e)) => error(e),
+                    Err(_) => error(cubeb::Error::error()),
+                })
+            })),
+            Err(e) => Box::new(future::ok(error(e))),
+        }
+    }
 
-        // Map IPC handle back to cubeb_devid.
-        let output_device = self.devidmap.from_handle(params.output_device) as *const _;
-        let output_stream_params = params.output_stream_params.as_ref().map(|osp| unsafe {
-            cubeb::StreamParamsRef::from_ptr(osp as *const StreamParams as *mut _)
-        });
+    fn start_stream_init(
+        &mut self,
+        context: &SharedContext,
+        stm_tok: usize,
+        params: &StreamInitParams,
+    ) -> cubeb::Result<oneshot::Receiver<cubeb::Result<()>>> {
+        self.finish_stream_inits();
 
-        let latency = params.latency_frames;
-
-        let server_stream = &mut self.streams[stm_tok];
-        assert!(size_of::<Box<ServerStreamCallbacks>>() == size_of::<usize>());
-        let user_ptr = server_stream.cbs.as_ref() as *const ServerStreamCallbacks as *mut c_void;
-
-        let stream = unsafe {
-            let stream = context.stream_init(
-                stream_name,
-                input_device,
-                input_stream_params,
-                output_device,
-                output_stream_params,
-                latency,
-                Some(data_cb_c),
-                Some(state_cb_c),
-                user_ptr,
-            );
-            match stream {
-                Ok(stream) => stream,
-                Err(e) => {
-                    debug!("Unregistering stream {:?} (stream error {:?})", stm_tok, e);
-                    self.streams.remove(stm_tok);
-                    return Err(e.into());
+        let server_stream = match self.streams.get_mut(stm_tok) {
+            Some(server_stream)
+                if server_stream.stream.is_none() && server_stream.init.is_none() =>
+            {
+                server_stream
+            }
+            _ => {
+                warn!(
+                    "StreamInit({}): invalid token or stream already initialized",
+                    stm_tok
+                );
+                return Err(cubeb::Error::invalid_parameter());
+            }
+        };
+        let cbs = server_stream.cbs.as_ref().unwrap();
+
+        // The shared memory areas and callbacks were sized by StreamCreate,
+        // so the stream must be initialized with matching parameters.
+        let frame_size = |p: Option<&StreamParams>| -> cubeb::Result<u16> {
+            match p {
+                Some(p) => {
+                    validate_stream_params(p)?;
+                    Ok(p.frame_size().unwrap() as u16)
                 }
+                None => Ok(0),
+            }
+        };
+        if frame_size(params.input_stream_params.as_ref())? != cbs.input_frame_size
+            || frame_size(params.output_stream_params.as_ref())? != cbs.output_frame_size
+        {
+            warn!(
+                "StreamInit({}): stream params don't match StreamCreate",
+                stm_tok
+            );
+            return Err(cubeb::Error::invalid_parameter());
+        }
+
+        // Map IPC handles back to cubeb_devids.
+        let (input_device, output_device) = match (
+            self.devidmap.from_handle(params.input_device),
+            self.devidmap.from_handle(params.output_device),
+        ) {
+            (Some(input), Some(output)) => (input, output),
+            _ => {
+                warn!(
+                    "StreamInit({}): invalid device handles {}, {}",
+                    stm_tok, params.input_device, params.output_device
+                );
+                return Err(cubeb::Error::invalid_parameter());
             }
         };
 
-        server_stream.stream = Some(stream);
+        let cbs = server_stream.cbs.take().unwrap();
+        let (init_tx, init_rx) = oneshot::channel();
+        let (done_tx, done_rx) = oneshot::channel();
+        let context = context.clone();
+        let params = params.clone();
+        let spawned = thread::Builder::new()
+            .name(format!("AudioIPC StreamInit {}", stm_tok))
+            .spawn(move || {
+                // Create cubeb stream from params
+                let stream_name = params
+                    .stream_name
+                    .as_ref()
+                    .and_then(|name| CStr::from_bytes_with_nul(name).ok());
+
+                let input_stream_params = params.input_stream_params.as_ref().map(|isp| unsafe {
+                    cubeb::StreamParamsRef::from_ptr(isp as *const StreamParams as *mut _)
+                });
+
+                let output_stream_params = params.output_stream_params.as_ref().map(|osp| unsafe {
+                    cubeb::StreamParamsRef::from_ptr(osp as *const StreamParams as *mut _)
+                });
+
+                let latency = params.latency_frames;
+
+                assert!(size_of::<Box<ServerStreamCallbacks>>() == size_of::<usize>());
+                let user_ptr = cbs.as_ref() as *const ServerStreamCallbacks as *mut c_void;
+
+                let (stream, result) = match unsafe {
+                    context.stream_init(
+                        stream_name,
+                        input_device as *const _,
+                        input_stream_params,
+                        output_device as *const _,
+                        output_stream_params,
+                        latency,
+                        Some(data_cb_c),
+                        Some(state_cb_c),
+                        user_ptr,
+                    )
+                } {
+                    Ok(stream) => (Some(stream), Ok(())),
+                    Err(e) => (None, Err(e)),
+                };
+
+                // The stream must be back with the server before the client
+                // learns it's initialized.  If the server has gone away, the
+                // stream is destroyed here.
+                drop(init_tx.send(InitializedStream { stream, cbs }));
+                drop(done_tx.send(result));
+            });
+        if let Err(e) = spawned {
+            warn!("StreamInit({}): failed to spawn thread: {:?}", stm_tok, e);
+            self.remove_stream(stm_tok);
+            return Err(cubeb::Error::error());
+        }
+
+        self.streams[stm_tok].init = Some(init_rx);
+        Ok(done_rx)
+    }
+
+    // Take back the streams, and their callbacks, whose initialization has
+    // completed.  Streams that failed to initialize are unregistered.
+    fn finish_stream_inits(&mut self) {
+        let mut failed = Vec::new();
+        for (stm_tok, server_stream) in self.streams.iter_mut() {
+            let initialized = match server_stream.init.as_mut().map(|init| init.try_recv()) {
+                Some(Ok(Some(initialized))) => initialized,
+                Some(Ok(None)) | None => continue,
+                Some(Err(_)) => {
+                    failed.push(stm_tok);
+                    continue;
+                }
+            };
+            server_stream.init = None;
+            server_stream.cbs = Some(initialized.cbs);
+            match initialized.stream {
+                Some(stream) => server_stream.stream = Some(stream),
+                None => failed.push(stm_tok),
+            }
+        }
+        for stm_tok in failed {
+            debug!("Unregistering stream {:?} (stream init failed)", stm_tok);
+            self.remove_stream(stm_tok);
+        }
+    }
 
-        Ok(ClientMessage::StreamInitialized)
+    fn remove_stream(&mut self, stm_tok: usize) {
+        let mut s = self.streams.remove(stm_tok);
+        // Stop callbacks using the region before it's recycled.
+        drop(s.stream.take());
+        if let Some(shm) = s.shm.take() {
+            self.shm_pool.recycle(shm);
+        }
     }
 }
 
@@ -852,7 +1953,7 @@ unsafe extern "C" fn device_collection_changed_input_cb_c(
     user_ptr: *mut c_void,
 ) {
     let ok = panic::catch_unwind(|| {
-        let manager = &mut *(user_ptr as *mut CubebDeviceCollectionManager);
+        let manager = &*(user_ptr as *const CubebDeviceCollectionManager);
         manager.device_collection_changed_callback(ffi::CUBEB_DEVICE_TYPE_INPUT);
     });
     ok.expect("Collection changed (input) callback panicked");
@@ -863,8 +1964,660 @@ unsafe extern "C" fn device_collection_changed_output_cb_c(
     user_ptr: *mut c_void,
 ) {
     let ok = panic::catch_unwind(|| {
-        let manager = &mut *(user_ptr as *mut CubebDeviceCollectionManager);
+        let manager = &*(user_ptr as *const CubebDeviceCollectionManager);
         manager.device_collection_changed_callback(ffi::CUBEB_DEVICE_TYPE_OUTPUT);
     });
     ok.expect("Collection changed (output) callback panicked");
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use audioipc::core;
+    use futures::future::FutureResult;
+    use std::process;
+    use std::sync::mpsc;
+
+    type Event = (usize, ffi::cubeb_device_type);
+
+    // Stands in for a client's DeviceCollectionServer.
+    struct Subscriber {
+        id: usize,
+        tx: mpsc::Sender<Event>,
+    }
+
+    impl rpc::Server for Subscriber {
+        type Request = DeviceCollectionReq;
+        type Response = DeviceCollectionResp;
+        type Future = FutureResult<Self::Response, ()>;
+        type Transport = Framed<
+            audioipc::AsyncMessageStream,
+            LengthDelimitedCodec<rpc::Envelope<Self::Response>, rpc::Envelope<Self::Request>>,
+        >;
+        const MAX_MESSAGE_LEN: usize = MAX_CALLBACK_MESSAGE_LEN;
+
+        fn process(&mut self, req: Self::Request) -> Self::Future {
+            match req {
+                DeviceCollectionReq::DeviceChange(device_type) => {
+                    drop(self.tx.send((self.id, device_type)));
+                    future::ok(DeviceCollectionResp::DeviceChange)
+                }
+            }
+        }
+    }
+
+    fn subscriber(
+        thread: &core::CoreThread,
+        id: usize,
+        tx: mpsc::Sender<Event>,
+    ) -> Arc<Mutex<CubebServerCallbacks>> {
+        let (server, client) = MessageStream::anonymous_ipc_pair().unwrap();
+        let (rpc_tx, rpc_rx) = oneshot::channel();
+        thread
+            .handle()
+            .spawn(future::lazy(move || {
+                let handle = reactor::Handle::default();
+                let transport = framed(
+                    server.into_tokio_ipc(&handle).unwrap(),
+                    rpc::server_codec::<Subscriber>(),
+                );
+                rpc::bind_server(transport, Subscriber { id, tx });
+                let transport = framed(
+                    client.into_tokio_ipc(&handle).unwrap(),
+                    rpc::client_codec::<DeviceCollectionClient>(),
+                );
+                drop(rpc_tx.send(rpc::bind_client::<DeviceCollectionClient>(transport)));
+                Ok(())
+            }))
+            .unwrap();
+        Arc::new(Mutex::new(CubebServerCallbacks {
+            rpc: rpc_rx.wait().unwrap(),
+            devtype: cubeb::DeviceType::empty(),
+        }))
+    }
+
+    fn events(rx: &mpsc::Receiver<Event>, count: usize) -> Vec<Event> {
+        let mut events: Vec<Event> = (0..count)
+            .map(|_| rx.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        events.sort();
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        events
+    }
+
+    #[test]
+    fn device_collection_changes_fan_out() {
+        const INPUT: ffi::cubeb_device_type = ffi::CUBEB_DEVICE_TYPE_INPUT;
+        const OUTPUT: ffi::cubeb_device_type = ffi::CUBEB_DEVICE_TYPE_OUTPUT;
+
+        let thread = core::spawn_thread("Device Collection Test", || Ok(()), || {}).unwrap();
+        let (tx, rx) = mpsc::channel();
+        let input = subscriber(&thread, 0, tx.clone());
+        let output = subscriber(&thread, 1, tx.clone());
+        let both = subscriber(&thread, 2, tx);
+
+        let manager = CubebDeviceCollectionManager::new();
+        {
+            let mut servers = manager.servers.lock().unwrap();
+            CubebDeviceCollectionManager::subscribe(&mut servers, &input, cubeb::DeviceType::INPUT);
+            CubebDeviceCollectionManager::subscribe(
+                &mut servers,
+                &output,
+                cubeb::DeviceType::OUTPUT,
+            );
+            CubebDeviceCollectionManager::subscribe(&mut servers, &both, cubeb::DeviceType::INPUT);
+            CubebDeviceCollectionManager::subscribe(&mut servers, &both, cubeb::DeviceType::OUTPUT);
+            assert_eq!(servers.len(), 3);
+        }
+
+        manager.device_collection_changed_callback(INPUT);
+        assert_eq!(events(&rx, 2), vec![(0, INPUT), (2, INPUT)]);
+        manager.device_collection_changed_callback(OUTPUT);
+        assert_eq!(events(&rx, 2), vec![(1, OUTPUT), (2, OUTPUT)]);
+
+        {
+            let mut servers = manager.servers.lock().unwrap();
+            CubebDeviceCollectionManager::unsubscribe(
+                &mut servers,
+                &both,
+                cubeb::DeviceType::INPUT,
+            );
+            assert_eq!(servers.len(), 3);
+            CubebDeviceCollectionManager::unsubscribe(
+                &mut servers,
+                &input,
+                cubeb::DeviceType::INPUT,
+            );
+            assert_eq!(servers.len(), 2);
+        }
+
+        manager.device_collection_changed_callback(INPUT);
+        assert!(events(&rx, 0).is_empty());
+        manager.device_collection_changed_callback(OUTPUT);
+        assert_eq!(events(&rx, 2), vec![(1, OUTPUT), (2, OUTPUT)]);
+    }
+
+    #[test]
+    fn client_identity_cross_checks_peer_pid() {
+        let claimed = process::id().wrapping_add(1);
+        let peer = |pid| {
+            Some(PeerCredentials {
+                pid,
+                uid: 1000,
+                gid: 100,
+            })
+        };
+
+        let identity = ClientIdentity::new(claimed, peer(Some(claimed))).unwrap();
+        assert_eq!(identity.pid, claimed);
+        assert_eq!(identity.uid, Some(1000));
+        assert!(identity.verified);
+
+        assert_eq!(
+            ClientIdentity::new(claimed, peer(Some(claimed.wrapping_add(1)))),
+            None
+        );
+
+        // Credentials that lack a pid vouch for nothing.
+        for peer in &[None, peer(None)] {
+            let identity = ClientIdentity::new(claimed, *peer).unwrap();
+            assert_eq!(identity.pid, claimed);
+            assert_eq!(identity.uid, None);
+            assert!(!identity.verified);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn anonymous_pair_reports_creator() {
+        let (server, _client) = MessageStream::anonymous_ipc_pair().unwrap();
+        let creds = server.peer_credentials().unwrap();
+        assert_eq!(creds.pid, Some(process::id()));
+    }
+
+    // Send a `ClientConnect` claiming `pid` over a connection set up as
+    // `Server::new_client` sets one up, and return it as the server gets it.
+    #[cfg(target_os = "linux")]
+    fn received_client_connect(pid: u32) -> ClientConnectParams {
+        use audioipc::platformhandle_passing::framed_with_platformhandles;
+        use futures::{Sink, Stream};
+
+        let (server, client) = MessageStream::anonymous_ipc_pair().unwrap();
+        server.set_pass_credentials().unwrap();
+        let mut rt = current_thread::Runtime::new().unwrap();
+        let handle = reactor::Handle::default();
+        let client = framed_with_platformhandles(
+            client.into_tokio_ipc(&handle).unwrap(),
+            LengthDelimitedCodec::<rpc::Envelope<ServerMessage>, ()>::default(),
+        );
+        let server = framed_with_platformhandles(
+            server.into_tokio_ipc(&handle).unwrap(),
+            LengthDelimitedCodec::<(), rpc::Envelope<ServerMessage>>::default(),
+        );
+
+        let connect = rpc::Envelope {
+            id: 0,
+            body: ServerMessage::ClientConnect(ClientConnectParams::new(pid)),
+        };
+        let _client = rt.block_on(client.send(connect)).unwrap();
+        let (msg, _server) = rt
+            .block_on(server.into_future())
+            .map_err(|(e, _)| e)
+            .unwrap();
+        match msg.unwrap().body {
+            ServerMessage::ClientConnect(params) => params,
+            body => panic!("unexpected message {:?}", body),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn handshake_checks_claimed_pid() {
+        let thread = core::spawn_thread("Handshake Test", || Ok(()), || {}).unwrap();
+
+        let params = received_client_connect(process::id());
+        assert_eq!(params.peer.and_then(|peer| peer.pid), Some(process::id()));
+        let mut server = CubebServer::new(thread.handle(), ServerConfig::default());
+        match server.process_client_connect(&params) {
+            ClientMessage::ClientConnected(_) => {}
+            r => panic!("Handshake failed: {:?}", r),
+        }
+        assert!(server.identity.unwrap().verified);
+
+        // A client claiming to be another process is turned away.
+        let params = received_client_connect(process::id().wrapping_add(1));
+        let mut server = CubebServer::new(thread.handle(), ServerConfig::default());
+        match server.process_client_connect(&params) {
+            ClientMessage::ClientRejected(PROTOCOL_VERSION) => {}
+            r => panic!("Handshake accepted: {:?}", r),
+        }
+        assert!(server.identity.is_none());
+    }
+
+    fn connected_server(thread: &core::CoreThread, config: ServerConfig) -> CubebServer {
+        let mut server = CubebServer::new(thread.handle(), config);
+        match server.process_client_connect(&ClientConnectParams::new(process::id())) {
+            ClientMessage::ClientConnected(_) => server,
+            r => panic!("Handshake failed: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn handshake_applies_message_limit() {
+        let thread = core::spawn_thread("Handshake Test", || Ok(()), || {}).unwrap();
+        let mut server = CubebServer::new(thread.handle(), ServerConfig::default());
+        let limit = MessageLimit::new(audioipc::codec::MAX_MESSAGE_LEN as usize);
+        server.set_message_limit(limit.clone());
+
+        let mut params = ClientConnectParams::new(process::id());
+        params.max_message_len = 4096;
+        match server.process_client_connect(&params) {
+            ClientMessage::ClientConnected(connection) => {
+                assert_eq!(connection.max_message_len, 4096)
+            }
+            r => panic!("Handshake failed: {:?}", r),
+        }
+        assert_eq!(limit.get(), 4096);
+    }
+
+    fn stream_params(format: ffi::cubeb_sample_format, channels: u32) -> StreamParams {
+        StreamParams {
+            format,
+            rate: 48000,
+            channels,
+            layout: ffi::CUBEB_LAYOUT_UNDEFINED,
+            prefs: ffi::CUBEB_STREAM_PREF_NONE,
+        }
+    }
+
+    fn create_params(output: StreamParams) -> StreamCreateParams {
+        StreamCreateParams {
+            input_stream_params: None,
+            output_stream_params: Some(output),
+            output_ring_buffer_frames: None,
+            shm_data_signal: false,
+            latency_frames: 256,
+        }
+    }
+
+    fn init_params(output: StreamParams, output_device: usize) -> StreamInitParams {
+        StreamInitParams {
+            stream_name: None,
+            input_device: 0,
+            input_stream_params: None,
+            output_device,
+            output_stream_params: Some(output),
+            latency_frames: 256,
+        }
+    }
+
+    fn is_invalid_parameter(resp: &ClientMessage) -> bool {
+        match *resp {
+            ClientMessage::Error(code) => code == ffi::CUBEB_ERROR_INVALID_PARAMETER,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn malicious_messages_are_rejected() {
+        let thread = core::spawn_thread("Validation Test", || Ok(()), || {}).unwrap();
+        let context = SharedContext::new(fake::init(None).unwrap());
+        let mut manager = CubebDeviceCollectionManager::new();
+        let mut server = connected_server(&thread, ServerConfig::default());
+        let mut send = |msg: &ServerMessage| match *msg {
+            ServerMessage::StreamInit(stm_tok, ref params) => server
+                .process_stream_init(&context, stm_tok, params)
+                .wait()
+                .unwrap(),
+            _ => server.process_msg(&context, &mut manager, msg),
+        };
+
+        let good = stream_params(ffi::CUBEB_SAMPLE_S16NE, 2);
+        let bad_params = [
+            stream_params(0xdead, 2),
+            stream_params(ffi::CUBEB_SAMPLE_S16NE, 0),
+            stream_params(ffi::CUBEB_SAMPLE_FLOAT32NE, u32::max_value()),
+            StreamParams { rate: 0, ..good },
+        ];
+        let mut malicious = vec![
+            ServerMessage::ContextGetDeviceEnumeration(0),
+            ServerMessage::ContextGetDeviceEnumeration(0x80),
+            ServerMessage::ContextRegisterDeviceCollectionChanged(u32::max_value(), true),
+            ServerMessage::StreamCreate(StreamCreateParams {
+                output_stream_params: None,
+                ..create_params(good)
+            }),
+            ServerMessage::StreamInit(42, init_params(good, 0)),
+            ServerMessage::StreamStart(42),
+            ServerMessage::StreamStop(usize::max_value()),
+            ServerMessage::StreamSetVolume(42, 1.0),
+            ServerMessage::StreamDestroy(42),
+        ];
+        for p in &bad_params {
+            malicious.push(ServerMessage::ContextGetMinLatency(*p));
+            malicious.push(ServerMessage::StreamCreate(create_params(*p)));
+        }
+        for msg in &malicious {
+            let resp = send(msg);
+            assert!(is_invalid_parameter(&resp), "{:?} -> {:?}", msg, resp);
+        }
+
+        let token = match send(&ServerMessage::StreamCreate(create_params(good))) {
+            ClientMessage::StreamCreated(created) => created.token,
+            r => panic!("StreamCreate failed: {:?}", r),
+        };
+        let malicious = [
+            // Not initialized yet.
+            ServerMessage::StreamStart(token),
+            // A device handle the server never issued.
+            ServerMessage::StreamInit(token, init_params(good, 1000)),
+            // Parameters that don't fit the shared memory sized by
+            // StreamCreate.
+            ServerMessage::StreamInit(
+                token,
+                init_params(stream_params(ffi::CUBEB_SAMPLE_S16NE, 8), 0),
+            ),
+            ServerMessage::StreamInit(
+                token,
+                StreamInitParams {
+                    input_stream_params: Some(good),
+                    ..init_params(good, 0)
+                },
+            ),
+        ];
+        for msg in &malicious {
+            let resp = send(msg);
+            assert!(is_invalid_parameter(&resp), "{:?} -> {:?}", msg, resp);
+        }
+
+        // The stream survives rejected requests.
+        match send(&ServerMessage::StreamInit(token, init_params(good, 0))) {
+            ClientMessage::StreamInitialized => {}
+            r => panic!("StreamInit failed: {:?}", r),
+        }
+        let resp = send(&ServerMessage::StreamInit(token, init_params(good, 0)));
+        assert!(
+            is_invalid_parameter(&resp),
+            "repeated StreamInit -> {:?}",
+            resp
+        );
+        match send(&ServerMessage::StreamDestroy(token)) {
+            ClientMessage::StreamDestroyed => {}
+            r => panic!("StreamDestroy failed: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn slow_stream_init_does_not_block_other_requests() {
+        let thread = core::spawn_thread("Stream Init Test", || Ok(()), || {}).unwrap();
+        let context = SharedContext::new(fake::init(None).unwrap());
+        let mut manager = CubebDeviceCollectionManager::new();
+        let mut server = connected_server(&thread, ServerConfig::default());
+        let good = stream_params(ffi::CUBEB_SAMPLE_S16NE, 2);
+        let mut create = || match server.process_msg(
+            &context,
+            &mut manager,
+            &ServerMessage::StreamCreate(create_params(good)),
+        ) {
+            ClientMessage::StreamCreated(created) => created.token,
+            r => panic!("StreamCreate failed: {:?}", r),
+        };
+        let slow = create();
+        let quick = create();
+
+        fake::hold_stream_init("slow init");
+        let slow_init = server.process_stream_init(
+            &context,
+            slow,
+            &StreamInitParams {
+                stream_name: Some(b"slow init\0".to_vec()),
+                ..init_params(good, 0)
+            },
+        );
+
+        match server
+            .process_stream_init(&context, quick, &init_params(good, 0))
+            .wait()
+        {
+            Ok(ClientMessage::StreamInitialized) => {}
+            r => panic!("StreamInit failed: {:?}", r),
+        }
+        match server.process_msg(
+            &context,
+            &mut manager,
+            &ServerMessage::StreamGetPosition(quick),
+        ) {
+            ClientMessage::StreamPosition(_) => {}
+            r => panic!("StreamGetPosition failed: {:?}", r),
+        }
+        // The slow stream can't be used, or initialized again, until its
+        // initialization completes.
+        let resp = server.process_msg(&context, &mut manager, &ServerMessage::StreamStart(slow));
+        assert!(is_invalid_parameter(&resp), "{:?}", resp);
+        let resp = server
+            .process_stream_init(&context, slow, &init_params(good, 0))
+            .wait()
+            .unwrap();
+        assert!(is_invalid_parameter(&resp), "{:?}", resp);
+
+        fake::release_stream_init("slow init");
+        match slow_init.wait() {
+            Ok(ClientMessage::StreamInitialized) => {}
+            r => panic!("StreamInit failed: {:?}", r),
+        }
+        match server.process_msg(
+            &context,
+            &mut manager,
+            &ServerMessage::StreamGetPosition(slow),
+        ) {
+            ClientMessage::StreamPosition(_) => {}
+            r => panic!("StreamGetPosition failed: {:?}", r),
+        }
+    }
+
+    enum CallbackEvent {
+        Data(oneshot::Sender<CallbackResp>),
+        State(ffi::cubeb_state),
+    }
+
+    // Stands in for a client's CallbackServer, leaving data callbacks for the
+    // test to answer.
+    struct TestCallbacks {
+        tx: mpsc::Sender<CallbackEvent>,
+    }
+
+    impl rpc::Server for TestCallbacks {
+        type Request = CallbackReq;
+        type Response = CallbackResp;
+        type Future = Box<dyn Future<Item = Self::Response, Error = ()>>;
+        type Transport = Framed<
+            audioipc::AsyncMessageStream,
+            LengthDelimitedCodec<rpc::Envelope<Self::Response>, rpc::Envelope<Self::Request>>,
+        >;
+        const MAX_MESSAGE_LEN: usize = MAX_CALLBACK_MESSAGE_LEN;
+
+        fn process(&mut self, req: Self::Request) -> Self::Future {
+            match req {
+                CallbackReq::Data { .. } => {
+                    let (tx, rx) = oneshot::channel();
+                    drop(self.tx.send(CallbackEvent::Data(tx)));
+                    Box::new(rx.or_else(|_| Ok::<_, ()>(CallbackResp::Data(0))))
+                }
+                CallbackReq::State(state) => {
+                    drop(self.tx.send(CallbackEvent::State(state)));
+                    Box::new(future::ok(CallbackResp::State))
+                }
+                CallbackReq::DeviceChange => Box::new(future::ok(CallbackResp::DeviceChange)),
+            }
+        }
+    }
+
+    fn serve_callbacks(
+        thread: &core::CoreThread,
+        created: &StreamCreate,
+    ) -> mpsc::Receiver<CallbackEvent> {
+        let (tx, rx) = mpsc::channel();
+        let stream = unsafe { MessageStream::from_raw_fd(created.platform_handles[0].into_raw()) };
+        thread
+            .handle()
+            .spawn(future::lazy(move || {
+                let handle = reactor::Handle::default();
+                let transport = framed(
+                    stream.into_tokio_ipc(&handle).unwrap(),
+                    rpc::server_codec::<TestCallbacks>(),
+                );
+                rpc::bind_server(transport, TestCallbacks { tx });
+                Ok(())
+            }))
+            .unwrap();
+        rx
+    }
+
+    fn next_data_callback(events: &mpsc::Receiver<CallbackEvent>) -> oneshot::Sender<CallbackResp> {
+        match events.recv_timeout(Duration::from_secs(5)) {
+            Ok(CallbackEvent::Data(tx)) => tx,
+            _ => panic!("Expected a data callback"),
+        }
+    }
+
+    #[test]
+    fn missed_data_callbacks() {
+        // Long enough for the client to answer in time when it's meant to.
+        const NFRAMES: isize = 8192;
+        let rpc_thread = core::spawn_thread("Missed Callbacks Test", || Ok(()), || {}).unwrap();
+        let context = fake::init(None).unwrap();
+        let mut manager = CubebDeviceCollectionManager::new();
+        let mut config = ServerConfig::default();
+        config.max_callback_misses = Some(3);
+        let mut server = connected_server(&rpc_thread, config);
+        let create =
+            ServerMessage::StreamCreate(create_params(stream_params(ffi::CUBEB_SAMPLE_S16NE, 2)));
+        let created = match server.process_msg(&context, &mut manager, &create) {
+            ClientMessage::StreamCreated(created) => created,
+            r => panic!("StreamCreate failed: {:?}", r),
+        };
+        let events = serve_callbacks(&rpc_thread, &created);
+        let stream = &mut server.streams[created.token];
+        let activity = stream.activity.clone();
+        let cbs = stream.cbs.as_mut().unwrap();
+        let region = cbs.region;
+        let mut callback = || {
+            let mut output = vec![0xffu8; NFRAMES as usize * 4];
+            let frames = cbs.data_callback(&[], &mut output, NFRAMES);
+            (frames, output.iter().all(|&b| b == 0))
+        };
+        let no_event = |events: &mpsc::Receiver<CallbackEvent>| {
+            events.recv_timeout(Duration::from_millis(100)).is_err()
+        };
+
+        // A callback the client doesn't answer in time is played as silence,
+        // and no other is requested until it's answered.
+        assert_eq!(callback(), (NFRAMES, true));
+        let late = next_data_callback(&events);
+        assert_eq!(callback(), (NFRAMES, true));
+        assert!(no_event(&events));
+        assert_eq!(activity.stats().missed_deadlines, 2);
+
+        // A late answer is discarded.  The third consecutive miss puts the
+        // stream in the error state.
+        drop(late.send(CallbackResp::Data(NFRAMES)));
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(callback(), (NFRAMES, true));
+        let late = next_data_callback(&events);
+        match events.recv_timeout(Duration::from_secs(5)) {
+            Ok(CallbackEvent::State(ffi::CUBEB_STATE_ERROR)) => {}
+            _ => panic!("Expected the error state"),
+        }
+        assert_eq!(activity.stats().missed_deadlines, 3);
+
+        // The errored stream plays silence, rather than draining, without
+        // calling the client.
+        assert_eq!(callback(), (NFRAMES, true));
+        assert!(no_event(&events));
+        assert_eq!(activity.stats().missed_deadlines, 3);
+
+        // Until restarted, as by StreamStart.
+        activity.errored.store(false, Ordering::Relaxed);
+        drop(late.send(CallbackResp::Data(NFRAMES)));
+        thread::sleep(Duration::from_millis(100));
+        let client = thread::spawn(move || {
+            let tx = next_data_callback(&events);
+            region.output_written();
+            drop(tx.send(CallbackResp::Data(NFRAMES)));
+            events
+        });
+        assert_eq!(callback().0, NFRAMES);
+        let events = client.join().unwrap();
+        assert_eq!(activity.stats().missed_deadlines, 3);
+
+        // An answer without output written is an error, rather than a replay
+        // of the previous callback's output.
+        let client = thread::spawn(move || {
+            drop(next_data_callback(&events).send(CallbackResp::Data(NFRAMES)));
+        });
+        assert_eq!(callback().0, 0);
+        client.join().unwrap();
+        assert_eq!(activity.stats().errors, 1);
+    }
+
+    #[test]
+    fn client_limits_are_enforced() {
+        let thread = core::spawn_thread("Limits Test", || Ok(()), || {}).unwrap();
+        let context = fake::init(None).unwrap();
+        let mut manager = CubebDeviceCollectionManager::new();
+        let create =
+            ServerMessage::StreamCreate(create_params(stream_params(ffi::CUBEB_SAMPLE_S16NE, 2)));
+        let limit_exceeded = |resp: ClientMessage, expected: ClientLimit| match resp {
+            ClientMessage::LimitExceeded(limit) => assert_eq!(limit, expected),
+            r => panic!("Expected {:?} limit, got {:?}", expected, r),
+        };
+
+        let mut config = ServerConfig::default();
+        config.limits.max_streams = Some(1);
+        let mut server = connected_server(&thread, config);
+        let token = match server.process_msg(&context, &mut manager, &create) {
+            ClientMessage::StreamCreated(created) => created.token,
+            r => panic!("StreamCreate failed: {:?}", r),
+        };
+        limit_exceeded(
+            server.process_msg(&context, &mut manager, &create),
+            ClientLimit::Streams,
+        );
+        // Destroying a stream makes room for another.
+        server.process_msg(&context, &mut manager, &ServerMessage::StreamDestroy(token));
+        match server.process_msg(&context, &mut manager, &create) {
+            ClientMessage::StreamCreated(_) => {}
+            r => panic!("StreamCreate failed: {:?}", r),
+        }
+
+        let mut config = ServerConfig::default();
+        config.limits.max_shm_size = Some(1024);
+        let mut server = connected_server(&thread, config);
+        limit_exceeded(
+            server.process_msg(&context, &mut manager, &create),
+            ClientLimit::SharedMemory,
+        );
+
+        let mut config = ServerConfig::default();
+        config.limits.max_messages_per_second = Some(3);
+        let mut server = connected_server(&thread, config);
+        let request = ServerMessage::ContextGetBackendId;
+        assert!((0..3).all(|_| server.within_message_rate(&request)));
+        assert!(!server.within_message_rate(&request));
+        assert!(server.within_message_rate(&ServerMessage::StreamDestroy(0)));
+        assert!(server.within_message_rate(&ServerMessage::ClientDisconnect));
+
+        let mut config = ServerConfig::default();
+        config.limits.max_device_collection_setups = Some(1);
+        let mut server = connected_server(&thread, config);
+        let setup = ServerMessage::ContextSetupDeviceCollectionCallback;
+        match server.process_msg(&context, &mut manager, &setup) {
+            ClientMessage::ContextSetupDeviceCollectionCallback(_) => {}
+            r => panic!("Device collection setup failed: {:?}", r),
+        }
+        limit_exceeded(
+            server.process_msg(&context, &mut manager, &setup),
+            ClientLimit::DeviceCollectionSetups,
+        );
+    }
+}
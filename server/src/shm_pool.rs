@@ -0,0 +1,244 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details
+
+// Shared memory areas of a client's destroyed streams, kept to be reused by
+// its later streams rather than allocating and mapping new areas.
+
+use crate::errors::*;
+use audioipc::shm::{Access, SharedMem};
+use audioipc::PlatformHandle;
+use std::ops::{Deref, DerefMut};
+
+/// Bounds on the shared memory a client's pool keeps for reuse.
+#[derive(Clone, Copy, Debug)]
+pub struct ShmPoolLimits {
+    /// Areas kept at once.  Zero disables reuse.
+    pub max_areas: usize,
+    /// Combined size in bytes of the areas kept.
+    pub max_size: usize,
+}
+
+impl Default for ShmPoolLimits {
+    fn default() -> Self {
+        ShmPoolLimits {
+            max_areas: 8,
+            max_size: 4 * 1024 * 1024,
+        }
+    }
+}
+
+/// Activity of a client's shared memory pool, for status queries.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct ShmPoolStats {
+    /// Areas handed out from the pool.
+    pub reused: u64,
+    /// Areas newly created because none in the pool fit.
+    pub allocated: u64,
+    /// Areas returned to the pool.
+    pub recycled: u64,
+    /// Areas released rather than returned because the pool was full.
+    pub discarded: u64,
+    pub pooled_areas: usize,
+    pub pooled_size: usize,
+}
+
+/// A shared memory area that can be returned to the `ShmPool` it came
+/// from.
+pub struct ShmArea {
+    shm: SharedMem,
+    // Kept to give the client of a later stream, if the area is reused.
+    handle: Option<PlatformHandle>,
+    access: Access,
+}
+
+impl Deref for ShmArea {
+    type Target = SharedMem;
+
+    fn deref(&self) -> &SharedMem {
+        &self.shm
+    }
+}
+
+impl DerefMut for ShmArea {
+    fn deref_mut(&mut self) -> &mut SharedMem {
+        &mut self.shm
+    }
+}
+
+pub struct ShmPool {
+    areas: Vec<ShmArea>,
+    limits: ShmPoolLimits,
+    stats: ShmPoolStats,
+}
+
+impl ShmPool {
+    pub fn new(limits: ShmPoolLimits) -> ShmPool {
+        ShmPool {
+            areas: Vec::new(),
+            limits,
+            stats: ShmPoolStats::default(),
+        }
+    }
+
+    /// An area of at least `size` bytes mapped with `access`, and a handle
+    /// for mapping it in the client.  A pooled area up to twice `size`, but
+    /// no larger than `max_size`, may be reused, so callers must use the
+    /// area's size rather than `size`.
+    pub fn allocate(
+        &mut self,
+        id: &str,
+        size: usize,
+        max_size: usize,
+        access: Access,
+    ) -> Result<(ShmArea, PlatformHandle)> {
+        let fit = self
+            .areas
+            .iter()
+            .enumerate()
+            .filter(|(_, area)| {
+                area.access == access
+                    && area.size() >= size
+                    && area.size() / 2 <= size
+                    && area.size() <= max_size
+            })
+            .min_by_key(|(_, area)| area.size())
+            .map(|(i, _)| i);
+
+        if let Some(i) = fit {
+            let area = self.areas.swap_remove(i);
+            let handle = match area.handle {
+                Some(ref handle) => PlatformHandle::duplicate(unsafe { handle.as_raw() })?,
+                None => unreachable!("pooled areas keep their handle"),
+            };
+            self.stats.reused += 1;
+            self.stats.pooled_areas -= 1;
+            self.stats.pooled_size -= area.size();
+            return Ok((area, handle));
+        }
+
+        let (shm, handle) = SharedMem::new(id, size, access)?;
+        let kept = if self.limits.max_areas > 0 {
+            Some(PlatformHandle::duplicate(unsafe { handle.as_raw() })?)
+        } else {
+            None
+        };
+        self.stats.allocated += 1;
+        Ok((
+            ShmArea {
+                shm,
+                handle: kept,
+                access,
+            },
+            handle,
+        ))
+    }
+
+    /// Return an area to the pool once its stream has been destroyed.
+    pub fn recycle(&mut self, mut area: ShmArea) {
+        let size = area.size();
+        let fits = area.handle.is_some()
+            && self.areas.len() < self.limits.max_areas
+            && self.stats.pooled_size + size <= self.limits.max_size;
+        // The next stream mustn't see this one's audio.
+        if !fits || area.zero().is_err() {
+            self.stats.discarded += 1;
+            return;
+        }
+        self.areas.push(area);
+        self.stats.recycled += 1;
+        self.stats.pooled_areas += 1;
+        self.stats.pooled_size += size;
+    }
+
+    pub fn stats(&self) -> ShmPoolStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ShmPool, ShmPoolLimits};
+    use audioipc::shm::Access;
+
+    #[test]
+    fn areas_are_zeroed_and_reused() {
+        let mut pool = ShmPool::new(ShmPoolLimits {
+            max_areas: 2,
+            max_size: 512,
+        });
+
+        let (mut area, _) = pool
+            .allocate(
+                "audioipc-pool-test",
+                256,
+                usize::max_value(),
+                Access::ReadWrite,
+            )
+            .unwrap();
+        unsafe { area.get_mut_slice(256).unwrap()[0] = 42 };
+        pool.recycle(area);
+
+        // Wrong access, or too small or too large a fit, allocate afresh.
+        let (other_access, _) = pool
+            .allocate(
+                "audioipc-pool-test",
+                256,
+                usize::max_value(),
+                Access::ReadOnly,
+            )
+            .unwrap();
+        let (too_small, _) = pool
+            .allocate(
+                "audioipc-pool-test",
+                257,
+                usize::max_value(),
+                Access::ReadWrite,
+            )
+            .unwrap();
+        let (too_large, _) = pool
+            .allocate(
+                "audioipc-pool-test",
+                127,
+                usize::max_value(),
+                Access::ReadWrite,
+            )
+            .unwrap();
+        assert_eq!(pool.stats().allocated, 4);
+
+        // Nor does one larger than the caller allows.
+        let (too_large_for_caller, _) = pool
+            .allocate("audioipc-pool-test", 200, 255, Access::ReadWrite)
+            .unwrap();
+        assert_eq!(too_large_for_caller.size(), 200);
+        assert_eq!(pool.stats().allocated, 5);
+        drop(too_large_for_caller);
+
+        let (area, _) = pool
+            .allocate(
+                "audioipc-pool-test",
+                200,
+                usize::max_value(),
+                Access::ReadWrite,
+            )
+            .unwrap();
+        assert_eq!(area.size(), 256);
+        assert_eq!(unsafe { area.get_slice(256).unwrap()[0] }, 0);
+        let stats = pool.stats();
+        assert_eq!(
+            (stats.reused, stats.pooled_areas, stats.pooled_size),
+            (1, 0, 0)
+        );
+
+        pool.recycle(area);
+        // Exceeds `max_size`.
+        pool.recycle(too_small);
+        pool.recycle(too_large);
+        // Exceeds `max_areas`.
+        pool.recycle(other_access);
+        let stats = pool.stats();
+        assert_eq!((stats.recycled, stats.discarded), (2, 2));
+        assert_eq!((stats.pooled_areas, stats.pooled_size), (2, 256 + 127));
+    }
+}
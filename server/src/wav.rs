@@ -0,0 +1,232 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details
+
+// Minimal WAV file writer, used to record the audio passing through a
+// stream for diagnosis.
+
+use audioipc::messages::StreamParams;
+use cubeb_core::ffi;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+
+const HEADER_SIZE: u32 = 44;
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+// Buffers an `AudioTap` queues before dropping audio.
+const TAP_QUEUE_LEN: usize = 64;
+
+pub struct WavWriter {
+    file: BufWriter<File>,
+    sample_size: usize,
+    // Samples are byte swapped to little endian before writing.
+    swap: bool,
+    swapped: Vec<u8>,
+    data_len: u32,
+}
+
+impl WavWriter {
+    /// Create a file at `path` for audio in the format described by
+    /// `params`.
+    pub fn create(path: &Path, params: &StreamParams) -> io::Result<WavWriter> {
+        let (format, sample_size, swap) = match params.format {
+            ffi::CUBEB_SAMPLE_S16LE => (WAVE_FORMAT_PCM, 2, false),
+            ffi::CUBEB_SAMPLE_S16BE => (WAVE_FORMAT_PCM, 2, true),
+            ffi::CUBEB_SAMPLE_FLOAT32LE => (WAVE_FORMAT_IEEE_FLOAT, 4, false),
+            ffi::CUBEB_SAMPLE_FLOAT32BE => (WAVE_FORMAT_IEEE_FLOAT, 4, true),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "unsupported sample format",
+                ))
+            }
+        };
+        let channels = params.channels as u16;
+        let block_align = channels * sample_size as u16;
+
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(b"RIFF")?;
+        // Chunk sizes are filled in by `finish`.
+        file.write_all(&(HEADER_SIZE - 8).to_le_bytes())?;
+        file.write_all(b"WAVEfmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        file.write_all(&format.to_le_bytes())?;
+        file.write_all(&channels.to_le_bytes())?;
+        file.write_all(&params.rate.to_le_bytes())?;
+        file.write_all(&(params.rate * u32::from(block_align)).to_le_bytes())?;
+        file.write_all(&block_align.to_le_bytes())?;
+        file.write_all(&(8 * sample_size as u16).to_le_bytes())?;
+        file.write_all(b"data")?;
+        file.write_all(&0u32.to_le_bytes())?;
+
+        Ok(WavWriter {
+            file,
+            sample_size,
+            swap,
+            swapped: Vec::new(),
+            data_len: 0,
+        })
+    }
+
+    /// Append audio to the file.  Audio beyond the largest size a WAV file
+    /// can describe is dropped.
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let available = (u32::max_value() - HEADER_SIZE - self.data_len) as usize;
+        let len = data.len().min(available);
+        let data = &data[..len - len % self.sample_size];
+
+        if self.swap {
+            self.swapped.clear();
+            for sample in data.chunks(self.sample_size) {
+                self.swapped.extend(sample.iter().rev());
+            }
+            self.file.write_all(&self.swapped)?;
+        } else {
+            self.file.write_all(data)?;
+        }
+        self.data_len += data.len() as u32;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file
+            .write_all(&(HEADER_SIZE - 8 + self.data_len).to_le_bytes())?;
+        self.file
+            .seek(SeekFrom::Start(u64::from(HEADER_SIZE) - 4))?;
+        self.file.write_all(&self.data_len.to_le_bytes())?;
+        self.file.flush()
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            debug!("Failed to finish WAV file: {:?}", e);
+        }
+    }
+}
+
+/// Records audio to a WAV file from a thread of its own, so the realtime
+/// callback queueing the audio never waits on file I/O.
+pub struct AudioTap {
+    // `None` once dropped, letting the thread finish the file.
+    queue: Option<mpsc::SyncSender<Vec<u8>>>,
+    // Buffers the thread has written, for reuse.
+    recycled: mpsc::Receiver<Vec<u8>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl AudioTap {
+    pub fn create(path: &Path, params: &StreamParams) -> io::Result<AudioTap> {
+        let mut writer = WavWriter::create(path, params)?;
+        let (queue, queued) = mpsc::sync_channel::<Vec<u8>>(TAP_QUEUE_LEN);
+        let (recycle, recycled) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("AudioIPC Audio Tap".into())
+            .spawn(move || {
+                for buffer in queued {
+                    if let Err(e) = writer.write(&buffer) {
+                        debug!("Failed to write audio tap: {:?}", e);
+                        return;
+                    }
+                    drop(recycle.send(buffer));
+                }
+            })?;
+        Ok(AudioTap {
+            queue: Some(queue),
+            recycled,
+            thread: Some(thread),
+        })
+    }
+
+    /// Queue `data` to be appended to the file.  Audio is dropped if the
+    /// thread falls too far behind.  Returns false once the thread has given
+    /// up after an error.
+    pub fn write(&mut self, data: &[u8]) -> bool {
+        let queue = match self.queue {
+            Some(ref queue) => queue,
+            None => return false,
+        };
+        let mut buffer = self.recycled.try_recv().unwrap_or_default();
+        buffer.clear();
+        buffer.extend_from_slice(data);
+        match queue.try_send(buffer) {
+            Ok(()) => true,
+            Err(mpsc::TrySendError::Full(_)) => {
+                trace!("Audio tap queue full, dropping {} bytes", data.len());
+                true
+            }
+            Err(mpsc::TrySendError::Disconnected(_)) => false,
+        }
+    }
+}
+
+impl Drop for AudioTap {
+    fn drop(&mut self) {
+        drop(self.queue.take());
+        if let Some(thread) = self.thread.take() {
+            drop(thread.join());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AudioTap, WavWriter};
+    use audioipc::messages::StreamParams;
+    use cubeb_core::ffi;
+
+    #[test]
+    fn writes_little_endian_wav() {
+        let path = std::env::temp_dir().join(format!("audioipc-wav-{}.wav", std::process::id()));
+        let params = StreamParams {
+            format: ffi::CUBEB_SAMPLE_S16BE,
+            rate: 48000,
+            channels: 2,
+            layout: ffi::CUBEB_LAYOUT_STEREO,
+            prefs: ffi::CUBEB_STREAM_PREF_NONE,
+        };
+        let mut writer = WavWriter::create(&path, &params).unwrap();
+        writer.write(&[0x12, 0x34, 0x56, 0x78]).unwrap();
+        // A partial sample is dropped.
+        writer.write(&[0x9a, 0xbc, 0xde]).unwrap();
+        drop(writer);
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(bytes.len(), 44 + 6);
+        assert_eq!(&bytes[..4], b"RIFF");
+        assert_eq!(&bytes[4..8], &(36u32 + 6).to_le_bytes());
+        assert_eq!(&bytes[22..24], &2u16.to_le_bytes());
+        assert_eq!(&bytes[28..32], &(48000u32 * 4).to_le_bytes());
+        assert_eq!(&bytes[40..44], &6u32.to_le_bytes());
+        assert_eq!(&bytes[44..], &[0x34, 0x12, 0x78, 0x56, 0xbc, 0x9a]);
+    }
+
+    #[test]
+    fn audio_tap_writes_queued_audio() {
+        let path = std::env::temp_dir().join(format!("audioipc-tap-{}.wav", std::process::id()));
+        let params = StreamParams {
+            format: ffi::CUBEB_SAMPLE_S16LE,
+            rate: 48000,
+            channels: 1,
+            layout: ffi::CUBEB_LAYOUT_MONO,
+            prefs: ffi::CUBEB_STREAM_PREF_NONE,
+        };
+        let mut tap = AudioTap::create(&path, &params).unwrap();
+        assert!(tap.write(&[1, 2]));
+        assert!(tap.write(&[3, 4]));
+        // Dropping the tap waits for the queued audio to be written.
+        drop(tap);
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(&bytes[40..44], &4u32.to_le_bytes());
+        assert_eq!(&bytes[44..], &[1, 2, 3, 4]);
+    }
+}